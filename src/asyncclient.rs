@@ -0,0 +1,159 @@
+use std::io::{IoResult, Writer};
+use std::io::net::tcp::TcpStream;
+
+#[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
+use std::io::BufferedReader;
+
+use basicclient::{BasicClient, ClientSender, ConnectionStatus};
+use connection::IrcStream;
+use irc_methods::IrcMethods;
+use message::Message;
+#[cfg(test)]
+use pipe::DuplexPipe;
+
+/// The incoming half of an `AsyncClient`: every message the client reads, in order, ending with the error that closed the connection (if any).
+#[experimental]
+pub struct MessageStream {
+    rx: Receiver<IoResult<Message<'static>>>
+}
+
+#[experimental]
+impl MessageStream {
+    /// Waits for the next message, returning `None` once the client task has finished.
+    #[experimental]
+    #[inline]
+    pub fn recv(&mut self) -> Option<IoResult<Message<'static>>> {
+        self.rx.recv_opt().ok()
+    }
+
+    /// Returns the next message if one has already arrived.
+    #[experimental]
+    #[inline]
+    pub fn try_recv(&mut self) -> Option<IoResult<Message<'static>>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+#[experimental]
+impl Iterator<IoResult<Message<'static>>> for MessageStream {
+    #[inline]
+    fn next(&mut self) -> Option<IoResult<Message<'static>>> {
+        self.recv()
+    }
+}
+
+/// The outgoing half of an `AsyncClient`. Writes go straight to the connection through the client's `ClientSender`, rather
+/// than waiting for the client task.
+#[experimental]
+#[deriving(Clone)]
+pub struct MessageSink<T: IrcStream = TcpStream> {
+    sender: ClientSender<T>
+}
+
+#[experimental]
+impl<T: IrcStream> MessageSink<T> {
+    #[experimental]
+    pub fn send(&mut self, msg: &Message) -> IoResult<()> {
+        write!(self, "{}", msg)
+    }
+}
+
+#[experimental]
+impl<T: IrcStream> Writer for MessageSink<T> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.sender.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> IoResult<()> {
+        self.sender.flush()
+    }
+}
+
+#[experimental]
+impl<T: IrcStream> IrcMethods for MessageSink<T> {
+    #[inline]
+    fn client_tags_enabled(&self) -> bool {
+        self.sender.client_tags_enabled()
+    }
+
+    #[inline]
+    fn quit(&mut self, message: Option<&str>) -> IoResult<()> {
+        self.sender.quit(message)
+    }
+}
+
+/// Runs a connected `BasicClient` on its own task, so that messages can be received and sent at the same time.
+#[experimental]
+pub struct AsyncClient;
+
+#[experimental]
+impl AsyncClient {
+    /// Moves the client to a new task. `PING`s and capability negotiation are still handled by the client; every other message
+    /// is passed to the returned stream. The task finishes when the connection closes or `quit` is called on the sink.
+    #[experimental]
+    pub fn spawn<T: IrcStream>(client: BasicClient<T>) -> (MessageStream, MessageSink<T>) {
+        let (in_tx, in_rx) = channel();
+        let mut client = client;
+        // The task blocks on reading, so writes can't be left queued for it
+        let _ = client.set_nonblocking(false);
+        let (reader, sender) = client.split();
+
+        spawn(proc() {
+            let mut reader = reader;
+            let in_tx = in_tx;
+
+            loop {
+                match reader.read_message() {
+                    Ok(Some(msg)) => {
+                        if in_tx.send_opt(Ok(msg)).is_err() {
+                            return;
+                        }
+                    },
+                    Ok(None) => {},
+                    Err(e) => {
+                        // The connection closing after `quit` isn't an error
+                        match reader.status() {
+                            ConnectionStatus::Disconnected => {},
+                            _ => { let _ = in_tx.send_opt(Err(e)); }
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+
+        (MessageStream { rx: in_rx }, MessageSink { sender: sender })
+    }
+}
+
+#[test]
+fn async_round_trip() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+
+    let mut server = BufferedReader::new(server_end.clone());
+    let mut server_out = server_end;
+    assert!(server.read_line().unwrap().as_slice() == "CAP END\r\n");
+    assert!(server.read_line().unwrap().as_slice() == "NICK relay\r\n");
+    assert!(server.read_line().unwrap().as_slice() == "USER relay * * :Relay\r\n");
+
+    let (mut stream, mut sink) = AsyncClient::spawn(client);
+
+    server_out.write(b"PING :token\r\n:alice!a@host PRIVMSG relay :hello\r\n").unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "PONG token\r\n");
+
+    let msg = stream.recv().unwrap().unwrap();
+    assert!(msg.command().as_slice() == "PRIVMSG");
+    assert!(msg.params().as_ref().unwrap()[1].as_slice() == "hello");
+
+    sink.privmsg("alice", "hi").unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "PRIVMSG alice :hi\r\n");
+
+    sink.quit(Some("bye")).unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "QUIT :bye\r\n");
+}
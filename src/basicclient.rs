@@ -1,15 +1,46 @@
-use std::collections::HashSet;
+use std::cmp::min;
+use std::collections::{HashSet, RingBuf};
 use std::io::{IoError, IoErrorKind, IoResult, Writer};
 use std::io::net::ip::{SocketAddr, ToSocketAddr};
 use std::io::net::tcp::TcpStream;
+use std::io::timer::sleep;
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::{AsRawFd, Fd};
+use std::str::Owned;
+use std::sync::{Arc, Mutex};
 use std::time::duration::Duration;
+use time::{get_time, precise_time_ns};
 
+#[cfg(test)]
+use std::io::{Acceptor, BufferedReader, Listener};
+#[cfg(test)]
+use std::io::net::tcp::TcpListener;
+
+use batch::{Batch, BatchTracker};
 use capability::{Capability, CapabilityModifier};
-use connection::Connection;
+use channel::ChannelState;
+use connection::{Connection, IrcStream};
+use ctcp::CtcpResponder;
+use echo::{EchoTracker, outgoing_messages};
+use flood::{FloodControl, SendQueue};
+use history::HistoryTracker;
 use irc_methods::IrcMethods;
-use message::Message;
+use isupport::{CaseMapping, ISupport};
+use keepalive::{Keepalive, KeepaliveAction};
+use labeled::{LabeledResponse, PendingLabels, ResponseHandle, add_label};
+use message::{Message, format_server_time};
+use metadata::{MetadataCache, MetadataEvent};
+use monitor::{Monitor, MonitorEvent, monitor_lines};
+use multiline::{MultilineLimits, plan_batches, reassemble};
+use prefix::{ClientPrefix, Prefix};
+use splitting::{split_text, text_budget};
+use tag::Tag;
+#[cfg(test)]
+use pipe::DuplexPipe;
 
 #[experimental]
+#[deriving(Clone, Show)]
 pub enum ConnectionStatus {
     NotConnected,
     Connecting,
@@ -18,12 +49,50 @@ pub enum ConnectionStatus {
     Error(IoError)
 }
 
+/// How many ended batches are kept for `read_event` or `next_batch` before the oldest are dropped.
+static MAX_COMPLETED_BATCHES: uint = 64;
+/// How many of our own sent messages are kept for `read_event`, and how many are remembered by the sender until the client
+/// looks at them.
+static MAX_SENT_EVENTS: uint = 64;
+static MAX_SENT_LINES: uint = 256;
+/// How many monitor and metadata events are kept for `read_event` before the oldest are dropped.
+static MAX_EVENTS: uint = 64;
+
+/// How many messages to ask for when catching up, if the server doesn't say how many it will send.
+static DEFAULT_HISTORY_LIMIT: uint = 100;
+/// How long, in milliseconds, `process_io` lets a write of the send queue block before leaving the rest for next time.
+static FLUSH_TIMEOUT_MS: u64 = 10;
+/// How long, in milliseconds, to wait before a second attempt to reconnect. The wait doubles with every failed attempt, up
+/// to the maximum, until we are registered again.
+static RECONNECT_DELAY_MS: u64 = 1000;
+static MAX_RECONNECT_DELAY_MS: u64 = 60000;
+
+/// Something read from the server by `BasicClient::read_event`.
+#[experimental]
+#[deriving(Clone)]
+pub enum ClientEvent {
+    Message(Message<'static>),
+    /// A `BATCH` and every message in it, once the batch has ended.
+    Batch(Batch),
+    /// A message we sent, as echoed back by the server with `echo-message` (so with its `msgid` and `time`), or as a local
+    /// copy with our own prefix if the server doesn't echo.
+    Sent(Message<'static>),
+    /// A monitored nick came online or went offline, or the server's monitor list was full.
+    Monitor(MonitorEvent),
+    /// A change to the metadata of a user or channel, or the failure of a `METADATA` command.
+    Metadata(MetadataEvent),
+    /// The server's `CAP ACK`, once the capabilities in it are enabled.
+    CapAck(Message<'static>)
+}
+
 #[experimental]
-pub struct BasicClient {
-    connection: Option<Connection<TcpStream>>,
+pub struct BasicClient<T: IrcStream = TcpStream> {
+    connection: Option<Connection<T>>,
     remote_addr: Option<SocketAddr>,
-    status: ConnectionStatus,
+    sender: ClientSender<T>,
     nick: String,
+    own_user: Option<String>,
+    own_host: Option<String>,
     user_name: String,
     real_name: String,
     wanted_caps: HashSet<Capability>,
@@ -31,18 +100,41 @@ pub struct BasicClient {
     requested_caps: HashSet<Capability>,   
     enabled_caps: HashSet<Capability>,
     listed_caps: HashSet<Capability>,
-    cap_partial_listing: bool
+    cap_partial_listing: bool,
+    isupport: ISupport,
+    channel_state: Option<ChannelState>,
+    history: Option<HistoryTracker>,
+    nonblocking: bool,
+    send_queue: Vec<u8>,
+    send_queue_partial: bool,
+    batch_count: uint,
+    read_timeout: Option<u64>,
+    keepalive: Option<Keepalive>,
+    auto_reconnect: bool,
+    reconnect_attempts: uint,
+    last_reconnect: u64,
+    batches: BatchTracker,
+    completed_batches: RingBuf<Batch>,
+    stream_batches: bool,
+    echoes: EchoTracker,
+    sent_events: RingBuf<Message<'static>>,
+    last_was_echo: bool,
+    metadata: MetadataCache,
+    events: RingBuf<ClientEvent>,
+    ctcp_responder: Option<CtcpResponder>
 }
 
 #[experimental]
-impl BasicClient {
+impl<T: IrcStream> BasicClient<T> {
     #[experimental]
-    pub fn new(nick: &str, user_name: &str, real_name: &str, wanted_caps: HashSet<Capability>) -> BasicClient {
+    pub fn new(nick: &str, user_name: &str, real_name: &str, wanted_caps: HashSet<Capability>) -> BasicClient<T> {
         BasicClient {
             connection: None,
             remote_addr: None,
-            status: NotConnected,
+            sender: ClientSender::new(),
             nick: String::from_str(nick),
+            own_user: None,
+            own_host: None,
             user_name: String::from_str(user_name),
             real_name: String::from_str(real_name),
             wanted_caps: wanted_caps,
@@ -50,13 +142,328 @@ impl BasicClient {
             requested_caps: HashSet::new(),
             enabled_caps: HashSet::new(),
             listed_caps: HashSet::new(),
-            cap_partial_listing: false
+            cap_partial_listing: false,
+            isupport: ISupport::new(),
+            channel_state: None,
+            history: None,
+            nonblocking: false,
+            send_queue: Vec::new(),
+            send_queue_partial: false,
+            batch_count: 0,
+            read_timeout: None,
+            keepalive: None,
+            auto_reconnect: false,
+            reconnect_attempts: 0,
+            last_reconnect: 0,
+            batches: BatchTracker::new(),
+            completed_batches: RingBuf::new(),
+            stream_batches: false,
+            echoes: EchoTracker::new(),
+            sent_events: RingBuf::new(),
+            last_was_echo: false,
+            metadata: MetadataCache::new(),
+            events: RingBuf::new(),
+            ctcp_responder: None
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn status(&self) -> ConnectionStatus {
+        self.sender.status()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.sender.is_connected()
+    }
+
+    /// Sends a `PRIVMSG`, split into as many messages as needed to fit the line length limit once the server has added our prefix.
+    /// Each line of `text` starts a new message, and with `draft/multiline` the pieces are sent as a single multiline batch.
+    #[experimental]
+    pub fn privmsg_split(&mut self, target: &str, text: &str) -> IoResult<()> {
+        self.send_split("PRIVMSG", target, text)
+    }
+
+    /// Sends a `NOTICE`, split in the same way as `privmsg_split`.
+    #[experimental]
+    pub fn notice_split(&mut self, target: &str, text: &str) -> IoResult<()> {
+        self.send_split("NOTICE", target, text)
+    }
+
+    fn send_split(&mut self, command: &str, target: &str, text: &str) -> IoResult<()> {
+        let budget = text_budget(command, target, self.nick.as_slice(), self.own_user.as_ref().map(|u| u.as_slice()), self.own_host.as_ref().map(|h| h.as_slice()));
+        let lines: Vec<Vec<String>> = text.lines_any().map(|line| split_text(line, budget)).collect();
+        let piece_count = lines.iter().fold(0, |count, pieces| count + pieces.len());
+
+        let limits = match self.cap_value("draft/multiline") {
+            Some(value) if self.is_cap_enabled("draft/multiline") => MultilineLimits::from_cap_value(value),
+            _ => None
+        };
+
+        if piece_count > 1 && limits.is_some() {
+            for batch in plan_batches(lines.as_slice(), limits.as_ref().unwrap()).iter() {
+                self.batch_count += 1;
+                let reference = format!("relay{}", self.batch_count);
+
+                try!(write!(self, "BATCH +{} draft/multiline {}\r\n", reference, target));
+                for &(ref piece, concat) in batch.iter() {
+                    // Pieces of the same line are joined back together without a line break
+                    let concat = if concat { ";draft/multiline-concat" } else { "" };
+                    try!(write!(self, "@batch={}{} {} {} :{}\r\n", reference, concat, command, target, piece));
+                }
+                try!(write!(self, "BATCH -{}\r\n", reference));
+            }
+            Ok(())
+        } else {
+            for piece in lines.iter().flat_map(|pieces| pieces.iter()).filter(|piece| !piece.is_empty()) {
+                try!(write!(self, "{} {} :{}\r\n", command, target, piece));
+            }
+            Ok(())
+        }
+    }
+
+    /// Sends a `PING` once the server has been quiet for a while, and treats the connection as dead if nothing comes back in
+    /// time. `None` turns this off.
+    #[experimental]
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+        match self.keepalive {
+            Some(ref mut keepalive) => keepalive.reset(now_ms()),
+            None => {}
+        }
+    }
+
+    /// The round-trip time, in milliseconds, of the last keepalive `PING`.
+    #[experimental]
+    pub fn lag(&self) -> Option<u64> {
+        self.keepalive.as_ref().and_then(|keepalive| keepalive.lag())
+    }
+
+    /// Whether to reconnect when the connection dies, rather than returning the error. The first attempt is made straight
+    /// away, and later ones wait longer and longer until one of them succeeds. A connection we quit isn't reopened.
+    #[experimental]
+    #[inline]
+    pub fn set_auto_reconnect(&mut self, auto_reconnect: bool) {
+        self.auto_reconnect = auto_reconnect;
+    }
+
+    /// Opens a new connection to the server we last connected to and registers again with the same nick. Capabilities,
+    /// `ISUPPORT` tokens and channel state start afresh.
+    #[experimental]
+    pub fn reconnect(&mut self) -> IoResult<()> {
+        let addr = match self.remote_addr {
+            Some(addr) => addr,
+            None => {
+                return Err(IoError {
+                    kind: IoErrorKind::OtherIoError,
+                    desc: "No address to reconnect to",
+                    detail: None
+                });
+            }
+        };
+
+        self.sender.close();
+        let stream: T = match IrcStream::open(addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.sender.set_status(Error(e.clone()));
+                return Err(e);
+            }
+        };
+
+        self.reset_session();
+        match self.history {
+            Some(ref mut history) => history.start_catch_up(),
+            None => {}
+        }
+        self.sender.monitor_reconnected();
+        self.metadata.reconnected();
+        self.sender.attach(stream.clone(), Connecting);
+        self.connection = Some(Connection::new(stream));
+        let timeout = self.read_timeout;
+        self.apply_read_timeout(timeout);
+
+        self.register()
+    }
+
+    fn reset_session(&mut self) {
+        self.available_caps.clear();
+        self.requested_caps.clear();
+        self.enabled_caps.clear();
+        self.listed_caps.clear();
+        self.cap_partial_listing = false;
+        self.sender.set_client_tags(false);
+        self.sender.set_labeled_response(false);
+        self.isupport = ISupport::new();
+        self.share_isupport();
+        if self.channel_state.is_some() {
+            self.channel_state = Some(ChannelState::new());
+        }
+        self.own_user = None;
+        self.own_host = None;
+        self.send_queue.clear();
+        self.send_queue_partial = false;
+        self.batches.clear();
+        self.echoes.clear();
+    }
+
+    /// Marks the connection as dead, then reconnects if auto-reconnect is on and we didn't quit.
+    fn connection_lost(&mut self, err: IoError) -> IoResult<()> {
+        if self.has_quit() {
+            self.sender.close();
+            return Err(err);
+        }
+        self.sender.set_status(Error(err.clone()));
+        self.sender.close();
+
+        if !self.auto_reconnect || self.remote_addr.is_none() {
+            return Err(err);
+        }
+        self.reconnect_after_backoff(err)
+    }
+
+    /// Tries to reconnect again if auto-reconnect is on and the last attempt failed, or fails with `NotConnected`.
+    fn retry_reconnect(&mut self) -> IoResult<()> {
+        let err = match self.status() {
+            Error(err) if self.auto_reconnect && self.remote_addr.is_some() => err,
+            _ => return Err(not_connected())
+        };
+        self.reconnect_after_backoff(err)
+    }
+
+    /// Reconnects once the wait after the last failed attempt is over, returning `err` if a non-blocking client would have to wait.
+    fn reconnect_after_backoff(&mut self, err: IoError) -> IoResult<()> {
+        let wait = match self.reconnect_attempts {
+            0 => 0,
+            attempts => {
+                let ready = self.last_reconnect + min(RECONNECT_DELAY_MS << min(attempts - 1, 6), MAX_RECONNECT_DELAY_MS);
+                let now = now_ms();
+                if ready > now { ready - now } else { 0 }
+            }
+        };
+        if wait > 0 {
+            // Non-blocking clients can't wait here, so they get the error and can call `reconnect` when they like
+            if self.nonblocking {
+                return Err(err);
+            }
+            sleep(Duration::milliseconds(wait as i64));
+        }
+
+        self.reconnect_attempts += 1;
+        self.last_reconnect = now_ms();
+        self.reconnect()
+    }
+
+    /// Whether the connection was closed by quitting, rather than by an error.
+    fn has_quit(&self) -> bool {
+        match self.status() {
+            Disconnected => true,
+            _ => false
+        }
+    }
+
+    fn check_keepalive(&mut self) -> IoResult<()> {
+        let action = match self.keepalive {
+            Some(ref mut keepalive) => keepalive.poll(now_ms()),
+            None => return Ok(())
+        };
+
+        match action {
+            KeepaliveAction::Ping(token) => self.ping(token.as_slice(), None),
+            KeepaliveAction::TimedOut => {
+                self.connection_lost(IoError {
+                    kind: IoErrorKind::TimedOut,
+                    desc: "Ping timeout",
+                    detail: None
+                })
+            },
+            KeepaliveAction::Wait => Ok(())
+        }
+    }
+
+    /// Holds outgoing lines in a token bucket queue to avoid being disconnected for flooding. See `ClientSender::set_flood_control`.
+    #[experimental]
+    #[inline]
+    pub fn set_flood_control(&mut self, config: Option<FloodControl>) -> IoResult<()> {
+        self.sender.set_flood_control(config)
+    }
+
+    /// Splits the client into a reader, which still handles `PING`s and capability negotiation, and a sender that can be
+    /// cloned and used from other tasks. Both halves see the same connection status, and `quit` on either closes the connection.
+    #[experimental]
+    pub fn split(self) -> (ClientReader<T>, ClientSender<T>) {
+        let sender = self.sender.clone();
+        (ClientReader { client: self }, sender)
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn enabled_caps<'a>(&'a self) -> &'a HashSet<Capability> {
+        &self.enabled_caps
+    }
+
+    #[experimental]
+    pub fn is_cap_enabled(&self, identifier: &str) -> bool {
+        self.enabled_caps.iter().any(|c| c.identifier.as_slice() == identifier)
+    }
+
+    /// The value the server gave for a capability in `CAP LS 302`, such as the limits for `draft/multiline`.
+    #[experimental]
+    pub fn cap_value<'a>(&'a self, identifier: &str) -> Option<&'a str> {
+        self.available_caps.iter()
+            .find(|c| c.identifier.as_slice() == identifier)
+            .and_then(|c| c.value.as_ref().map(|value| value.as_slice()))
+    }
+
+    /// Our current nick, as confirmed by the server.
+    #[experimental]
+    #[inline]
+    pub fn current_nick<'a>(&'a self) -> &'a str {
+        self.nick.as_slice()
+    }
+
+    /// The `RPL_ISUPPORT` tokens advertised by the server.
+    #[experimental]
+    #[inline]
+    pub fn isupport<'a>(&'a self) -> &'a ISupport {
+        &self.isupport
+    }
+
+    /// Starts tracking the members, modes and topic of the channels we join.
+    #[experimental]
+    pub fn track_channels(&mut self) {
+        if self.channel_state.is_none() {
+            self.channel_state = Some(ChannelState::new());
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn channel_state<'a>(&'a self) -> Option<&'a ChannelState> {
+        self.channel_state.as_ref()
+    }
+
+    /// Starts remembering the last message seen in each conversation and dropping messages whose `msgid` has already been
+    /// seen. With `draft/chathistory`, anything missed while disconnected is then fetched after reconnecting: for each
+    /// channel when we rejoin it, and for private conversations at the end of the MOTD.
+    #[experimental]
+    pub fn track_history(&mut self) {
+        if self.history.is_none() {
+            self.history = Some(HistoryTracker::new());
         }
     }
 
     #[experimental]
     #[inline]
-    pub fn get_stream<'a>(&'a mut self) -> &'a mut TcpStream {
+    pub fn history<'a>(&'a self) -> Option<&'a HistoryTracker> {
+        self.history.as_ref()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn get_stream<'a>(&'a mut self) -> &'a mut T {
         self.connection.as_mut().unwrap().get_stream()
     }
     
@@ -74,121 +481,644 @@ impl BasicClient {
             self.user(user.as_slice(), real.as_slice())
         }) {
             Ok(_) => {
-                self.status = Connected;
+                self.sender.set_status(Connected);
+                match self.keepalive {
+                    Some(ref mut keepalive) => keepalive.reset(now_ms()),
+                    None => {}
+                }
                 Ok(())
             },
             Err(e) => {
-                self.status = Error(e.clone());
+                self.sender.set_status(Error(e.clone()));
                 Err(e)
             }
         }
     }
 
+    /// Reads the next message, answering `PING`s and negotiating capabilities along the way.
+    ///
+    /// With a keepalive set, our own `PING`s are sent while waiting. If the connection dies and auto-reconnect is on, the
+    /// client reconnects and carries on reading from the new connection, starting with its registration replies.
     #[experimental]
     pub fn read_message(&mut self) -> IoResult<Option<Message<'static>>> {
-        match self.status {
-            Connected | Connecting => {
-                loop {
-                    match self.connection.as_mut().unwrap().read_message() {
-                        Ok(msg_opt) => {
-                            match msg_opt {
-                                Some(msg) => {
-                                    match msg.command().as_slice() {
-                                        "PING" => {
-                                            match msg.params() {
-                                                &Some(ref params) => {
-                                                    let mut p_iter = params.iter().map(|p| p.as_slice());
-                                                    try!(self.pong(p_iter.next().unwrap(), p_iter.next()));
-                                                },
-                                                &None => {
-                                                    try!(self.write(b"PONG\r\n"));
-                                                }
-                                            }
-                                        },
-                                        "CAP" => {
-                                            match self.negotiate_capabilities(msg) {
-                                                Ok(msg_opt) => {
-                                                    if msg_opt.is_some() {
-                                                        return Ok(msg_opt);
-                                                    }
-                                                },
-                                                err => return err
-                                            }
-                                        },
-                                        "ERROR" => {
-                                            let err = IoError {
-                                                kind: IoErrorKind::OtherIoError,
-                                                desc: "IRC Error",
-                                                detail: msg.params().as_ref().map(|p| p[0].to_string())
-                                            };
-                                            self.status = Error(err.clone());
-                                            let con = self.connection.as_mut().unwrap().get_stream();
-                                            let _ = con.close_write().and(con.close_read());
-                                            return Err(err);
-                                        }
-                                        _ => {
-                                            return Ok(Some(msg));
-                                        }
-                                    }
-                                },
-                                None => return Ok(None)
-                            }
-                        },
-                        err => return err
+        let result = self.read_message_keepalive();
+
+        if self.keepalive.is_some() {
+            // Put back the timeout that may have been shortened to wake up for the keepalive
+            let timeout = self.read_timeout;
+            self.apply_read_timeout(timeout);
+        }
+        result
+    }
+
+    /// Reads the next message or completed batch. Messages that belong to a batch, and the `BATCH` lines themselves, are
+    /// only returned as part of the batch unless `set_stream_batches` is on, in which case they are returned as they arrive
+    /// as well. A message tagged with a batch that was never started is returned on its own.
+    #[experimental]
+    pub fn read_event(&mut self) -> IoResult<Option<ClientEvent>> {
+        loop {
+            self.track_sent();
+            match self.sent_events.pop_front() {
+                Some(msg) => return Ok(Some(ClientEvent::Sent(msg))),
+                None => {}
+            }
+
+            // Targets that didn't fit in the monitor list, whichever half tried to add them
+            let refused = self.sender.take_monitor_refused();
+            if !refused.is_empty() {
+                self.push_event(ClientEvent::Monitor(MonitorEvent::ListFull(refused)));
+            }
+            match self.events.pop_front() {
+                Some(event) => return Ok(Some(event)),
+                None => {}
+            }
+
+            match self.completed_batches.pop_front() {
+                // A multiline message is returned as the single message it stands for
+                Some(batch) => return Ok(Some(match reassemble(&batch) {
+                    Some(msg) => ClientEvent::Message(msg),
+                    None => ClientEvent::Batch(batch)
+                })),
+                None => {}
+            }
+
+            let msg = match try!(self.read_message()) {
+                Some(msg) => msg,
+                None => return Ok(None)
+            };
+            // Our own echoed messages come back as `Sent` instead
+            if self.last_was_echo {
+                continue;
+            }
+
+            let batched = msg.command().as_slice() == "BATCH" || msg.batch().map_or(false, |batch| self.batches.is_open(batch));
+            if self.stream_batches || !batched {
+                return Ok(Some(ClientEvent::Message(msg)));
+            }
+        }
+    }
+
+    /// Takes the oldest batch that has ended but not yet been returned by `read_event`. Batches are collected whether or
+    /// not `read_event` is used, so callers of `read_message` or `process_io` should take them from here.
+    #[experimental]
+    #[inline]
+    pub fn next_batch(&mut self) -> Option<Batch> {
+        self.completed_batches.pop_front()
+    }
+
+    /// Takes the `CAP ACK`s that haven't been returned by `read_event` yet, leaving any other events queued. `read_message`
+    /// doesn't return them, so its callers can take them from here.
+    #[experimental]
+    pub fn take_cap_acks(&mut self) -> Vec<Message<'static>> {
+        let mut acks = Vec::new();
+        let events = mem::replace(&mut self.events, RingBuf::new());
+        for event in events.into_iter() {
+            match event {
+                ClientEvent::CapAck(msg) => acks.push(msg),
+                event => self.events.push_back(event)
+            }
+        }
+        acks
+    }
+
+    /// Sends a raw line with a `label` tag and returns a handle for the server's response, or sends it as it is and returns
+    /// `None` if `labeled-response` isn't enabled. Responses are still returned by `read_message` as well.
+    #[experimental]
+    pub fn request(&mut self, line: &str) -> IoResult<Option<ResponseHandle>> {
+        let (line, handle) = self.sender.label_line(line);
+        try!(self.write(format!("{}\r\n", line).as_bytes()));
+        Ok(handle)
+    }
+
+    /// Picks up what the sender has sent since we last looked, either to wait for its echo or, without `echo-message`, to
+    /// return a local copy of it from `read_event`.
+    fn track_sent(&mut self) {
+        let sent = self.sender.take_sent();
+        if sent.is_empty() {
+            return;
+        }
+
+        let echo_message = self.is_cap_enabled("echo-message");
+        for msg in sent.into_iter() {
+            if echo_message {
+                self.echoes.sent(&msg, self.isupport.case_mapping());
+            } else {
+                let echo = self.local_echo(msg);
+                self.push_sent_event(echo);
+            }
+        }
+    }
+
+    /// Gives a message we sent our own prefix and the current time, as the server would have.
+    fn local_echo(&self, mut msg: Message<'static>) -> Message<'static> {
+        *msg.prefix_mut() = Some(Prefix::Client(ClientPrefix {
+            nick: Owned(self.nick.clone()),
+            ident: Owned(self.own_user.clone().unwrap_or(String::new())),
+            host: Owned(self.own_host.clone().unwrap_or(String::new()))
+        }));
+
+        if !msg.has_tag("time") {
+            let time = Tag::new("time", Some(format_server_time(get_time()).as_slice()));
+            if msg.tags().is_none() {
+                *msg.tags_mut() = Some(Vec::new());
+            }
+            msg.tags_mut().as_mut().unwrap().push(time);
+        }
+        msg
+    }
+
+    fn push_sent_event(&mut self, msg: Message<'static>) {
+        if self.sent_events.len() == MAX_SENT_EVENTS {
+            self.sent_events.pop_front();
+        }
+        self.sent_events.push_back(msg);
+    }
+
+    /// A copy of the nicks being monitored and whether they are online. The sender keeps the list, so that `monitor_add`
+    /// and `monitor_remove` on a `ClientSender` are tracked too.
+    #[experimental]
+    pub fn monitor(&self) -> Monitor {
+        self.sender.shared.lock().monitor.clone()
+    }
+
+    /// The metadata of the users and channels we have seen, and the keys we are subscribed to.
+    #[experimental]
+    #[inline]
+    pub fn metadata<'a>(&'a self) -> &'a MetadataCache {
+        &self.metadata
+    }
+
+    /// Answers CTCP requests such as `VERSION` and `PING` automatically, or stops doing so with `None`. The requests are
+    /// still returned as ordinary messages.
+    #[experimental]
+    pub fn set_ctcp_responder(&mut self, responder: Option<CtcpResponder>) {
+        self.ctcp_responder = responder;
+    }
+
+    fn push_event(&mut self, event: ClientEvent) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Whether `read_event` should return batched messages individually as well as in their `Batch`.
+    #[experimental]
+    pub fn set_stream_batches(&mut self, stream: bool) {
+        self.stream_batches = stream;
+    }
+
+    fn read_message_keepalive(&mut self) -> IoResult<Option<Message<'static>>> {
+        loop {
+            if !self.is_connected() {
+                try!(self.retry_reconnect());
+            }
+
+            let wait = self.keepalive.as_ref().map(|keepalive| keepalive.next_poll_in(now_ms()));
+            let keepalive_first = match (wait, self.read_timeout) {
+                (Some(wait), Some(timeout)) => wait < timeout,
+                (Some(_), None) => true,
+                (None, _) => false
+            };
+            if keepalive_first {
+                self.apply_read_timeout(wait);
+            }
+
+            match self.connection.as_mut().unwrap().read_message() {
+                Ok(Some(msg)) => {
+                    match try!(self.handle_message(msg)) {
+                        Some(msg) => return Ok(Some(msg)),
+                        None => {}
                     }
-                }
-            },
-            _ => {
+                },
+                Ok(None) => return Ok(None),
+                Err(ref e) if e.kind == IoErrorKind::TimedOut && keepalive_first => {},
+                Err(ref e) if e.kind == IoErrorKind::TimedOut => return Err(e.clone()),
+                Err(e) => try!(self.connection_lost(e))
+            }
+
+            try!(self.check_keepalive());
+        }
+    }
+
+    /// Registers with the server over an already-connected stream, such as an in-memory pipe in tests.
+    #[experimental]
+    pub fn connect_stream(&mut self, stream: T) -> IoResult<()> {
+        match self.status() {
+            Connected | Connecting => {
                 Err(IoError {
-                    kind: IoErrorKind::NotConnected,
-                    desc: "Not connected",
+                    kind: IoErrorKind::OtherIoError,
+                    desc: "Already connected",
                     detail: None
                 })
+            },
+            _ => {
+                self.sender.attach(stream.clone(), Connecting);
+                self.connection = Some(Connection::new(stream));
+                self.remote_addr = None;
+                let timeout = self.read_timeout;
+                self.apply_read_timeout(timeout);
+
+                self.register()
             }
         }
     }
 
-    fn negotiate_capabilities(&mut self, msg: Message<'static>) -> IoResult<Option<Message<'static>>> {
-        match *msg.params() {
-            Some(ref params) => {
-                let mut p_iter = params.iter().map(|p| p.as_slice()).skip(1);
-                match p_iter.next() {
-                    Some(sub_cmd) if sub_cmd == "LS" => {
-                        match p_iter.next() {
-                            Some(p) if p == "*" => {
-                                self.cap_partial_listing = true;
-                                match p_iter.next() {
-                                    Some(caps) => {
-                                        for cap in caps.split_terminator(' ').map(|c| Capability::from_str(c)) {
-                                            if cap.is_some() {
-                                                self.available_caps.insert(cap.unwrap());
-                                            }
-                                        }
-                                        Ok(None)
-                                    },
-                                    None => {
-                                        try!(self.cap_end())
-                                        Ok(None)
-                                    }
-                                }
-                            },
-                            Some(p) => {
-                                if self.cap_partial_listing {
-                                    self.cap_partial_listing = false;
-                                }
+    /// Sets the timeout, in milliseconds, after which a read gives up waiting for data.
+    #[experimental]
+    pub fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.read_timeout = timeout_ms;
+        self.apply_read_timeout(timeout_ms);
+    }
 
-                                for cap in p.split_terminator(' ').map(|c| Capability::from_str(c)) {
-                                    if cap.is_some() {
-                                        self.available_caps.insert(cap.unwrap());
-                                    }
-                                }
+    fn apply_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        match self.connection {
+            Some(ref mut con) => con.set_read_timeout(timeout_ms),
+            None => {}
+        }
+    }
 
-                                // FIXME(JA): We should break this in to smaller chunks to make sure we don't exceed the IRC line length.
-                                let requested_caps: Vec<Capability> = self.wanted_caps.intersection(&self.available_caps).map(|c| c.clone()).collect();
-                                try!(self.cap_req(&requested_caps));
-                                self.requested_caps = requested_caps.into_iter().collect();
-                                Ok(None)
-                            },
+    /// Puts the client into (or out of) non-blocking mode. In non-blocking mode reads never wait for data, writes are queued
+    /// rather than sent, and the connection is driven by calling `process_io` whenever the socket is readable or writable.
+    #[experimental]
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> IoResult<()> {
+        self.set_read_timeout(if nonblocking { Some(0) } else { None });
+        self.nonblocking = nonblocking;
+
+        if !nonblocking {
+            self.flush_send_queue(None)
+        } else {
+            Ok(())
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblocking
+    }
+
+    /// Whether there are queued writes waiting for the socket to become writable.
+    #[experimental]
+    #[inline]
+    pub fn wants_write(&self) -> bool {
+        !self.send_queue.is_empty()
+    }
+
+    /// Reads and handles every message that can be read without blocking, then sends any queued writes.
+    ///
+    /// `PING`s and capability negotiation are handled as in `read_message`; everything else is returned.
+    #[experimental]
+    pub fn process_io(&mut self) -> IoResult<Vec<Message<'static>>> {
+        let mut messages = Vec::new();
+
+        if !self.is_connected() {
+            try!(self.retry_reconnect());
+        }
+
+        loop {
+            match self.connection.as_mut().unwrap().read_message() {
+                Ok(Some(msg)) => {
+                    match try!(self.handle_message(msg)) {
+                        Some(msg) => messages.push(msg),
+                        None => {}
+                    }
+                },
+                Ok(None) => {},
+                Err(ref e) if e.kind == IoErrorKind::TimedOut => break,
+                // If we reconnect, carry on reading from the new connection
+                Err(e) => try!(self.connection_lost(e))
+            }
+        }
+
+        try!(self.check_keepalive());
+        try!(self.flush_send_queue(Some(FLUSH_TIMEOUT_MS)));
+        Ok(messages)
+    }
+
+    /// Sends the queued writes, giving up after `timeout_ms` and keeping whatever wasn't sent queued. Without a timeout it
+    /// blocks until everything has been sent.
+    fn flush_send_queue(&mut self, timeout_ms: Option<u64>) -> IoResult<()> {
+        if self.send_queue.is_empty() || !self.is_connected() {
+            return Ok(());
+        }
+
+        // A trailing partial line stays queued until the rest of it is written
+        let lines_end = self.send_queue.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let written = try!(self.sender.send_some(self.send_queue.slice_to(lines_end), self.send_queue_partial, timeout_ms));
+        if written > 0 {
+            self.send_queue_partial = self.send_queue[written - 1] != b'\n';
+            self.send_queue = self.send_queue.slice_from(written).to_vec();
+        }
+
+        if timeout_ms.is_none() && !self.send_queue.is_empty() {
+            // Only a partial line is left, which the sender buffers until the rest of it is written
+            try!(self.sender.write(self.send_queue.as_slice()));
+            self.send_queue.clear();
+        }
+        Ok(())
+    }
+
+    /// Handles `PING`, `CAP` and `ERROR` and updates the client's state, returning the message if the caller should see it.
+    fn handle_message(&mut self, msg: Message<'static>) -> IoResult<Option<Message<'static>>> {
+        self.last_was_echo = false;
+        let keepalive_pong = match self.keepalive {
+            Some(ref mut keepalive) => keepalive.received(now_ms(), &msg),
+            None => false
+        };
+        if keepalive_pong {
+            return Ok(None);
+        }
+
+        match msg.command().as_slice() {
+            "PING" => {
+                match msg.params() {
+                    &Some(ref params) => {
+                        let mut p_iter = params.iter().map(|p| p.as_slice());
+                        try!(self.pong(p_iter.next().unwrap(), p_iter.next()));
+                    },
+                    &None => {
+                        try!(self.write(b"PONG\r\n"));
+                    }
+                }
+                Ok(None)
+            },
+            "CAP" => {
+                let result = self.negotiate_capabilities(msg);
+                // Let the sender know whether it can send client-only tags and labels
+                self.sender.set_client_tags(self.is_cap_enabled("message-tags"));
+                self.sender.set_labeled_response(self.is_cap_enabled("labeled-response"));
+                result
+            },
+            "ERROR" => {
+                let err = IoError {
+                    kind: IoErrorKind::OtherIoError,
+                    desc: "IRC Error",
+                    detail: msg.params().as_ref().map(|p| p[0].to_string())
+                };
+                // The server closing the link after we quit isn't an error
+                if !self.has_quit() {
+                    self.sender.set_status(Error(err.clone()));
+                }
+                self.sender.close();
+                Err(err)
+            }
+            _ => {
+                let duplicate = match self.history {
+                    Some(ref mut history) => !history.record(self.nick.as_slice(), &self.isupport, &msg),
+                    None => false
+                };
+                if duplicate {
+                    return Ok(None);
+                }
+
+                self.track_sent();
+                if self.echoes.is_echo(self.nick.as_slice(), self.isupport.case_mapping(), &msg) {
+                    self.last_was_echo = true;
+                    self.push_sent_event(msg.clone());
+                }
+
+                match (msg.label(), msg.command().as_slice()) {
+                    // A labeled batch is answered once it ends
+                    (Some(_), "BATCH") | (None, _) => {},
+                    (Some(label), "ACK") => self.sender.respond(label, LabeledResponse::Ack),
+                    (Some(label), _) => self.sender.respond(label, LabeledResponse::Message(msg.clone()))
+                }
+
+                match self.batches.process(&msg) {
+                    Some(batch) => {
+                        match batch.label() {
+                            Some(label) => self.sender.respond(label, LabeledResponse::Batch(batch.clone())),
+                            None => {}
+                        }
+                        // Don't let batches pile up forever if nobody is taking them
+                        if self.completed_batches.len() == MAX_COMPLETED_BATCHES {
+                            self.completed_batches.pop_front();
+                        }
+                        self.completed_batches.push_back(batch);
+                    },
+                    None => {}
+                }
+                // Replayed history says nothing about what is happening now
+                if !self.is_history(&msg) {
+                    self.update_state(&msg);
+                    try!(self.catch_up_history(&msg));
+                }
+                try!(self.update_monitor(&msg));
+                try!(self.update_metadata(&msg));
+                try!(self.answer_ctcp(&msg));
+                Ok(Some(msg))
+            }
+        }
+    }
+
+    /// Whether a message was replayed from a `chathistory` batch.
+    fn is_history(&self, msg: &Message<'static>) -> bool {
+        msg.batch().map_or(false, |batch| self.batches.is_within(batch, "chathistory"))
+    }
+
+    /// Fetches what was missed in a conversation while we were disconnected, once we are back in it.
+    fn catch_up_history(&mut self, msg: &Message<'static>) -> IoResult<()> {
+        if self.history.is_none() || !self.is_cap_enabled("draft/chathistory") {
+            return Ok(());
+        }
+
+        let own_join = msg.command().as_slice() == "JOIN" && match *msg.prefix() {
+            Some(ref prefix) => self.isupport.case_mapping().equals(prefix.nick().unwrap_or(""), self.nick.as_slice()),
+            None => false
+        };
+
+        let requests = {
+            let history = self.history.as_mut().unwrap();
+            match (msg.command().as_slice(), msg.params()) {
+                ("JOIN", &Some(ref params)) if own_join => {
+                    match history.catch_up(params[0].as_slice(), &self.isupport) {
+                        Some(selector) => vec![(params[0].to_string(), selector)],
+                        None => Vec::new()
+                    }
+                },
+                // RPL_ENDOFMOTD and ERR_NOMOTD
+                ("376", _) | ("422", _) => history.catch_up_queries(&self.isupport),
+                _ => return Ok(())
+            }
+        };
+
+        let limit = match self.isupport.value("CHATHISTORY").and_then(|limit| from_str::<uint>(limit)) {
+            Some(limit) if limit > 0 => limit,
+            _ => DEFAULT_HISTORY_LIMIT
+        };
+        for &(ref target, ref selector) in requests.iter() {
+            try!(self.chathistory_after(target.as_slice(), selector, limit));
+        }
+        Ok(())
+    }
+
+    /// Lets the sender know the `MONITOR` limit and case mapping, for `monitor_add` and `monitor_remove`.
+    fn share_isupport(&self) {
+        let limit = self.isupport.value("MONITOR").and_then(|limit| from_str::<uint>(limit));
+        self.sender.set_monitor_limits(limit, self.isupport.case_mapping());
+    }
+
+    /// Tracks the `MONITOR` replies, and monitors everything again once a new connection is registered.
+    fn update_monitor(&mut self, msg: &Message<'static>) -> IoResult<()> {
+        match msg.command().as_slice() {
+            // RPL_ENDOFMOTD and ERR_NOMOTD
+            "376" | "422" => {
+                for line in self.sender.monitor_readd_lines().iter() {
+                    try!(write!(self, "{}\r\n", line));
+                }
+            },
+            "730" | "731" | "732" | "734" => {
+                for event in self.sender.update_monitor(msg).into_iter() {
+                    self.push_event(ClientEvent::Monitor(event));
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Keeps the metadata cache up to date, and subscribes to the same keys again once a new connection is registered.
+    fn update_metadata(&mut self, msg: &Message<'static>) -> IoResult<()> {
+        match msg.command().as_slice() {
+            // RPL_ENDOFMOTD and ERR_NOMOTD
+            "376" | "422" => {
+                let keys = self.metadata.take_resubscribe();
+                if !keys.is_empty() && self.is_cap_enabled("draft/metadata-2") {
+                    try!(self.metadata_sub(keys.iter().map(|key| key.as_slice()).collect()));
+                }
+            },
+            _ => {
+                match self.metadata.update(msg, self.isupport.case_mapping()) {
+                    Some(event) => self.push_event(ClientEvent::Metadata(event)),
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a CTCP request, unless it is our own echo or an old request replayed from history.
+    fn answer_ctcp(&mut self, msg: &Message<'static>) -> IoResult<()> {
+        if self.last_was_echo || self.is_history(msg) {
+            return Ok(());
+        }
+
+        let reply = match self.ctcp_responder {
+            Some(ref mut responder) => responder.respond(msg, now_ms()),
+            None => None
+        };
+        match reply {
+            Some((nick, reply)) => self.ctcp_reply(reply.command(), nick.as_slice(), reply.params().unwrap_or("")),
+            None => Ok(())
+        }
+    }
+
+    fn update_state(&mut self, msg: &Message<'static>) {
+        match msg.command().as_slice() {
+            "001" => {
+                self.reconnect_attempts = 0;
+                match *msg.params() {
+                    Some(ref params) => self.nick = params[0].to_string(),
+                    None => {}
+                }
+            },
+            "005" => {
+                self.isupport.update(msg);
+                self.share_isupport();
+            },
+            _ => {}
+        }
+
+        match self.channel_state {
+            Some(ref mut state) => state.update(self.nick.as_slice(), &self.isupport, msg),
+            None => {}
+        }
+
+        // Our own prefix tells us how much room the server's copy of our messages will need
+        let own_prefix = match *msg.prefix() {
+            Some(ref prefix) => self.isupport.case_mapping().equals(prefix.nick().unwrap_or(""), self.nick.as_slice()),
+            None => false
+        };
+        if own_prefix {
+            let prefix = msg.prefix().as_ref().unwrap();
+            match prefix.ident() {
+                Some(user) => self.own_user = Some(user.to_string()),
+                None => {}
+            }
+            match prefix.host_opt() {
+                Some(host) => self.own_host = Some(host.to_string()),
+                None => {}
+            }
+        }
+
+        match (msg.command().as_slice(), msg.params()) {
+            ("CHGHOST", &Some(ref params)) if own_prefix && params.len() > 1 => {
+                self.own_user = Some(params[0].to_string());
+                self.own_host = Some(params[1].to_string());
+            },
+            // RPL_VISIBLEHOST
+            ("396", &Some(ref params)) if params.len() > 1 => {
+                self.own_host = Some(params[1].to_string());
+            },
+            _ => {}
+        }
+
+        if msg.command().as_slice() == "NICK" {
+            let own_nick = match (msg.prefix(), msg.params()) {
+                (&Some(ref prefix), &Some(ref params)) if self.isupport.case_mapping().equals(prefix.nick().unwrap_or(""), self.nick.as_slice()) => {
+                    Some(params[0].to_string())
+                },
+                _ => None
+            };
+            match own_nick {
+                Some(nick) => self.nick = nick,
+                None => {}
+            }
+        }
+    }
+
+    fn negotiate_capabilities(&mut self, msg: Message<'static>) -> IoResult<Option<Message<'static>>> {
+        match *msg.params() {
+            Some(ref params) => {
+                let mut p_iter = params.iter().map(|p| p.as_slice()).skip(1);
+                match p_iter.next() {
+                    Some(sub_cmd) if sub_cmd == "LS" => {
+                        match p_iter.next() {
+                            Some(p) if p == "*" => {
+                                self.cap_partial_listing = true;
+                                match p_iter.next() {
+                                    Some(caps) => {
+                                        for cap in caps.split_terminator(' ').map(|c| Capability::from_str(c)) {
+                                            if cap.is_some() {
+                                                self.available_caps.insert(cap.unwrap());
+                                            }
+                                        }
+                                        Ok(None)
+                                    },
+                                    None => {
+                                        try!(self.cap_end())
+                                        Ok(None)
+                                    }
+                                }
+                            },
+                            Some(p) => {
+                                if self.cap_partial_listing {
+                                    self.cap_partial_listing = false;
+                                }
+
+                                for cap in p.split_terminator(' ').map(|c| Capability::from_str(c)) {
+                                    if cap.is_some() {
+                                        self.available_caps.insert(cap.unwrap());
+                                    }
+                                }
+
+                                // FIXME(JA): We should break this in to smaller chunks to make sure we don't exceed the IRC line length.
+                                let requested_caps: Vec<Capability> = self.wanted_caps.intersection(&self.available_caps).map(|c| c.clone()).collect();
+                                try!(self.cap_req(&requested_caps));
+                                self.requested_caps = requested_caps.into_iter().collect();
+                                Ok(None)
+                            },
                             None => {
                                 try!(self.cap_end())
                                 Ok(None)
@@ -231,6 +1161,8 @@ impl BasicClient {
                                 if self.enabled_caps == self.requested_caps {
                                     try!(self.cap_end());
                                 }
+                                // Let `read_event` callers and dispatchers know which capabilities are now enabled
+                                self.push_event(ClientEvent::CapAck(msg.clone()));
                                 Ok(None)
                             },
                             None => {
@@ -325,9 +1257,19 @@ impl BasicClient {
 }
 
 #[experimental]
-impl<A: ToSocketAddr> BasicClient {
+impl BasicClient<TcpStream> {
+    /// The socket's file descriptor, for registering with `poll`, `epoll` or similar.
+    #[cfg(unix)]
+    #[experimental]
+    pub fn as_raw_fd(&self) -> Option<Fd> {
+        self.connection.as_ref().map(|con| con.get_ref().as_raw_fd())
+    }
+}
+
+#[experimental]
+impl<A: ToSocketAddr> BasicClient<TcpStream> {
     pub fn connect_to(&mut self, addr: A) -> IoResult<()> {
-        match self.status {
+        match self.status() {
             Connected | Connecting => {
                 Err(IoError {
                     kind: IoErrorKind::OtherIoError,
@@ -340,20 +1282,22 @@ impl<A: ToSocketAddr> BasicClient {
                     Ok(mut con) => {
                         match con.get_stream().peer_name() {
                             Ok(addr) => {
+                                self.sender.attach(con.get_ref().clone(), Connecting);
                                 self.remote_addr = Some(addr);
                                 self.connection = Some(con);
-                                self.status = Connecting;
-                                
+                                let timeout = self.read_timeout;
+                                self.apply_read_timeout(timeout);
+
                                 self.register()
                             }
                             Err(e) => {
-                                self.status = Error(e.clone());
+                                self.sender.set_status(Error(e.clone()));
                                 Err(e)
                             }
                         }
                     },
                     Err(e) => {
-                        self.status = Error(e.clone());
+                        self.sender.set_status(Error(e.clone()));
                         Err(e)
                     }
                 }
@@ -362,7 +1306,7 @@ impl<A: ToSocketAddr> BasicClient {
     }
 
     pub fn connect_to_timeout(&mut self, addr: A, timeout: Duration) -> IoResult<()> {
-        match self.status {
+        match self.status() {
             Connected | Connecting => {
                 Err(IoError {
                     kind: IoErrorKind::OtherIoError,
@@ -375,20 +1319,22 @@ impl<A: ToSocketAddr> BasicClient {
                     Ok(mut con) => {
                         match con.get_stream().peer_name() {
                             Ok(addr) => {
+                                self.sender.attach(con.get_ref().clone(), Connected);
                                 self.remote_addr = Some(addr);
                                 self.connection = Some(con);
-                                self.status = Connected;
-                                
+                                let timeout = self.read_timeout;
+                                self.apply_read_timeout(timeout);
+
                                 self.register()
                             }
                             Err(e) => {
-                                self.status = Error(e.clone());
+                                self.sender.set_status(Error(e.clone()));
                                 Err(e)
                             }
                         }
                     },
                     Err(e) => {
-                        self.status = Error(e.clone());
+                        self.sender.set_status(Error(e.clone()));
                         Err(e)
                     }
                 }
@@ -398,76 +1344,892 @@ impl<A: ToSocketAddr> BasicClient {
 }
 
 #[experimental]
-impl IrcMethods for BasicClient {
+impl<T: IrcStream> IrcMethods for BasicClient<T> {
+    #[inline]
+    fn client_tags_enabled(&self) -> bool {
+        self.is_cap_enabled("message-tags")
+    }
+
     fn quit(&mut self, message: Option<&str>) -> IoResult<()> {
-        match self.status {
-            Connected | Connecting => {
-                match self.connection.as_mut().unwrap().quit(message) {
-                    Ok(_) => {
-                        self.status = Disconnected;
-                        Ok(())
-                    },
-                    err => err
-                }
-            },
-            _ => {
-                Err(IoError {
-                    kind: IoErrorKind::NotConnected,
-                    desc: "Not connected",
-                    detail: None
-                })
-            }
+        if !self.is_connected() {
+            return Err(not_connected());
+        }
+
+        try!(self.flush_send_queue(None));
+        self.sender.quit(message)
+    }
+
+    /// Monitors as many of `targets` as the server's `MONITOR` limit allows. Those that don't fit are reported with a
+    /// `ListFull` event.
+    fn monitor_add(&mut self, targets: Vec<&str>) -> IoResult<()> {
+        for line in self.sender.monitor_add_lines(targets.as_slice()).iter() {
+            try!(write!(self, "{}\r\n", line));
         }
+        Ok(())
+    }
+
+    fn monitor_remove(&mut self, targets: Vec<&str>) -> IoResult<()> {
+        for line in self.sender.monitor_remove_lines(targets.as_slice()).iter() {
+            try!(write!(self, "{}\r\n", line));
+        }
+        Ok(())
+    }
+
+    fn monitor_clear(&mut self) -> IoResult<()> {
+        self.sender.shared.lock().monitor.clear();
+        self.write(b"MONITOR C\r\n")
     }
 }
 
 #[stable]
-impl Writer for BasicClient {
+impl<T: IrcStream> Writer for BasicClient<T> {
     fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if self.nonblocking && self.is_connected() {
+            self.send_queue.push_all(buf);
+            Ok(())
+        } else {
+            self.sender.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        try!(self.flush_send_queue(None));
+        self.sender.flush()
+    }
+}
+
+impl<T: IrcStream> Drop for BasicClient<T> {
+    fn drop(&mut self) {
+        let _ = self.quit(Some("rust-relay"));
+    }
+}
+
+/// The reading half of a split `BasicClient`. Like the client itself, it quits when dropped.
+#[experimental]
+pub struct ClientReader<T: IrcStream = TcpStream> {
+    client: BasicClient<T>
+}
+
+#[experimental]
+impl<T: IrcStream> ClientReader<T> {
+    /// Reads the next message, answering `PING`s and negotiating capabilities as `BasicClient::read_message` does.
+    #[experimental]
+    #[inline]
+    pub fn read_message(&mut self) -> IoResult<Option<Message<'static>>> {
+        self.client.read_message()
+    }
+
+    /// Reads the next message or completed batch, as `BasicClient::read_event` does.
+    #[experimental]
+    #[inline]
+    pub fn read_event(&mut self) -> IoResult<Option<ClientEvent>> {
+        self.client.read_event()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn status(&self) -> ConnectionStatus {
+        self.client.status()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    /// The client being read from, for its nick, `ISUPPORT` tokens, capabilities and channel state.
+    #[experimental]
+    #[inline]
+    pub fn get_ref<'a>(&'a self) -> &'a BasicClient<T> {
+        &self.client
+    }
+}
+
+struct SharedState<T> {
+    stream: Option<T>,
+    status: ConnectionStatus,
+    queue: Option<SendQueue>,
+    draining: bool,
+    client_tags: bool,
+    labeled_response: bool,
+    labels: PendingLabels,
+    sent: RingBuf<Message<'static>>,
+    monitor: Monitor,
+    monitor_limit: Option<uint>,
+    monitor_refused: Vec<String>,
+    case_mapping: CaseMapping
+}
+
+impl<T: IrcStream> SharedState<T> {
+    fn is_connected(&self) -> bool {
         match self.status {
-            Connected | Connecting => {
-                match self.connection.as_mut().unwrap().write(buf) {
-                    Err(e) => {
-                        self.status = Error(e.clone());
-                        Err(e)
+            Connected | Connecting => true,
+            _ => false
+        }
+    }
+
+    /// Writes every queued line that the token bucket allows, returning how long until the next one can go.
+    fn drain(&mut self) -> IoResult<Option<u64>> {
+        let now = now_ms();
+        let mut written = Vec::new();
+        let result = {
+            let (queue, stream) = match (self.queue.as_mut(), self.stream.as_mut()) {
+                (Some(queue), Some(stream)) => (queue, stream),
+                _ => return Ok(None)
+            };
+
+            let mut result: IoResult<()> = Ok(());
+            loop {
+                match queue.pop(now) {
+                    Some(line) => {
+                        match stream.write(line.as_slice()) {
+                            Ok(()) => written.push(line),
+                            Err(e) => {
+                                result = Err(e);
+                                break;
+                            }
+                        }
                     },
-                    ok => ok
+                    None => break
                 }
-            },
-            _ => {
-                Err(IoError {
-                    kind: IoErrorKind::NotConnected,
-                    desc: "Not connected",
-                    detail: None
-                })
             }
+
+            if result.is_ok() && !written.is_empty() {
+                result = stream.flush();
+            }
+            result.map(|_| queue.next_ready_in(now))
+        };
+
+        // Lines are only remembered for their echoes once they have been written
+        for line in written.iter() {
+            self.record_sent(line.as_slice(), line.len(), false);
         }
+        result
     }
 
-    fn flush(&mut self) -> IoResult<()> {
-        match self.status {
-            Connected | Connecting => {
-                match self.connection.as_mut().unwrap().flush() {
-                    Err(e) => {
-                        self.status = Error(e.clone());
-                        Err(e)
+    /// Remembers our messages that had their first byte among the `written` bytes of `data` sent, so that the client can
+    /// match up their echoes. If `partial`, the first line was begun, and remembered, by an earlier write.
+    fn record_sent(&mut self, data: &[u8], written: uint, partial: bool) {
+        if written == 0 {
+            return;
+        }
+
+        let start = if partial {
+            match data.iter().position(|&b| b == b'\n') {
+                Some(i) => i + 1,
+                None => return
+            }
+        } else {
+            0
+        };
+        let end = if data[written - 1] == b'\n' {
+            written
+        } else {
+            data.slice_from(written).iter().position(|&b| b == b'\n').map_or(data.len(), |i| written + i + 1)
+        };
+        if start >= end {
+            return;
+        }
+
+        for msg in outgoing_messages(data.slice(start, end)).into_iter() {
+            if self.sent.len() == MAX_SENT_LINES {
+                self.sent.pop_front();
+            }
+            self.sent.push_back(msg);
+        }
+    }
+}
+
+/// The writing half of a `BasicClient`. Clones share the connection, and each line is written whole, so clones can be used
+/// from several tasks at once without their lines being interleaved.
+#[experimental]
+pub struct ClientSender<T: IrcStream = TcpStream> {
+    shared: Arc<Mutex<SharedState<T>>>,
+    line: Vec<u8>
+}
+
+impl<T: IrcStream> ClientSender<T> {
+    fn new() -> ClientSender<T> {
+        ClientSender {
+            shared: Arc::new(Mutex::new(SharedState {
+                stream: None,
+                status: NotConnected,
+                queue: None,
+                draining: false,
+                client_tags: false,
+                labeled_response: false,
+                labels: PendingLabels::new(),
+                sent: RingBuf::new(),
+                monitor: Monitor::new(),
+                monitor_limit: None,
+                monitor_refused: Vec::new(),
+                case_mapping: CaseMapping::Rfc1459
+            })),
+            line: Vec::new()
+        }
+    }
+
+    #[experimental]
+    pub fn status(&self) -> ConnectionStatus {
+        self.shared.lock().status.clone()
+    }
+
+    #[experimental]
+    pub fn is_connected(&self) -> bool {
+        self.shared.lock().is_connected()
+    }
+
+    /// Sends lines through a token bucket queue rather than straight away, or stops doing so if `config` is `None`, in which
+    /// case anything still queued is sent immediately.
+    #[experimental]
+    pub fn set_flood_control(&self, config: Option<FloodControl>) -> IoResult<()> {
+        let mut shared = self.shared.lock();
+
+        match config {
+            Some(config) => {
+                if shared.queue.is_some() {
+                    shared.queue.as_mut().unwrap().set_config(config);
+                } else {
+                    shared.queue = Some(SendQueue::new(config));
+                }
+                Ok(())
+            },
+            None => {
+                let lines = match shared.queue.take() {
+                    Some(mut queue) => queue.take_all(),
+                    None => return Ok(())
+                };
+                if lines.is_empty() {
+                    return Ok(());
+                }
+
+                let result = match shared.stream {
+                    Some(ref mut stream) => {
+                        for line in lines.iter() {
+                            try!(stream.write(line.as_slice()));
+                        }
+                        stream.flush()
                     },
-                    ok => ok
+                    None => return Ok(())
+                };
+                for line in lines.iter() {
+                    shared.record_sent(line.as_slice(), line.len(), false);
                 }
+                result
+            }
+        }
+    }
+
+    /// How many lines are waiting for the flood control queue.
+    #[experimental]
+    pub fn queued_lines(&self) -> uint {
+        self.shared.lock().queue.as_ref().map_or(0, |queue| queue.len())
+    }
+
+    fn attach(&self, stream: T, status: ConnectionStatus) {
+        let mut shared = self.shared.lock();
+        shared.stream = Some(stream);
+        shared.status = status;
+    }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        self.shared.lock().status = status;
+    }
+
+    fn set_client_tags(&self, enabled: bool) {
+        self.shared.lock().client_tags = enabled;
+    }
+
+    fn set_labeled_response(&self, enabled: bool) {
+        let mut shared = self.shared.lock();
+        shared.labeled_response = enabled;
+        if !enabled {
+            shared.labels.clear();
+        }
+    }
+
+    /// Labels `line` if the server supports `labeled-response`.
+    fn label_line(&self, line: &str) -> (String, Option<ResponseHandle>) {
+        let line = line.trim_right_chars(['\r', '\n'].as_slice());
+        let mut shared = self.shared.lock();
+
+        if shared.labeled_response {
+            let handle = shared.labels.register();
+            (add_label(line, handle.label()), Some(handle))
+        } else {
+            (String::from_str(line), None)
+        }
+    }
+
+    fn respond(&self, label: &str, response: LabeledResponse) {
+        self.shared.lock().labels.respond(label, response);
+    }
+
+    fn set_monitor_limits(&self, limit: Option<uint>, case_mapping: CaseMapping) {
+        let mut shared = self.shared.lock();
+        shared.monitor_limit = limit;
+        shared.case_mapping = case_mapping;
+    }
+
+    /// Starts monitoring as many of `targets` as the server's limit allows, returning the `MONITOR +` lines to send. The
+    /// targets that don't fit are kept for the client to report.
+    fn monitor_add_lines(&self, targets: &[&str]) -> Vec<String> {
+        let mut shared = self.shared.lock();
+        let limit = shared.monitor_limit;
+        let case_mapping = shared.case_mapping.clone();
+        let (added, refused) = shared.monitor.add(targets, limit, case_mapping);
+        shared.monitor_refused.extend(refused.into_iter());
+
+        let added: Vec<&str> = added.iter().map(|nick| nick.as_slice()).collect();
+        monitor_lines("+", added.as_slice())
+    }
+
+    /// Stops monitoring `targets`, returning the `MONITOR -` lines for those that were being monitored.
+    fn monitor_remove_lines(&self, targets: &[&str]) -> Vec<String> {
+        let mut shared = self.shared.lock();
+        let case_mapping = shared.case_mapping.clone();
+        let removed = shared.monitor.remove(targets, case_mapping);
+
+        let removed: Vec<&str> = removed.iter().map(|nick| nick.as_slice()).collect();
+        monitor_lines("-", removed.as_slice())
+    }
+
+    fn take_monitor_refused(&self) -> Vec<String> {
+        let mut shared = self.shared.lock();
+        mem::replace(&mut shared.monitor_refused, Vec::new())
+    }
+
+    fn update_monitor(&self, msg: &Message<'static>) -> Vec<MonitorEvent> {
+        let mut shared = self.shared.lock();
+        let case_mapping = shared.case_mapping.clone();
+        shared.monitor.update(msg, case_mapping)
+    }
+
+    fn monitor_reconnected(&self) {
+        self.shared.lock().monitor.reconnected();
+    }
+
+    fn monitor_readd_lines(&self) -> Vec<String> {
+        self.shared.lock().monitor.take_readd()
+    }
+
+    /// Sends a raw line with a `label` tag and returns a handle for the server's response, or sends it as it is and returns
+    /// `None` if `labeled-response` isn't enabled.
+    #[experimental]
+    pub fn request(&mut self, line: &str) -> IoResult<Option<ResponseHandle>> {
+        let (line, handle) = self.label_line(line);
+        try!(self.write(format!("{}\r\n", line).as_bytes()));
+        Ok(handle)
+    }
+
+    fn close(&self) {
+        match self.shared.lock().stream {
+            Some(ref mut stream) => { let _ = stream.close(); },
+            None => {}
+        }
+    }
+
+    /// Writes complete lines to the stream, or to the flood control queue, while holding the lock.
+    fn send(&self, data: &[u8]) -> IoResult<()> {
+        let mut shared = self.shared.lock();
+        if !shared.is_connected() {
+            return Err(not_connected());
+        }
+
+        let queued = shared.queue.is_some();
+        let result = if queued {
+            shared.queue.as_mut().unwrap().push_lines(data);
+            match shared.drain() {
+                Ok(Some(_)) => {
+                    self.start_draining(&mut *shared);
+                    Ok(())
+                },
+                Ok(None) => Ok(()),
+                Err(e) => Err(e)
+            }
+        } else {
+            match shared.stream {
+                Some(ref mut stream) => stream.write(data).and_then(|_| stream.flush()),
+                None => return Err(not_connected())
+            }
+        };
+
+        match result {
+            Err(e) => {
+                shared.status = Error(e.clone());
+                Err(e)
             },
-            _ => {
-                Err(IoError {
-                    kind: IoErrorKind::NotConnected,
-                    desc: "Not connected",
-                    detail: None
-                })
+            ok => {
+                // Queued lines are remembered by `drain` when they are written
+                if !queued {
+                    shared.record_sent(data, data.len(), false);
+                }
+                ok
             }
         }
     }
+
+    /// Writes as much of `data` as the stream takes within `timeout_ms`, returning how many bytes went out. If `partial`,
+    /// `data` starts with the rest of a line whose beginning has already been written.
+    fn send_some(&self, data: &[u8], partial: bool, timeout_ms: Option<u64>) -> IoResult<uint> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let mut shared = self.shared.lock();
+        if !shared.is_connected() {
+            return Err(not_connected());
+        }
+
+        // The flood control queue takes whole lines without blocking, but the rest of a half-written line has to go
+        // straight after its beginning
+        let direct_end = if shared.queue.is_none() {
+            data.len()
+        } else if partial {
+            data.iter().position(|&b| b == b'\n').map_or(data.len(), |i| i + 1)
+        } else {
+            0
+        };
+        if direct_end == 0 {
+            drop(shared);
+            return self.send(data).map(|_| data.len());
+        }
+
+        let result = match shared.stream {
+            Some(ref mut stream) => {
+                stream.set_write_timeout(timeout_ms);
+                let result = match stream.write(data.slice_to(direct_end)).and_then(|_| stream.flush()) {
+                    Ok(()) => Ok(direct_end),
+                    Err(IoError { kind: IoErrorKind::ShortWrite(written), .. }) => Ok(written),
+                    Err(ref e) if e.kind == IoErrorKind::TimedOut => Ok(0),
+                    Err(e) => Err(e)
+                };
+                stream.set_write_timeout(None);
+                result
+            },
+            None => return Err(not_connected())
+        };
+
+        let written = match result {
+            Ok(written) => written,
+            Err(e) => {
+                shared.status = Error(e.clone());
+                return Err(e);
+            }
+        };
+        shared.record_sent(data.slice_to(direct_end), written, partial);
+
+        if written == direct_end && direct_end < data.len() {
+            drop(shared);
+            try!(self.send(data.slice_from(direct_end)));
+            return Ok(data.len());
+        }
+        Ok(written)
+    }
+
+    fn take_sent(&self) -> Vec<Message<'static>> {
+        let mut shared = self.shared.lock();
+        let mut sent = Vec::new();
+        loop {
+            match shared.sent.pop_front() {
+                Some(msg) => sent.push(msg),
+                None => break
+            }
+        }
+        sent
+    }
+
+    /// Starts a task that sends queued lines as the token bucket refills, unless one is already running.
+    fn start_draining(&self, shared: &mut SharedState<T>) {
+        if shared.draining {
+            return;
+        }
+        shared.draining = true;
+
+        let shared = self.shared.clone();
+        spawn(proc() {
+            loop {
+                let wait = {
+                    let mut state = shared.lock();
+                    let result = if state.is_connected() { state.drain() } else { Ok(None) };
+                    match result {
+                        Ok(Some(wait)) => wait,
+                        Ok(None) => {
+                            state.draining = false;
+                            return;
+                        },
+                        Err(e) => {
+                            state.status = Error(e);
+                            state.draining = false;
+                            return;
+                        }
+                    }
+                };
+                sleep(Duration::milliseconds(wait as i64));
+            }
+        });
+    }
 }
 
-impl Drop for BasicClient {
-    fn drop(&mut self) {
-        let _ = self.quit(Some("rust-relay"));
+impl<T: IrcStream> Clone for ClientSender<T> {
+    fn clone(&self) -> ClientSender<T> {
+        ClientSender {
+            shared: self.shared.clone(),
+            line: Vec::new()
+        }
+    }
+}
+
+#[experimental]
+impl<T: IrcStream> IrcMethods for ClientSender<T> {
+    #[inline]
+    fn client_tags_enabled(&self) -> bool {
+        self.shared.lock().client_tags
+    }
+
+    fn quit(&mut self, message: Option<&str>) -> IoResult<()> {
+        try!(self.__quit(message));
+
+        let mut shared = self.shared.lock();
+        shared.status = Disconnected;
+        match shared.stream {
+            Some(ref mut stream) => stream.close(),
+            None => Ok(())
+        }
+    }
+
+    /// Monitors as many of `targets` as the server's `MONITOR` limit allows. Those that don't fit are reported by the
+    /// reader with a `ListFull` event.
+    fn monitor_add(&mut self, targets: Vec<&str>) -> IoResult<()> {
+        for line in self.monitor_add_lines(targets.as_slice()).iter() {
+            try!(write!(self, "{}\r\n", line));
+        }
+        Ok(())
+    }
+
+    fn monitor_remove(&mut self, targets: Vec<&str>) -> IoResult<()> {
+        for line in self.monitor_remove_lines(targets.as_slice()).iter() {
+            try!(write!(self, "{}\r\n", line));
+        }
+        Ok(())
+    }
+
+    fn monitor_clear(&mut self) -> IoResult<()> {
+        self.shared.lock().monitor.clear();
+        self.write(b"MONITOR C\r\n")
+    }
+}
+
+#[experimental]
+impl<T: IrcStream> Writer for ClientSender<T> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if !self.is_connected() {
+            return Err(not_connected());
+        }
+
+        self.line.push_all(buf);
+        // Hold back any unfinished line until the rest of it is written
+        match self.line.iter().rposition(|&b| b == b'\n') {
+            Some(end) => {
+                let rest = self.line.slice_from(end + 1).to_vec();
+                self.line.truncate(end + 1);
+                let result = self.send(self.line.as_slice());
+                self.line = rest;
+                result
+            },
+            None => Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self.shared.lock().stream {
+            Some(ref mut stream) => stream.flush(),
+            None => Err(not_connected())
+        }
+    }
+}
+
+#[inline]
+fn now_ms() -> u64 {
+    precise_time_ns() / 1_000_000
+}
+
+fn not_connected() -> IoError {
+    IoError {
+        kind: IoErrorKind::NotConnected,
+        desc: "Not connected",
+        detail: None
+    }
+}
+
+#[test]
+fn split_halves_share_connection() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+
+    let mut server = BufferedReader::new(server_end.clone());
+    let mut server_out = server_end;
+    for _ in range(0u, 3) {
+        server.read_line().unwrap();
+    }
+
+    let (mut reader, mut sender) = client.split();
+    let mut other = sender.clone();
+
+    server_out.write(b"PING :token\r\n:alice!a@host PRIVMSG relay :hello\r\n").unwrap();
+    assert!(reader.read_message().unwrap().unwrap().command().as_slice() == "PRIVMSG");
+    assert!(server.read_line().unwrap().as_slice() == "PONG token\r\n");
+
+    sender.privmsg("alice", "hi").unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "PRIVMSG alice :hi\r\n");
+
+    other.quit(Some("bye")).unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "QUIT :bye\r\n");
+    assert!(!reader.is_connected());
+    assert!(sender.privmsg("alice", "too late").is_err());
+}
+
+#[test]
+fn batches_are_grouped() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+
+    let mut server_out = server_end;
+    server_out.write(b":irc.example.com BATCH +ns netsplit a.hub b.hub\r\n").unwrap();
+    server_out.write(b"@batch=ns :alice!a@host QUIT :a.hub b.hub\r\n").unwrap();
+    server_out.write(b":bob!b@host PRIVMSG relay :hi\r\n").unwrap();
+    server_out.write(b":irc.example.com BATCH -ns\r\n").unwrap();
+    server_out.write(b":bob!b@host PRIVMSG relay :bye\r\n").unwrap();
+    server_out.write(b"@batch=unknown :carol!c@host PRIVMSG relay :lost\r\n").unwrap();
+
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Message(msg) => assert!(msg.params().as_ref().unwrap()[1].as_slice() == "hi"),
+        _ => panic!("expected a message")
+    }
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Batch(batch) => assert!(batch.batch_type() == "netsplit" && batch.messages().len() == 1),
+        _ => panic!("expected a batch")
+    }
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Message(msg) => assert!(msg.params().as_ref().unwrap()[1].as_slice() == "bye"),
+        _ => panic!("expected a message")
+    }
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Message(msg) => assert!(msg.params().as_ref().unwrap()[1].as_slice() == "lost"),
+        _ => panic!("expected a message")
+    }
+}
+
+#[test]
+fn replayed_history_is_not_live() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.track_channels();
+    client.connect_stream(client_end).unwrap();
+
+    let mut server_out = server_end;
+    server_out.write(b":irc.example.com BATCH +h chathistory #old\r\n").unwrap();
+    server_out.write(b"@batch=h;time=2011-10-19T16:40:51.620Z :relay!r@host JOIN #old\r\n").unwrap();
+    server_out.write(b":irc.example.com BATCH -h\r\n").unwrap();
+    server_out.write(b":relay!r@host JOIN #new\r\n").unwrap();
+    for _ in range(0u, 4) {
+        client.read_message().unwrap();
+    }
+
+    let channels = client.channel_state().unwrap();
+    assert!(!channels.is_joined("#old") && channels.is_joined("#new"));
+}
+
+#[test]
+fn labeled_requests() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+    client.sender.set_labeled_response(true);
+
+    let mut server = BufferedReader::new(server_end.clone());
+    let mut server_out = server_end;
+    for _ in range(0u, 3) {
+        server.read_line().unwrap();
     }
+
+    let whois = client.request("WHOIS alice").unwrap().unwrap();
+    assert!(server.read_line().unwrap() == format!("@label={} WHOIS alice\r\n", whois.label()));
+    let away = client.request("AWAY :lunch").unwrap().unwrap();
+    server.read_line().unwrap();
+
+    server_out.write(format!("@label={} :irc.example.com 306 relay :You have been marked as away\r\n", away.label()).as_bytes()).unwrap();
+    server_out.write(format!("@label={} :irc.example.com BATCH +w labeled-response\r\n", whois.label()).as_bytes()).unwrap();
+    server_out.write(b"@batch=w :irc.example.com 311 relay alice a host * :Alice\r\n").unwrap();
+    server_out.write(b":irc.example.com BATCH -w\r\n").unwrap();
+    for _ in range(0u, 4) {
+        client.read_message().unwrap();
+    }
+
+    match away.try_get() {
+        Some(LabeledResponse::Message(msg)) => assert!(msg.command().as_slice() == "306"),
+        _ => panic!("expected a single reply")
+    }
+    match whois.wait(Some(0)) {
+        Ok(LabeledResponse::Batch(batch)) => assert!(batch.messages().len() == 1),
+        _ => panic!("expected a batch")
+    }
+}
+
+#[test]
+fn local_echo_without_echo_message() {
+    let (client_end, _server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+
+    client.privmsg("#chan", "hello").unwrap();
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Sent(msg) => {
+            assert!(msg.prefix().as_ref().unwrap().nick() == Some("relay"));
+            assert!(msg.params().as_ref().unwrap()[1].as_slice() == "hello");
+            assert!(msg.server_time().is_some());
+        },
+        _ => panic!("expected our own message")
+    }
+}
+
+#[test]
+fn monitor_respects_limit() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+
+    let mut server = BufferedReader::new(server_end.clone());
+    let mut server_out = server_end;
+    for _ in range(0u, 3) {
+        server.read_line().unwrap();
+    }
+
+    server_out.write(b":irc.example.com 005 relay MONITOR=1 :are supported by this server\r\n").unwrap();
+    client.read_message().unwrap();
+    client.monitor_add(vec!["alice", "bob"]).unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "MONITOR + alice\r\n");
+
+    server_out.write(b":irc.example.com 730 relay :alice!a@host\r\n").unwrap();
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Monitor(MonitorEvent::ListFull(refused)) => assert!(refused == vec![String::from_str("bob")]),
+        _ => panic!("expected bob to be refused")
+    }
+    client.read_event().unwrap();
+    match client.read_event().unwrap().unwrap() {
+        ClientEvent::Monitor(MonitorEvent::Online(nick)) => assert!(nick.as_slice() == "alice"),
+        _ => panic!("expected alice to be online")
+    }
+}
+
+#[test]
+fn sender_monitors_for_reader() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+
+    let mut server = BufferedReader::new(server_end.clone());
+    let mut server_out = server_end;
+    for _ in range(0u, 3) {
+        server.read_line().unwrap();
+    }
+
+    server_out.write(b":irc.example.com 005 relay MONITOR=1 :are supported by this server\r\n").unwrap();
+    client.read_message().unwrap();
+    let (mut reader, mut sender) = client.split();
+    sender.monitor_add(vec!["alice", "bob"]).unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "MONITOR + alice\r\n");
+
+    match reader.read_event().unwrap().unwrap() {
+        ClientEvent::Monitor(MonitorEvent::ListFull(refused)) => assert!(refused == vec![String::from_str("bob")]),
+        _ => panic!("expected bob to be refused")
+    }
+    assert!(reader.get_ref().monitor().is_monitored("Alice", CaseMapping::Rfc1459));
+
+    sender.monitor_remove(vec!["alice"]).unwrap();
+    assert!(server.read_line().unwrap().as_slice() == "MONITOR - alice\r\n");
+    assert!(reader.get_ref().monitor().targets().is_empty());
+}
+
+#[test]
+fn ctcp_responder() {
+    let (client_end, server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+    client.set_ctcp_responder(Some(CtcpResponder::new("relay 0.1")));
+
+    let mut server = BufferedReader::new(server_end.clone());
+    let mut server_out = server_end;
+    for _ in range(0u, 3) {
+        server.read_line().unwrap();
+    }
+
+    // Requests replayed from history were answered long ago
+    server_out.write(b":irc.example.com BATCH +h chathistory relay\r\n").unwrap();
+    server_out.write(b"@batch=h;time=2011-10-19T16:40:51.620Z :alice!a@host PRIVMSG relay :\x01VERSION\x01\r\n").unwrap();
+    server_out.write(b":irc.example.com BATCH -h\r\n").unwrap();
+    server_out.write(b":alice!a@host PRIVMSG relay :\x01PING 1234\x01\r\n").unwrap();
+    for _ in range(0u, 4) {
+        client.read_message().unwrap();
+    }
+    assert!(server.read_line().unwrap().as_slice() == "NOTICE alice :\x01PING 1234\x01\r\n");
+}
+
+#[test]
+fn no_reconnect_after_quit() {
+    let (client_end, _server_end) = DuplexPipe::new();
+    let mut client: BasicClient<DuplexPipe> = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.connect_stream(client_end).unwrap();
+    client.remote_addr = from_str("127.0.0.1:6667");
+    client.set_auto_reconnect(true);
+
+    client.quit(Some("bye")).unwrap();
+    let err = client.connection_lost(IoError {
+        kind: IoErrorKind::EndOfFile,
+        desc: "end of file",
+        detail: None
+    }).unwrap_err();
+
+    // Reopening a pipe fails, so trying to reconnect would have left an error status
+    assert!(err.kind == IoErrorKind::EndOfFile);
+    match client.status() {
+        Disconnected => {},
+        status => panic!("unexpected status {}", status)
+    }
+}
+
+#[test]
+fn reconnect_retries_after_failure() {
+    let mut acceptor = TcpListener::bind("127.0.0.1:0").listen().unwrap();
+    let addr = acceptor.socket_name().unwrap();
+    let mut client: BasicClient = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    client.set_auto_reconnect(true);
+    client.connect_to(addr).unwrap();
+
+    // With nothing listening any more, the first attempt to reconnect is refused
+    drop(acceptor.accept().unwrap());
+    drop(acceptor);
+    assert!(client.read_message().is_err());
+    match client.status() {
+        Error(_) => {},
+        status => panic!("unexpected status {}", status)
+    }
+
+    let acceptor = TcpListener::bind(addr).listen().unwrap();
+    spawn(proc() {
+        let mut acceptor = acceptor;
+        let mut server_out = acceptor.accept().unwrap();
+        let mut server = BufferedReader::new(server_out.clone());
+        for _ in range(0u, 3) {
+            server.read_line().unwrap();
+        }
+        server_out.write(b":irc.example.com 001 relay :Welcome\r\n").unwrap();
+    });
+
+    // The next read waits out the backoff and tries again
+    assert!(client.read_message().unwrap().unwrap().command().as_slice() == "001");
+    assert!(client.reconnect_attempts == 0);
 }
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use message::Message;
+
+/// A completed `BATCH`: its type and parameters, the messages tagged with its reference in the order they arrived, and
+/// any batches nested inside it.
+#[experimental]
+#[deriving(Clone)]
+pub struct Batch {
+    reference: String,
+    batch_type: String,
+    params: Vec<String>,
+    start: Message<'static>,
+    messages: Vec<Message<'static>>,
+    nested: Vec<Batch>
+}
+
+#[experimental]
+impl Batch {
+    /// The reference tag the server chose, without the `+` or `-`.
+    #[experimental]
+    #[inline]
+    pub fn reference<'a>(&'a self) -> &'a str {
+        self.reference.as_slice()
+    }
+
+    /// The batch type, e.g. `netsplit` or `chathistory`.
+    #[experimental]
+    #[inline]
+    pub fn batch_type<'a>(&'a self) -> &'a str {
+        self.batch_type.as_slice()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn params<'a>(&'a self) -> &'a [String] {
+        self.params.as_slice()
+    }
+
+    /// The label of the command this batch answers, from `labeled-response`.
+    #[experimental]
+    #[inline]
+    pub fn label<'a>(&'a self) -> Option<&'a str> {
+        self.start.label()
+    }
+
+    /// The `BATCH +reference` line that opened the batch, which carries the tags for the batch as a whole.
+    #[experimental]
+    #[inline]
+    pub fn start<'a>(&'a self) -> &'a Message<'static> {
+        &self.start
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn messages<'a>(&'a self) -> &'a [Message<'static>] {
+        self.messages.as_slice()
+    }
+
+    /// Batches that were opened inside this one, in the order they were closed.
+    #[experimental]
+    #[inline]
+    pub fn nested<'a>(&'a self) -> &'a [Batch] {
+        self.nested.as_slice()
+    }
+}
+
+/// Collects batched messages until their `BATCH -reference` arrives.
+#[experimental]
+pub struct BatchTracker {
+    open: HashMap<String, (Batch, Option<String>)>
+}
+
+#[experimental]
+impl BatchTracker {
+    #[experimental]
+    pub fn new() -> BatchTracker {
+        BatchTracker {
+            open: HashMap::new()
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_open(&self, reference: &str) -> bool {
+        self.open.contains_key(&String::from_str(reference))
+    }
+
+    /// Whether the open batch `reference`, or a batch it is nested in, is of type `batch_type`.
+    #[experimental]
+    pub fn is_within(&self, reference: &str, batch_type: &str) -> bool {
+        let mut reference = String::from_str(reference);
+        loop {
+            let parent = match self.open.get(&reference) {
+                Some(&(ref batch, ref parent)) => {
+                    if batch.batch_type.as_slice() == batch_type {
+                        return true;
+                    }
+                    match *parent {
+                        Some(ref parent) => parent.clone(),
+                        None => return false
+                    }
+                },
+                None => return false
+            };
+            reference = parent;
+        }
+    }
+
+    /// Forgets every open batch, e.g. after reconnecting.
+    #[experimental]
+    pub fn clear(&mut self) {
+        self.open.clear();
+    }
+
+    /// Records a message, returning the batch it completes. Batches closed inside another batch are added to their parent
+    /// instead of being returned.
+    #[experimental]
+    pub fn process(&mut self, msg: &Message<'static>) -> Option<Batch> {
+        let params = match (msg.command().as_slice(), msg.params()) {
+            ("BATCH", &Some(ref params)) => params,
+            ("BATCH", &None) => return None,
+            _ => {
+                match msg.batch() {
+                    Some(reference) => {
+                        match self.open.get_mut(&String::from_str(reference)) {
+                            Some(&(ref mut batch, _)) => batch.messages.push(msg.clone()),
+                            None => {}
+                        }
+                    },
+                    None => {}
+                }
+                return None;
+            }
+        };
+
+        let reference = params[0].as_slice();
+        if reference.starts_with("+") && params.len() > 1 {
+            let batch = Batch {
+                reference: String::from_str(reference.slice_from(1)),
+                batch_type: params[1].to_string(),
+                params: params.iter().skip(2).map(|p| p.to_string()).collect(),
+                start: msg.clone(),
+                messages: Vec::new(),
+                nested: Vec::new()
+            };
+            let parent = msg.batch().map(|parent| String::from_str(parent));
+            self.open.insert(batch.reference.clone(), (batch, parent));
+            None
+        } else if reference.starts_with("-") {
+            let (batch, parent) = match self.open.remove(&String::from_str(reference.slice_from(1))) {
+                Some(open) => open,
+                None => return None
+            };
+
+            match parent {
+                Some(parent) => {
+                    match self.open.get_mut(&parent) {
+                        Some(&(ref mut parent, _)) => {
+                            parent.nested.push(batch);
+                            return None;
+                        },
+                        // The parent has already gone, so hand the batch over on its own
+                        None => {}
+                    }
+                    Some(batch)
+                },
+                None => Some(batch)
+            }
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn nested_batches() {
+    let mut tracker = BatchTracker::new();
+    let lines = [
+        ":irc.example.com BATCH +outer example.com/foo",
+        "@batch=outer :irc.example.com BATCH +inner example.com/bar",
+        "@batch=inner :nick!user@host PRIVMSG #chan :inner",
+        "@batch=outer :nick!user@host PRIVMSG #chan :outer",
+        ":irc.example.com BATCH -inner"
+    ];
+    for line in lines.iter() {
+        if line.ends_with("-inner") {
+            assert!(tracker.is_within("inner", "example.com/foo") && !tracker.is_within("outer", "example.com/bar"));
+        }
+        assert!(tracker.process(&Message::from_str(*line).unwrap()).is_none());
+    }
+
+    let batch = tracker.process(&Message::from_str(":irc.example.com BATCH -outer").unwrap()).unwrap();
+    assert!(batch.reference() == "outer" && batch.batch_type() == "example.com/foo");
+    assert!(batch.messages().len() == 1);
+    assert!(batch.nested().len() == 1);
+    assert!(batch.nested()[0].messages()[0].params().as_ref().unwrap()[1].as_slice() == "inner");
+    assert!(!tracker.is_open("outer") && !tracker.is_open("inner"));
+}
+
+#[test]
+fn batch_params() {
+    let mut tracker = BatchTracker::new();
+    tracker.process(&Message::from_str(":irc.example.com BATCH +yXNAbvnRHTRBv netsplit irc.hub other.host").unwrap());
+    tracker.process(&Message::from_str("@batch=yXNAbvnRHTRBv :aji!a@a QUIT :irc.hub other.host").unwrap());
+    // Messages for batches we never saw opened are ignored
+    tracker.process(&Message::from_str("@batch=unknown :bob!b@b QUIT :irc.hub other.host").unwrap());
+
+    let batch = tracker.process(&Message::from_str(":irc.example.com BATCH -yXNAbvnRHTRBv").unwrap()).unwrap();
+    assert!(batch.batch_type() == "netsplit");
+    assert!(batch.params().to_vec() == vec![String::from_str("irc.hub"), String::from_str("other.host")]);
+    assert!(batch.messages().len() == 1);
+}
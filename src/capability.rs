@@ -49,7 +49,7 @@ impl Capability {
                         Some(Capability {
                             identifier: identifier,
                             modifier: modifier,
-                            value: Some(String::from_str(s.slice_from(val_sep)))
+                            value: Some(String::from_str(s.slice_from(val_sep + 1)))
                         })
                     },
                     None => {
@@ -0,0 +1,659 @@
+use std::collections::HashMap;
+use std::mem;
+
+use isupport::{CaseMapping, ISupport};
+use message::Message;
+use mode::ModeChange;
+use user::User;
+
+#[experimental]
+#[deriving(Clone, Show)]
+pub struct Topic {
+    pub text: String,
+    pub set_by: Option<String>,
+    pub set_at: Option<i64>
+}
+
+#[experimental]
+#[deriving(Clone, Show)]
+pub struct Member {
+    nick: String,
+    prefixes: String
+}
+
+#[experimental]
+impl Member {
+    #[experimental]
+    #[inline]
+    pub fn nick<'a>(&'a self) -> &'a str {
+        self.nick.as_slice()
+    }
+
+    /// All of the member's prefix symbols, highest rank first.
+    #[experimental]
+    #[inline]
+    pub fn prefixes<'a>(&'a self) -> &'a str {
+        self.prefixes.as_slice()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn highest_prefix(&self) -> Option<char> {
+        self.prefixes.as_slice().chars().next()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn has_prefix(&self, symbol: char) -> bool {
+        self.prefixes.as_slice().contains_char(symbol)
+    }
+
+    fn add_prefix(&mut self, symbol: char, isupport: &ISupport) {
+        if !self.has_prefix(symbol) {
+            self.prefixes.push(symbol);
+            self.sort_prefixes(isupport);
+        }
+    }
+
+    fn remove_prefix(&mut self, symbol: char) {
+        self.prefixes = self.prefixes.as_slice().chars().filter(|c| *c != symbol).collect();
+    }
+
+    fn sort_prefixes(&mut self, isupport: &ISupport) {
+        let mut symbols: Vec<char> = self.prefixes.as_slice().chars().collect();
+        symbols.sort_by(|a, b| isupport.prefix_rank(*a).cmp(&isupport.prefix_rank(*b)));
+        self.prefixes = symbols.into_iter().collect();
+    }
+}
+
+#[experimental]
+#[deriving(Clone)]
+pub struct Channel {
+    name: String,
+    members: HashMap<String, Member>,
+    modes: HashMap<char, Option<String>>,
+    list_modes: HashMap<char, Vec<String>>,
+    topic: Option<Topic>,
+    created: Option<i64>,
+    case_mapping: CaseMapping,
+    receiving_names: bool
+}
+
+#[experimental]
+impl Channel {
+    fn new(name: &str, case_mapping: CaseMapping) -> Channel {
+        Channel {
+            name: String::from_str(name),
+            members: HashMap::new(),
+            modes: HashMap::new(),
+            list_modes: HashMap::new(),
+            topic: None,
+            created: None,
+            case_mapping: case_mapping,
+            receiving_names: false
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+
+    #[experimental]
+    pub fn members<'a>(&'a self) -> Vec<&'a Member> {
+        self.members.values().collect()
+    }
+
+    #[experimental]
+    pub fn member<'a>(&'a self, nick: &str) -> Option<&'a Member> {
+        self.members.get(&self.case_mapping.casefold(nick))
+    }
+
+    /// The channel's modes other than list modes, with their parameter if they have one.
+    #[experimental]
+    #[inline]
+    pub fn modes<'a>(&'a self) -> &'a HashMap<char, Option<String>> {
+        &self.modes
+    }
+
+    /// The entries of a list mode (such as `b`) seen since we joined.
+    #[experimental]
+    pub fn list_mode<'a>(&'a self, mode: char) -> Option<&'a Vec<String>> {
+        self.list_modes.get(&mode)
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn topic<'a>(&'a self) -> &'a Option<Topic> {
+        &self.topic
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn created(&self) -> Option<i64> {
+        self.created
+    }
+
+    /// Switches to a new case mapping, folding the members' nicks again.
+    fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        let members = mem::replace(&mut self.members, HashMap::new());
+        for (_, member) in members.into_iter() {
+            self.members.insert(case_mapping.casefold(member.nick.as_slice()), member);
+        }
+        self.case_mapping = case_mapping;
+    }
+
+    fn add_member(&mut self, nick: &str, prefixes: &str) {
+        self.members.insert(self.case_mapping.casefold(nick), Member {
+            nick: String::from_str(nick),
+            prefixes: String::from_str(prefixes)
+        });
+    }
+
+    fn remove_member(&mut self, nick: &str) -> bool {
+        self.members.remove(&self.case_mapping.casefold(nick)).is_some()
+    }
+
+    fn rename_member(&mut self, old_nick: &str, new_nick: &str) {
+        match self.members.remove(&self.case_mapping.casefold(old_nick)) {
+            Some(mut member) => {
+                member.nick = String::from_str(new_nick);
+                self.members.insert(self.case_mapping.casefold(new_nick), member);
+            },
+            None => {}
+        }
+    }
+
+    fn apply_modes(&mut self, isupport: &ISupport, modes: &str, args: &[&str]) {
+        let list_modes = isupport.chan_modes()[0].as_slice();
+
+        for change in ModeChange::parse(isupport, modes, args).into_iter() {
+            match isupport.prefix_symbol(change.mode) {
+                Some(symbol) => {
+                    match change.arg {
+                        Some(ref nick) => {
+                            match self.members.get_mut(&self.case_mapping.casefold(nick.as_slice())) {
+                                Some(member) => {
+                                    if change.adding {
+                                        member.add_prefix(symbol, isupport);
+                                    } else {
+                                        member.remove_prefix(symbol);
+                                    }
+                                },
+                                None => {}
+                            }
+                        },
+                        None => {}
+                    }
+                    continue;
+                },
+                None => {}
+            }
+
+            if list_modes.contains_char(change.mode) {
+                match change.arg {
+                    Some(arg) => {
+                        if !self.list_modes.contains_key(&change.mode) {
+                            self.list_modes.insert(change.mode, Vec::new());
+                        }
+                        let list = self.list_modes.get_mut(&change.mode).unwrap();
+                        list.retain(|entry| *entry != arg);
+                        if change.adding {
+                            list.push(arg);
+                        }
+                    },
+                    None => {}
+                }
+            } else if change.adding {
+                self.modes.insert(change.mode, change.arg);
+            } else {
+                self.modes.remove(&change.mode);
+            }
+        }
+    }
+}
+
+/// Tracks the channels we are in, along with their members, modes and topic, and the users we share them with.
+#[experimental]
+#[deriving(Clone)]
+pub struct ChannelState {
+    channels: HashMap<String, Channel>,
+    users: HashMap<String, User>,
+    case_mapping: CaseMapping
+}
+
+#[experimental]
+impl ChannelState {
+    #[experimental]
+    pub fn new() -> ChannelState {
+        ChannelState {
+            channels: HashMap::new(),
+            users: HashMap::new(),
+            case_mapping: CaseMapping::Rfc1459
+        }
+    }
+
+    #[experimental]
+    pub fn user<'a>(&'a self, nick: &str) -> Option<&'a User> {
+        self.users.get(&self.case_mapping.casefold(nick))
+    }
+
+    #[experimental]
+    pub fn users<'a>(&'a self) -> Vec<&'a User> {
+        self.users.values().collect()
+    }
+
+    #[experimental]
+    pub fn channel<'a>(&'a self, name: &str) -> Option<&'a Channel> {
+        self.channels.get(&self.case_mapping.casefold(name))
+    }
+
+    #[experimental]
+    pub fn channels<'a>(&'a self) -> Vec<&'a Channel> {
+        self.channels.values().collect()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_joined(&self, name: &str) -> bool {
+        self.channel(name).is_some()
+    }
+
+    #[experimental]
+    pub fn member<'a>(&'a self, channel: &str, nick: &str) -> Option<&'a Member> {
+        self.channel(channel).and_then(|c| c.member(nick))
+    }
+
+    /// The channels that the given nick shares with us.
+    #[experimental]
+    pub fn channels_of<'a>(&'a self, nick: &str) -> Vec<&'a Channel> {
+        let folded = self.case_mapping.casefold(nick);
+        self.channels.values().filter(|c| c.members.contains_key(&folded)).collect()
+    }
+
+    fn channel_mut<'a>(&'a mut self, name: &str) -> Option<&'a mut Channel> {
+        self.channels.get_mut(&self.case_mapping.casefold(name))
+    }
+
+    fn user_mut<'a>(&'a mut self, nick: &str) -> Option<&'a mut User> {
+        self.users.get_mut(&self.case_mapping.casefold(nick))
+    }
+
+    fn add_user<'a>(&'a mut self, nick: &str) -> &'a mut User {
+        let folded = self.case_mapping.casefold(nick);
+        if !self.users.contains_key(&folded) {
+            self.users.insert(folded.clone(), User::new(nick));
+        }
+        self.users.get_mut(&folded).unwrap()
+    }
+
+    /// Forgets the users that no longer share a channel with us.
+    fn collect_users(&mut self) {
+        let channels = &self.channels;
+        let gone: Vec<String> = self.users.keys().filter(|nick| !channels.values().any(|c| c.members.contains_key(*nick))).map(|nick| nick.clone()).collect();
+        for nick in gone.iter() {
+            self.users.remove(nick);
+        }
+    }
+
+    /// Switches to a new case mapping, such as one announced in `ISUPPORT`, folding every channel name and nick again.
+    fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        if self.case_mapping == case_mapping {
+            return;
+        }
+
+        let channels = mem::replace(&mut self.channels, HashMap::new());
+        for (_, mut channel) in channels.into_iter() {
+            channel.set_case_mapping(case_mapping.clone());
+            self.channels.insert(case_mapping.casefold(channel.name.as_slice()), channel);
+        }
+        let users = mem::replace(&mut self.users, HashMap::new());
+        for (_, user) in users.into_iter() {
+            self.users.insert(case_mapping.casefold(user.nick()), user);
+        }
+        self.case_mapping = case_mapping;
+    }
+
+    /// Updates the state from a message received from the server.
+    #[experimental]
+    pub fn update(&mut self, own_nick: &str, isupport: &ISupport, msg: &Message) {
+        self.set_case_mapping(isupport.case_mapping());
+        let case_mapping = self.case_mapping.clone();
+
+        let source = match *msg.prefix() {
+            Some(ref prefix) => prefix.nick().unwrap_or(""),
+            None => ""
+        };
+        let user_host = match *msg.prefix() {
+            Some(ref prefix) if prefix.is_client() => {
+                match (prefix.ident(), prefix.host_opt()) {
+                    (Some(ident), Some(host)) => Some((ident, host)),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+        let is_self = case_mapping.equals(source, own_nick);
+
+        let params: Vec<&str> = match *msg.params() {
+            Some(ref params) => params.iter().map(|p| p.as_slice()).collect(),
+            None => Vec::new()
+        };
+
+        match (msg.command().as_slice(), params.len()) {
+            ("JOIN", n) if n > 0 => {
+                for name in params[0].split(',') {
+                    if is_self {
+                        self.channels.insert(case_mapping.casefold(name), Channel::new(name, case_mapping.clone()));
+                    }
+                    let joined = match self.channel_mut(name) {
+                        Some(channel) => {
+                            channel.add_member(source, "");
+                            true
+                        },
+                        None => false
+                    };
+
+                    if joined {
+                        let user = self.add_user(source);
+                        match user_host {
+                            Some((user_name, host)) => user.set_user_host(user_name, host),
+                            None => {}
+                        }
+                        // extended-join adds the account name and realname
+                        if n > 2 {
+                            user.set_account(params[1]);
+                            user.set_real_name(params[2]);
+                        }
+                    }
+                }
+            },
+            ("PART", n) if n > 0 => {
+                for name in params[0].split(',') {
+                    if is_self {
+                        self.channels.remove(&case_mapping.casefold(name));
+                    } else {
+                        match self.channel_mut(name) {
+                            Some(channel) => { channel.remove_member(source); },
+                            None => {}
+                        }
+                    }
+                }
+                self.collect_users();
+            },
+            ("KICK", n) if n > 1 => {
+                if case_mapping.equals(params[1], own_nick) {
+                    self.channels.remove(&case_mapping.casefold(params[0]));
+                } else {
+                    match self.channel_mut(params[0]) {
+                        Some(channel) => { channel.remove_member(params[1]); },
+                        None => {}
+                    }
+                }
+                self.collect_users();
+            },
+            ("QUIT", _) => {
+                if is_self {
+                    self.channels.clear();
+                    self.users.clear();
+                } else {
+                    for channel in self.channels.values_mut() {
+                        channel.remove_member(source);
+                    }
+                    self.users.remove(&case_mapping.casefold(source));
+                }
+            },
+            ("NICK", n) if n > 0 => {
+                for channel in self.channels.values_mut() {
+                    channel.rename_member(source, params[0]);
+                }
+                match self.users.remove(&case_mapping.casefold(source)) {
+                    Some(mut user) => {
+                        user.set_nick(params[0]);
+                        self.users.insert(case_mapping.casefold(params[0]), user);
+                    },
+                    None => {}
+                }
+            },
+            // account-notify
+            ("ACCOUNT", n) if n > 0 => {
+                match self.user_mut(source) {
+                    Some(user) => user.set_account(params[0]),
+                    None => {}
+                }
+            },
+            // away-notify, which sends AWAY with no parameters when a user returns
+            ("AWAY", n) => {
+                match self.user_mut(source) {
+                    Some(user) => user.set_away(if n > 0 { Some(params[0]) } else { None }),
+                    None => {}
+                }
+            },
+            ("CHGHOST", n) if n > 1 => {
+                match self.user_mut(source) {
+                    Some(user) => user.set_user_host(params[0], params[1]),
+                    None => {}
+                }
+            },
+            ("SETNAME", n) if n > 0 => {
+                match self.user_mut(source) {
+                    Some(user) => user.set_real_name(params[0]),
+                    None => {}
+                }
+            },
+            ("MODE", n) if n > 1 => {
+                match self.channel_mut(params[0]) {
+                    Some(channel) => channel.apply_modes(isupport, params[1], params.slice_from(2)),
+                    None => {}
+                }
+            },
+            ("TOPIC", n) if n > 1 => {
+                match self.channel_mut(params[0]) {
+                    Some(channel) => {
+                        channel.topic = if params[1] == "" {
+                            None
+                        } else {
+                            Some(Topic {
+                                text: String::from_str(params[1]),
+                                set_by: Some(String::from_str(source)),
+                                set_at: None
+                            })
+                        };
+                    },
+                    None => {}
+                }
+            },
+            // RPL_CHANNELMODEIS
+            ("324", n) if n > 2 => {
+                match self.channel_mut(params[1]) {
+                    Some(channel) => {
+                        channel.modes.clear();
+                        channel.apply_modes(isupport, params[2], params.slice_from(3));
+                    },
+                    None => {}
+                }
+            },
+            // RPL_CREATIONTIME
+            ("329", n) if n > 2 => {
+                match self.channel_mut(params[1]) {
+                    Some(channel) => channel.created = from_str::<i64>(params[2]),
+                    None => {}
+                }
+            },
+            // RPL_NOTOPIC
+            ("331", n) if n > 1 => {
+                match self.channel_mut(params[1]) {
+                    Some(channel) => channel.topic = None,
+                    None => {}
+                }
+            },
+            // RPL_TOPIC
+            ("332", n) if n > 2 => {
+                match self.channel_mut(params[1]) {
+                    Some(channel) => {
+                        channel.topic = Some(Topic {
+                            text: String::from_str(params[2]),
+                            set_by: None,
+                            set_at: None
+                        });
+                    },
+                    None => {}
+                }
+            },
+            // RPL_TOPICWHOTIME
+            ("333", n) if n > 3 => {
+                match self.channel_mut(params[1]) {
+                    Some(channel) => {
+                        match channel.topic {
+                            Some(ref mut topic) => {
+                                topic.set_by = Some(String::from_str(params[2]));
+                                topic.set_at = from_str::<i64>(params[3]);
+                            },
+                            None => {}
+                        }
+                    },
+                    None => {}
+                }
+            },
+            // RPL_NAMREPLY
+            ("353", n) if n > 3 => {
+                let mut names = Vec::new();
+                match self.channel_mut(params[2]) {
+                    Some(channel) => {
+                        if !channel.receiving_names {
+                            channel.members.clear();
+                            channel.receiving_names = true;
+                        }
+
+                        for name in params[3].split(' ').filter(|n| *n != "") {
+                            // With multi-prefix every prefix the member has is listed, otherwise only the highest
+                            let split = name.chars().position(|c| isupport.prefix_rank(c).is_none()).unwrap_or(name.len());
+                            let (prefixes, mask) = (name.slice_to(split), name.slice_from(split));
+                            // userhost-in-names sends each member as nick!user@host
+                            let mut parts = mask.splitn(1, '!');
+                            let nick = parts.next().unwrap_or(mask);
+                            let user_host = parts.next().and_then(|uh| {
+                                let mut uh = uh.splitn(1, '@');
+                                match (uh.next(), uh.next()) {
+                                    (Some(u), Some(h)) => Some((u, h)),
+                                    _ => None
+                                }
+                            });
+
+                            channel.add_member(nick, prefixes);
+                            match channel.members.get_mut(&case_mapping.casefold(nick)) {
+                                Some(member) => member.sort_prefixes(isupport),
+                                None => {}
+                            }
+                            names.push((nick, user_host));
+                        }
+                    },
+                    None => {}
+                }
+
+                for &(nick, user_host) in names.iter() {
+                    let user = self.add_user(nick);
+                    match user_host {
+                        Some((user_name, host)) => user.set_user_host(user_name, host),
+                        None => {}
+                    }
+                }
+            },
+            // RPL_ENDOFNAMES
+            ("366", n) if n > 1 => {
+                match self.channel_mut(params[1]) {
+                    Some(channel) => channel.receiving_names = false,
+                    None => {}
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn names_and_modes() {
+    let isupport = ISupport::new();
+    let mut state = ChannelState::new();
+
+    for line in [
+        ":me!me@host JOIN #test",
+        ":server 353 me = #test :me @+Alice +bob",
+        ":server 366 me #test :End of /NAMES list.",
+        ":server 332 me #test :Hello world",
+        ":server 333 me #test alice 1400000000",
+        ":Alice!a@host MODE #test -o+kl bob secret 10",
+        ":bob!b@host NICK Robert"
+    ].iter() {
+        state.update("me", &isupport, &Message::from_str(*line).unwrap());
+    }
+
+    let channel = state.channel("#TEST").unwrap();
+    assert!(channel.members().len() == 3);
+    assert!(channel.member("alice").unwrap().prefixes() == "@+");
+    assert!(channel.member("robert").unwrap().prefixes() == "+");
+    assert!(channel.member("bob").is_none());
+    assert!(channel.modes().get(&'k') == Some(&Some(String::from_str("secret"))));
+    assert!(channel.modes().get(&'l') == Some(&Some(String::from_str("10"))));
+
+    let topic = channel.topic().as_ref().unwrap();
+    assert!(topic.text.as_slice() == "Hello world");
+    assert!(topic.set_at == Some(1400000000));
+
+    state.update("me", &isupport, &Message::from_str(":me!me@host PART #test").unwrap());
+    assert!(!state.is_joined("#test"));
+    assert!(state.users().is_empty());
+}
+
+#[test]
+fn user_tracking() {
+    let isupport = ISupport::new();
+    let mut state = ChannelState::new();
+
+    for line in [
+        ":me!me@host JOIN #a * :Me",
+        ":me!me@host JOIN #b * :Me",
+        ":alice!a@example.com JOIN #a alice :Alice Liddell",
+        ":alice!a@example.com JOIN #b alice :Alice Liddell",
+        ":alice!a@example.com AWAY :Down the rabbit hole",
+        ":alice!a@example.com CHGHOST alice wonderland.example",
+        ":alice!alice@wonderland.example ACCOUNT *",
+        ":alice!alice@wonderland.example NICK Alice2"
+    ].iter() {
+        state.update("me", &isupport, &Message::from_str(*line).unwrap());
+    }
+
+    {
+        let user = state.user("ALICE2").unwrap();
+        assert!(user.nick() == "Alice2");
+        assert!(user.user_name() == Some("alice"));
+        assert!(user.host() == Some("wonderland.example"));
+        assert!(user.real_name() == Some("Alice Liddell"));
+        assert!(user.account().is_none());
+        assert!(user.away_message() == Some("Down the rabbit hole"));
+    }
+
+    state.update("me", &isupport, &Message::from_str(":Alice2!alice@wonderland.example PART #a").unwrap());
+    assert!(state.user("alice2").is_some());
+    state.update("me", &isupport, &Message::from_str(":me!me@host PART #b").unwrap());
+    assert!(state.user("alice2").is_none());
+}
+
+#[test]
+fn case_mapping_changes() {
+    let mut isupport = ISupport::new();
+    let mut state = ChannelState::new();
+
+    state.update("me", &isupport, &Message::from_str(":me!me@host JOIN #a[b]").unwrap());
+    state.update("me", &isupport, &Message::from_str(":n[x]!n@host JOIN #a[b]").unwrap());
+    assert!(state.member("#A{B}", "N{X}").is_some());
+
+    let casemapping = Message::from_str(":server 005 me CASEMAPPING=ascii :are supported by this server").unwrap();
+    isupport.update(&casemapping);
+    state.update("me", &isupport, &casemapping);
+    assert!(state.member("#A[B]", "N[X]").is_some());
+    assert!(state.member("#a{b}", "n[x]").is_none());
+    assert!(state.channel("#a[b]").unwrap().member("n{x}").is_none());
+    assert!(state.user("N[X]").is_some());
+}
@@ -1,5 +1,5 @@
 use std::io::{IoResult, Stream, Writer};
-use std::io::net::ip::ToSocketAddr;
+use std::io::net::ip::{SocketAddr, ToSocketAddr};
 use std::io::net::tcp::TcpStream;
 use std::time::duration::Duration;
 
@@ -7,6 +7,49 @@ use irc_methods::IrcMethods;
 use message::Message;
 use parser::Parser;
 
+/// A stream that an IRC connection can run over.
+#[experimental]
+pub trait IrcStream: Stream + Clone + Send {
+    /// Sets the timeout, in milliseconds, after which a read fails with `TimedOut`.
+    #[experimental]
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>);
+
+    /// Sets the timeout, in milliseconds, after which a write fails with `TimedOut`, or `ShortWrite` if some of it was sent.
+    #[experimental]
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>);
+
+    /// Shuts down both directions of the stream.
+    #[experimental]
+    fn close(&mut self) -> IoResult<()>;
+
+    /// Opens a new stream to `addr`, for reconnecting.
+    #[experimental]
+    fn open(addr: SocketAddr) -> IoResult<Self>;
+}
+
+#[experimental]
+impl IrcStream for TcpStream {
+    #[inline]
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        TcpStream::set_read_timeout(self, timeout_ms)
+    }
+
+    #[inline]
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        TcpStream::set_write_timeout(self, timeout_ms)
+    }
+
+    #[inline]
+    fn close(&mut self) -> IoResult<()> {
+        self.close_write().and_then(|_| self.close_read())
+    }
+
+    #[inline]
+    fn open(addr: SocketAddr) -> IoResult<TcpStream> {
+        TcpStream::connect(addr)
+    }
+}
+
 #[unstable]
 pub struct Connection<T: Stream + Clone> {
     stream: T,
@@ -29,6 +72,12 @@ impl<T: Stream + Clone> Connection<T> {
         &mut self.stream
     }
 
+    #[experimental]
+    #[inline]
+    pub fn get_ref<'a>(&'a self) -> &'a T {
+        &self.stream
+    }
+
     #[unstable]
     #[inline]
     pub fn read_message(&mut self) -> IoResult<Option<Message<'static>>> {
@@ -55,9 +104,19 @@ impl<A: ToSocketAddr> Connection<TcpStream> {
 }
 
 #[experimental]
-impl IrcMethods for Connection<TcpStream> {
+impl<T: IrcStream> Connection<T> {
+    /// Sets the timeout, in milliseconds, for reading messages. A timed out read fails with `TimedOut` and leaves any partial message buffered.
+    #[experimental]
+    #[inline]
+    pub fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.parser.get_mut().set_read_timeout(timeout_ms)
+    }
+}
+
+#[experimental]
+impl<T: IrcStream> IrcMethods for Connection<T> {
     fn quit(&mut self, message: Option<&str>) -> IoResult<()> {
-        self.__quit(message).and_then(|_| self.flush()).and_then(|_| self.stream.close_write()).and_then(|_| self.stream.close_read())
+        self.__quit(message).and_then(|_| self.flush()).and_then(|_| self.stream.close())
     }
 }
 
@@ -0,0 +1,277 @@
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::num::Float;
+use time::now;
+
+use flood::FloodControl;
+use message::Message;
+
+/// The commands `CtcpResponder` answers, for `CLIENTINFO`.
+static SUPPORTED: &'static [&'static str] = &["ACTION", "CLIENTINFO", "PING", "SOURCE", "TIME", "VERSION"];
+
+/// A client-to-client protocol message, such as `\x01ACTION waves\x01`, carried in the text of a `PRIVMSG` (a request) or a
+/// `NOTICE` (a reply).
+#[experimental]
+#[deriving(Clone, PartialEq, Eq)]
+pub struct Ctcp {
+    command: String,
+    params: Option<String>
+}
+
+#[experimental]
+impl Ctcp {
+    #[experimental]
+    pub fn new(command: &str, params: Option<&str>) -> Ctcp {
+        Ctcp {
+            command: command.to_ascii_upper(),
+            params: params.map(|params| String::from_str(params))
+        }
+    }
+
+    /// Reads the text of a `PRIVMSG` or `NOTICE`, returning `None` if it isn't CTCP. The closing `\x01` is optional, as
+    /// some clients leave it off.
+    #[experimental]
+    pub fn from_text(text: &str) -> Option<Ctcp> {
+        let text = low_level_dequote(text);
+        let text = text.as_slice();
+        if !text.starts_with("\x01") || text.len() < 2 {
+            return None;
+        }
+
+        let body = text.slice_from(1);
+        let body = if body.ends_with("\x01") { body.slice_to(body.len() - 1) } else { body };
+        if body.is_empty() {
+            return None;
+        }
+
+        let mut parts = body.splitn(1, ' ');
+        let command = parts.next().unwrap();
+        Some(Ctcp::new(command, parts.next()))
+    }
+
+    /// The CTCP in a `PRIVMSG` or `NOTICE`, if there is one.
+    #[experimental]
+    pub fn from_message(msg: &Message) -> Option<Ctcp> {
+        match (msg.command().as_slice(), msg.params()) {
+            ("PRIVMSG", &Some(ref params)) | ("NOTICE", &Some(ref params)) if params.len() > 1 => Ctcp::from_text(params[1].as_slice()),
+            _ => None
+        }
+    }
+
+    /// The command, in upper case.
+    #[experimental]
+    #[inline]
+    pub fn command<'a>(&'a self) -> &'a str {
+        self.command.as_slice()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn params<'a>(&'a self) -> Option<&'a str> {
+        self.params.as_ref().map(|params| params.as_slice())
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_action(&self) -> bool {
+        self.command.as_slice() == "ACTION"
+    }
+
+    /// The text to send, with its `\x01` delimiters, low-level quoted so that it can go in a single line. A `\x01` in the
+    /// parameters can't be sent, so it is left out.
+    #[experimental]
+    pub fn to_text(&self) -> String {
+        let mut text = format!("\x01{}", self.command);
+        match self.params {
+            Some(ref params) => {
+                text.push(' ');
+                text.push_str(params.as_slice().replace("\x01", "").as_slice());
+            },
+            None => {}
+        }
+        text.push('\x01');
+        low_level_quote(text.as_slice())
+    }
+}
+
+#[experimental]
+impl fmt::Show for Ctcp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_text())
+    }
+}
+
+/// Escapes NUL, CR, LF and `\x10` with the `\x10` quote character.
+#[experimental]
+pub fn low_level_quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\x10' => quoted.push_str("\x10\x10"),
+            '\0' => quoted.push_str("\x100"),
+            '\n' => quoted.push_str("\x10n"),
+            '\r' => quoted.push_str("\x10r"),
+            c => quoted.push(c)
+        }
+    }
+    quoted
+}
+
+/// Undoes `low_level_quote`. A `\x10` before any other character is dropped.
+#[experimental]
+pub fn low_level_dequote(text: &str) -> String {
+    let mut dequoted = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    loop {
+        match chars.next() {
+            Some('\x10') => {
+                match chars.next() {
+                    Some('0') => dequoted.push('\0'),
+                    Some('n') => dequoted.push('\n'),
+                    Some('r') => dequoted.push('\r'),
+                    Some(c) => dequoted.push(c),
+                    None => break
+                }
+            },
+            Some(c) => dequoted.push(c),
+            None => break
+        }
+    }
+    dequoted
+}
+
+/// Answers `VERSION`, `PING`, `TIME`, `CLIENTINFO` and `SOURCE` requests, at most as fast as its token bucket allows so that
+/// a flood of requests can't get us disconnected for flooding. Requests beyond that are ignored.
+#[experimental]
+pub struct CtcpResponder {
+    version: Option<String>,
+    source: Option<String>,
+    rate_limit: FloodControl,
+    tokens: f64,
+    last_refill: Option<u64>
+}
+
+#[experimental]
+impl CtcpResponder {
+    /// A responder that replies to `VERSION` with `version`, and to at most 3 requests at once and then one every 2 seconds.
+    #[experimental]
+    pub fn new(version: &str) -> CtcpResponder {
+        CtcpResponder {
+            version: Some(String::from_str(version)),
+            source: None,
+            rate_limit: FloodControl::new(3, 2000),
+            tokens: 3.0,
+            last_refill: None
+        }
+    }
+
+    /// What to reply to `VERSION`, or `None` not to reply.
+    #[experimental]
+    pub fn set_version(&mut self, version: Option<&str>) {
+        self.version = version.map(|version| String::from_str(version));
+    }
+
+    /// Where to get the client's source code, for `SOURCE`, or `None` not to reply.
+    #[experimental]
+    pub fn set_source(&mut self, source: Option<&str>) {
+        self.source = source.map(|source| String::from_str(source));
+    }
+
+    /// Uses `burst` and `refill_ms` to limit replies; `bytes_per_token` is ignored.
+    #[experimental]
+    pub fn set_rate_limit(&mut self, rate_limit: FloodControl) {
+        self.tokens = self.tokens.min(rate_limit.burst as f64);
+        self.rate_limit = rate_limit;
+    }
+
+    /// The nick to reply to and the reply, if `msg` is a request we answer and the rate limit allows it at `now_ms`, a time
+    /// in milliseconds from any fixed point.
+    #[experimental]
+    pub fn respond(&mut self, msg: &Message, now_ms: u64) -> Option<(String, Ctcp)> {
+        if msg.command().as_slice() != "PRIVMSG" {
+            return None;
+        }
+        let (nick, request) = match (msg.prefix().as_ref().and_then(|prefix| prefix.nick()), Ctcp::from_message(msg)) {
+            (Some(nick), Some(request)) => (nick, request),
+            _ => return None
+        };
+
+        let params = match request.command() {
+            "VERSION" => match self.version {
+                Some(ref version) => Some(version.clone()),
+                None => return None
+            },
+            "SOURCE" => match self.source {
+                Some(ref source) => Some(source.clone()),
+                None => return None
+            },
+            "PING" => request.params().map(|params| String::from_str(params)),
+            "TIME" => Some(now().rfc822z().to_string()),
+            "CLIENTINFO" => Some(self.client_info()),
+            _ => return None
+        };
+
+        self.refill(now_ms);
+        if self.tokens < 1.0 {
+            return None;
+        }
+        self.tokens -= 1.0;
+
+        Some((String::from_str(nick), Ctcp::new(request.command(), params.as_ref().map(|params| params.as_slice()))))
+    }
+
+    fn client_info(&self) -> String {
+        SUPPORTED.iter()
+            .filter(|&&command| match command {
+                "VERSION" => self.version.is_some(),
+                "SOURCE" => self.source.is_some(),
+                _ => true
+            })
+            .map(|command| *command)
+            .collect::<Vec<&str>>()
+            .connect(" ")
+    }
+
+    fn refill(&mut self, now_ms: u64) {
+        match self.last_refill {
+            Some(last) if now_ms > last && self.rate_limit.refill_ms > 0 => {
+                let earned = (now_ms - last) as f64 / self.rate_limit.refill_ms as f64;
+                self.tokens = (self.tokens + earned).min(self.rate_limit.burst as f64);
+            },
+            _ => {}
+        }
+        self.last_refill = Some(now_ms);
+    }
+}
+
+#[test]
+fn parsing() {
+    let ctcp = Ctcp::from_text("\x01ACTION waves\x01").unwrap();
+    assert!(ctcp.is_action() && ctcp.params() == Some("waves"));
+    assert!(Ctcp::from_text("\x01version").unwrap() == Ctcp::new("VERSION", None));
+    assert!(Ctcp::from_text("hello").is_none());
+    assert!(Ctcp::from_text("\x01\x01").is_none());
+
+    let msg = Message::from_str(":alice!a@host PRIVMSG relay :\x01PING 123\x10n456\x01").unwrap();
+    assert!(Ctcp::from_message(&msg).unwrap().params() == Some("123\n456"));
+    assert!(Ctcp::new("PING", Some("123\n456")).to_text().as_slice() == "\x01PING 123\x10n456\x01");
+    assert!(low_level_dequote(low_level_quote("a\x10b\0c\r\n").as_slice()).as_slice() == "a\x10b\0c\r\n");
+}
+
+#[test]
+fn responder() {
+    let mut responder = CtcpResponder::new("relay 0.1");
+    let version = Message::from_str(":alice!a@host PRIVMSG relay :\x01VERSION\x01").unwrap();
+    let (nick, reply) = responder.respond(&version, 0).unwrap();
+    assert!(nick.as_slice() == "alice" && reply.to_text().as_slice() == "\x01VERSION relay 0.1\x01");
+
+    let info = Message::from_str(":alice!a@host PRIVMSG relay :\x01CLIENTINFO\x01").unwrap();
+    assert!(responder.respond(&info, 0).unwrap().1.params() == Some("ACTION CLIENTINFO PING TIME VERSION"));
+    assert!(responder.respond(&Message::from_str(":alice!a@host NOTICE relay :\x01VERSION x\x01").unwrap(), 0).is_none());
+    assert!(responder.respond(&Message::from_str(":alice!a@host PRIVMSG relay :\x01SOURCE\x01").unwrap(), 0).is_none());
+
+    // The third request uses up the burst, and the next has to wait for a token
+    assert!(responder.respond(&version, 0).is_some());
+    assert!(responder.respond(&version, 1000).is_none());
+    assert!(responder.respond(&version, 2000).is_some());
+}
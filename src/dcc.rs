@@ -0,0 +1,675 @@
+use std::ascii::AsciiExt;
+use std::io::{Acceptor, IoError, IoErrorKind, IoResult, Listener};
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::net::tcp::{TcpAcceptor, TcpListener, TcpStream};
+use time::precise_time_ns;
+
+use connection::IrcStream;
+use ctcp::Ctcp;
+#[cfg(test)]
+use std::io::{MemReader, MemWriter};
+
+/// The longest line accepted over DCC CHAT, as with `Parser`.
+static MAX_CHAT_LINE: uint = 1024;
+static TRANSFER_CHUNK: uint = 4096;
+/// How long a sender waits for the final acknowledgement once the whole file is sent.
+static ACK_TIMEOUT_MS: u64 = 30000;
+
+/// An offer to chat or send a file, from `DCC CHAT` or `DCC SEND`.
+///
+/// A passive (or reverse) offer has port 0 and a token: the other side should listen and reply with an offer of its own
+/// carrying the same token, which `Dcc::passive_reply` makes.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct DccOffer {
+    /// The file name, or `chat` for a chat.
+    pub filename: String,
+    pub addr: SocketAddr,
+    pub size: Option<u64>,
+    pub token: Option<String>
+}
+
+#[experimental]
+impl DccOffer {
+    #[experimental]
+    #[inline]
+    pub fn is_passive(&self) -> bool {
+        self.addr.port == 0 && self.token.is_some()
+    }
+}
+
+/// A request to resume a transfer from `position`, or the sender's acceptance of it, from `DCC RESUME` or `DCC ACCEPT`.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct DccResume {
+    pub filename: String,
+    /// The port of the offer being resumed, which identifies it along with the token.
+    pub port: u16,
+    pub position: u64,
+    pub token: Option<String>
+}
+
+/// A `DCC` request, carried in a CTCP.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Dcc {
+    Chat(DccOffer),
+    Send(DccOffer),
+    Resume(DccResume),
+    Accept(DccResume)
+}
+
+#[experimental]
+impl Dcc {
+    /// Reads a `DCC` CTCP, returning `None` if it isn't one or isn't understood. The address may be an IPv4 address as a
+    /// single number, as is traditional, or an IPv6 or dotted IPv4 address.
+    #[experimental]
+    pub fn from_ctcp(ctcp: &Ctcp) -> Option<Dcc> {
+        if ctcp.command() != "DCC" {
+            return None;
+        }
+        let args = split_args(ctcp.params().unwrap_or(""));
+        if args.len() < 4 {
+            return None;
+        }
+        let arg = |i: uint| if args.len() > i { Some(args[i].as_slice()) } else { None };
+
+        match args[0].to_ascii_upper().as_slice() {
+            "CHAT" | "SEND" => {
+                let chat = args[0].to_ascii_upper().as_slice() == "CHAT";
+                let addr = match (parse_ip(args[2].as_slice()), from_str::<u16>(args[3].as_slice())) {
+                    (Some(ip), Some(port)) => SocketAddr { ip: ip, port: port },
+                    _ => return None
+                };
+                let offer = DccOffer {
+                    filename: args[1].clone(),
+                    addr: addr,
+                    size: if chat { None } else { arg(4).and_then(|size| from_str::<u64>(size)) },
+                    token: arg(if chat { 4 } else { 5 }).map(|token| String::from_str(token))
+                };
+                Some(if chat { Dcc::Chat(offer) } else { Dcc::Send(offer) })
+            },
+            "RESUME" | "ACCEPT" => {
+                let resume = match (from_str::<u16>(args[2].as_slice()), from_str::<u64>(args[3].as_slice())) {
+                    (Some(port), Some(position)) => DccResume {
+                        filename: args[1].clone(),
+                        port: port,
+                        position: position,
+                        token: arg(4).map(|token| String::from_str(token))
+                    },
+                    _ => return None
+                };
+                Some(if args[0].to_ascii_upper().as_slice() == "RESUME" { Dcc::Resume(resume) } else { Dcc::Accept(resume) })
+            },
+            _ => None
+        }
+    }
+
+    /// The CTCP to send, with `ctcp_request`.
+    #[experimental]
+    pub fn to_ctcp(&self) -> Ctcp {
+        let params = match *self {
+            Dcc::Chat(ref offer) => {
+                format!("CHAT chat {} {}{}", format_ip(offer.addr.ip), offer.addr.port, format_token(&offer.token))
+            },
+            Dcc::Send(ref offer) => {
+                // A token has to come after the size, so the size can't be left out if there is one
+                let size = match (offer.size, &offer.token) {
+                    (Some(size), _) => format!(" {}", size),
+                    (None, &Some(_)) => String::from_str(" 0"),
+                    (None, &None) => String::new()
+                };
+                format!("SEND {} {} {}{}{}", quote_filename(offer.filename.as_slice()), format_ip(offer.addr.ip), offer.addr.port, size,
+                        format_token(&offer.token))
+            },
+            Dcc::Resume(ref resume) | Dcc::Accept(ref resume) => {
+                let kind = match *self { Dcc::Resume(_) => "RESUME", _ => "ACCEPT" };
+                format!("{} {} {} {}{}", kind, quote_filename(resume.filename.as_slice()), resume.port, resume.position,
+                        format_token(&resume.token))
+            }
+        };
+        Ctcp::new("DCC", Some(params.as_slice()))
+    }
+
+    /// Our answer to a passive offer: the same offer, but with the address we are listening on.
+    #[experimental]
+    pub fn passive_reply(&self, addr: SocketAddr) -> Option<Dcc> {
+        match *self {
+            Dcc::Chat(ref offer) if offer.is_passive() => Some(Dcc::Chat(DccOffer { addr: addr, ..offer.clone() })),
+            Dcc::Send(ref offer) if offer.is_passive() => Some(Dcc::Send(DccOffer { addr: addr, ..offer.clone() })),
+            _ => None
+        }
+    }
+
+    /// Asks the sender of a file offer to start from `position`, e.g. because part of the file is already here.
+    #[experimental]
+    pub fn resume(&self, position: u64) -> Option<Dcc> {
+        match *self {
+            Dcc::Send(ref offer) => Some(Dcc::Resume(DccResume {
+                filename: offer.filename.clone(),
+                port: offer.addr.port,
+                position: position,
+                token: offer.token.clone()
+            })),
+            _ => None
+        }
+    }
+
+    /// Agrees to a `RESUME` request.
+    #[experimental]
+    pub fn accept(&self) -> Option<Dcc> {
+        match *self {
+            Dcc::Resume(ref resume) => Some(Dcc::Accept(resume.clone())),
+            _ => None
+        }
+    }
+}
+
+/// A socket waiting for the other side of a DCC offer to connect.
+#[experimental]
+pub struct DccListener {
+    acceptor: TcpAcceptor,
+    addr: SocketAddr
+}
+
+#[experimental]
+impl DccListener {
+    /// Listens on any free port on `ip`.
+    #[experimental]
+    pub fn bind(ip: IpAddr) -> IoResult<DccListener> {
+        let mut listener = try!(TcpListener::bind(SocketAddr { ip: ip, port: 0 }));
+        let addr = try!(listener.socket_name());
+
+        Ok(DccListener {
+            acceptor: try!(listener.listen()),
+            addr: addr
+        })
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// An offer to chat on this socket.
+    #[experimental]
+    pub fn offer_chat(&self) -> Dcc {
+        Dcc::Chat(DccOffer {
+            filename: String::from_str("chat"),
+            addr: self.addr,
+            size: None,
+            token: None
+        })
+    }
+
+    /// An offer to send a file on this socket.
+    #[experimental]
+    pub fn offer_send(&self, filename: &str, size: u64) -> Dcc {
+        Dcc::Send(DccOffer {
+            filename: String::from_str(filename),
+            addr: self.addr,
+            size: Some(size),
+            token: None
+        })
+    }
+
+    /// Waits for the other side to connect, failing with `TimedOut` after `timeout_ms` milliseconds.
+    #[experimental]
+    pub fn accept(&mut self, timeout_ms: Option<u64>) -> IoResult<TcpStream> {
+        self.acceptor.set_timeout(timeout_ms);
+        self.acceptor.accept()
+    }
+}
+
+/// A DCC CHAT connection, which carries plain lines of text rather than IRC messages.
+#[experimental]
+pub struct DccChat<S: Reader + Writer> {
+    stream: S,
+    buffer: Vec<u8>
+}
+
+#[experimental]
+impl DccChat<TcpStream> {
+    /// Connects to the address in an offer.
+    #[experimental]
+    pub fn connect(offer: &DccOffer) -> IoResult<DccChat<TcpStream>> {
+        Ok(DccChat::new(try!(TcpStream::connect(offer.addr))))
+    }
+}
+
+#[experimental]
+impl<S: Reader + Writer> DccChat<S> {
+    #[experimental]
+    pub fn new(stream: S) -> DccChat<S> {
+        DccChat {
+            stream: stream,
+            buffer: Vec::new()
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut S {
+        &mut self.stream
+    }
+
+    /// Reads the next line, without its line ending. Like `Parser::read_message`, this returns `None` if a whole line hasn't
+    /// arrived yet.
+    #[experimental]
+    pub fn read_line(&mut self) -> IoResult<Option<String>> {
+        match self.line_from_buffer() {
+            Some(line) => return Ok(Some(line)),
+            None => {}
+        }
+
+        if self.buffer.len() >= MAX_CHAT_LINE {
+            return Err(IoError {
+                kind: IoErrorKind::OtherIoError,
+                desc: "DCC CHAT line exceeded maximum size of 1024 bytes",
+                detail: None
+            });
+        }
+
+        let mut in_buf = [0u8, ..1024];
+        let bytes_read = try!(self.stream.read(in_buf.as_mut_slice()));
+        self.buffer.push_all(in_buf.slice_to(bytes_read));
+        Ok(self.line_from_buffer())
+    }
+
+    #[experimental]
+    pub fn send_line(&mut self, line: &str) -> IoResult<()> {
+        try!(self.stream.write(line.trim_right_chars(['\r', '\n'].as_slice()).as_bytes()));
+        try!(self.stream.write(b"\r\n"));
+        self.stream.flush()
+    }
+
+    #[experimental]
+    pub fn send_action(&mut self, text: &str) -> IoResult<()> {
+        self.send_line(Ctcp::new("ACTION", Some(text)).to_text().as_slice())
+    }
+
+    fn line_from_buffer(&mut self) -> Option<String> {
+        loop {
+            let end = match self.buffer.iter().position(|&b| b == b'\r' || b == b'\n') {
+                Some(end) => end,
+                None => return None
+            };
+
+            let line = String::from_utf8_lossy(self.buffer.slice_to(end)).into_string();
+            self.buffer = self.buffer.slice_from(end + 1).to_vec();
+            // Skip the empty "line" between a CR and its LF, and any blank lines
+            if !line.is_empty() {
+                return Some(line);
+            }
+        }
+    }
+}
+
+/// How far a file transfer has got.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct DccProgress {
+    /// The position in the file, counting anything skipped by resuming.
+    pub transferred: u64,
+    pub size: Option<u64>
+}
+
+#[experimental]
+impl DccProgress {
+    #[experimental]
+    pub fn is_complete(&self) -> bool {
+        self.size.map_or(false, |size| self.transferred >= size)
+    }
+}
+
+/// Sends a file over a DCC SEND connection, a chunk at a time so that the caller can report progress.
+///
+/// When resuming, `file` should already be at `position`.
+#[experimental]
+pub struct DccSend<R: Reader, S: IrcStream> {
+    file: R,
+    stream: S,
+    position: u64,
+    size: Option<u64>,
+    acked: Option<u32>,
+    ack_buffer: Vec<u8>,
+    closed: bool,
+    done: bool
+}
+
+#[experimental]
+impl<R: Reader, S: IrcStream> DccSend<R, S> {
+    #[experimental]
+    pub fn new(file: R, stream: S, position: u64, size: Option<u64>) -> DccSend<R, S> {
+        DccSend {
+            file: file,
+            stream: stream,
+            position: position,
+            size: size,
+            acked: None,
+            ack_buffer: Vec::new(),
+            closed: false,
+            done: false
+        }
+    }
+
+    /// Sends the next chunk, returning `None` once the whole file has been sent and acknowledged (or the receiver has hung
+    /// up, which is how some clients acknowledge the end).
+    #[experimental]
+    pub fn step(&mut self) -> IoResult<Option<DccProgress>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut buf = [0u8, ..TRANSFER_CHUNK];
+        let bytes_read = match self.file.read(buf.as_mut_slice()) {
+            Ok(bytes_read) => bytes_read,
+            Err(ref e) if e.kind == IoErrorKind::EndOfFile => 0,
+            Err(e) => return Err(e)
+        };
+
+        if bytes_read == 0 {
+            self.done = true;
+            try!(self.wait_for_ack());
+            return Ok(None);
+        }
+
+        try!(self.stream.write(buf.slice_to(bytes_read)));
+        self.position += bytes_read as u64;
+        // Keep the acknowledgements from piling up, or the receiver would stop reading
+        try!(self.read_acks(Some(0)));
+
+        Ok(Some(DccProgress {
+            transferred: self.position,
+            size: self.size
+        }))
+    }
+
+    /// Sends the rest of the file, returning how far it got.
+    #[experimental]
+    pub fn run(&mut self) -> IoResult<u64> {
+        loop {
+            match try!(self.step()) {
+                Some(_) => {},
+                None => return Ok(self.position)
+            }
+        }
+    }
+
+    fn wait_for_ack(&mut self) -> IoResult<()> {
+        let deadline = now_ms() + ACK_TIMEOUT_MS;
+        // Acknowledgements are only 32 bits, so they wrap around for files over 4GB
+        let expected = self.position as u32;
+
+        while !self.closed && self.acked != Some(expected) {
+            if now_ms() >= deadline {
+                return Err(IoError {
+                    kind: IoErrorKind::TimedOut,
+                    desc: "The receiver didn't acknowledge the end of the file",
+                    detail: None
+                });
+            }
+            try!(self.read_acks(Some(100)));
+        }
+        Ok(())
+    }
+
+    fn read_acks(&mut self, timeout_ms: Option<u64>) -> IoResult<()> {
+        self.stream.set_read_timeout(timeout_ms);
+        let mut buf = [0u8, ..64];
+
+        loop {
+            match self.stream.read(buf.as_mut_slice()) {
+                Ok(bytes_read) => self.ack_buffer.push_all(buf.slice_to(bytes_read)),
+                Err(ref e) if e.kind == IoErrorKind::TimedOut => break,
+                Err(ref e) if e.kind == IoErrorKind::EndOfFile => {
+                    self.closed = true;
+                    break;
+                },
+                Err(e) => return Err(e)
+            }
+        }
+
+        while self.ack_buffer.len() >= 4 {
+            let ack = self.ack_buffer.slice_to(4).iter().fold(0u32, |ack, &b| (ack << 8) | b as u32);
+            self.acked = Some(ack);
+            self.ack_buffer = self.ack_buffer.slice_from(4).to_vec();
+        }
+        Ok(())
+    }
+}
+
+/// Receives a file over a DCC SEND connection, a chunk at a time so that the caller can report progress.
+///
+/// When resuming, `file` should already be at `position`.
+#[experimental]
+pub struct DccReceive<W: Writer, S: IrcStream> {
+    file: W,
+    stream: S,
+    position: u64,
+    size: Option<u64>,
+    done: bool
+}
+
+#[experimental]
+impl<W: Writer, S: IrcStream> DccReceive<W, S> {
+    #[experimental]
+    pub fn new(file: W, stream: S, position: u64, size: Option<u64>) -> DccReceive<W, S> {
+        DccReceive {
+            file: file,
+            stream: stream,
+            position: position,
+            size: size,
+            done: false
+        }
+    }
+
+    /// Receives and acknowledges the next chunk, returning `None` once the whole file is here. Fails with `EndOfFile` if the
+    /// sender hangs up before then.
+    #[experimental]
+    pub fn step(&mut self) -> IoResult<Option<DccProgress>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut buf = [0u8, ..TRANSFER_CHUNK];
+        let bytes_read = match self.stream.read(buf.as_mut_slice()) {
+            Ok(bytes_read) => bytes_read,
+            Err(ref e) if e.kind == IoErrorKind::EndOfFile => {
+                self.done = true;
+                return match self.size {
+                    Some(size) if self.position < size => Err(IoError {
+                        kind: IoErrorKind::EndOfFile,
+                        desc: "DCC transfer ended before the whole file was received",
+                        detail: Some(format!("{} of {} bytes", self.position, size))
+                    }),
+                    _ => Ok(None)
+                };
+            },
+            Err(e) => return Err(e)
+        };
+
+        try!(self.file.write(buf.slice_to(bytes_read)));
+        self.position += bytes_read as u64;
+        try!(self.stream.write_be_u32(self.position as u32));
+
+        let progress = DccProgress {
+            transferred: self.position,
+            size: self.size
+        };
+        if progress.is_complete() {
+            self.done = true;
+            try!(self.file.flush());
+        }
+        Ok(Some(progress))
+    }
+
+    /// Receives the rest of the file, returning how far it got.
+    #[experimental]
+    pub fn run(&mut self) -> IoResult<u64> {
+        loop {
+            match try!(self.step()) {
+                Some(_) => {},
+                None => return Ok(self.position)
+            }
+        }
+    }
+
+    /// The file being written, e.g. to close it once the transfer is over.
+    #[experimental]
+    #[inline]
+    pub fn get_ref<'a>(&'a self) -> &'a W {
+        &self.file
+    }
+}
+
+/// Splits DCC arguments on spaces, keeping a double-quoted file name together.
+fn split_args(params: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut rest = params.trim_left_chars(' ');
+
+    while !rest.is_empty() {
+        if rest.starts_with("\"") {
+            match rest.slice_from(1).find('"') {
+                Some(end) => {
+                    args.push(String::from_str(rest.slice(1, end + 1)));
+                    rest = rest.slice_from(end + 2);
+                },
+                None => {
+                    args.push(String::from_str(rest.slice_from(1)));
+                    rest = "";
+                }
+            }
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            args.push(String::from_str(rest.slice_to(end)));
+            rest = rest.slice_from(end);
+        }
+        rest = rest.trim_left_chars(' ');
+    }
+    args
+}
+
+fn quote_filename(filename: &str) -> String {
+    if filename.contains_char(' ') {
+        format!("\"{}\"", filename)
+    } else {
+        String::from_str(filename)
+    }
+}
+
+fn format_token(token: &Option<String>) -> String {
+    match *token {
+        Some(ref token) => format!(" {}", token),
+        None => String::new()
+    }
+}
+
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    match from_str::<u32>(ip) {
+        Some(n) => Some(Ipv4Addr((n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8)),
+        None => from_str::<IpAddr>(ip)
+    }
+}
+
+fn format_ip(ip: IpAddr) -> String {
+    match ip {
+        Ipv4Addr(a, b, c, d) => ((a as u32 << 24) | (b as u32 << 16) | (c as u32 << 8) | d as u32).to_string(),
+        Ipv6Addr(..) => ip.to_string()
+    }
+}
+
+fn now_ms() -> u64 {
+    precise_time_ns() / 1_000_000
+}
+
+#[test]
+fn offers() {
+    let send = Dcc::from_ctcp(&Ctcp::new("DCC", Some("SEND \"my file.txt\" 2130706433 5000 1024"))).unwrap();
+    match send {
+        Dcc::Send(ref offer) => {
+            assert!(offer.filename.as_slice() == "my file.txt" && offer.size == Some(1024) && !offer.is_passive());
+            assert!(offer.addr == SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 5000 });
+        },
+        _ => panic!("expected a SEND offer")
+    }
+    assert!(send.to_ctcp().params() == Some("SEND \"my file.txt\" 2130706433 5000 1024"));
+
+    let passive = Dcc::from_ctcp(&Ctcp::new("DCC", Some("SEND file.bin ::1 0 2048 42"))).unwrap();
+    let reply = passive.passive_reply(SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), port: 6000 }).unwrap();
+    assert!(reply.to_ctcp().params() == Some("SEND file.bin ::1 6000 2048 42"));
+
+    let chat = Dcc::from_ctcp(&Ctcp::new("DCC", Some("CHAT chat 127.0.0.1 0 7"))).unwrap();
+    assert!(chat.to_ctcp().params() == Some("CHAT chat 2130706433 0 7"));
+
+    let resume = passive.resume(1000).unwrap();
+    assert!(resume.to_ctcp().params() == Some("RESUME file.bin 0 1000 42"));
+    assert!(Dcc::from_ctcp(&resume.accept().unwrap().to_ctcp()) == Some(Dcc::Accept(DccResume {
+        filename: String::from_str("file.bin"),
+        port: 0,
+        position: 1000,
+        token: Some(String::from_str("42"))
+    })));
+    assert!(Dcc::from_ctcp(&Ctcp::new("DCC", Some("SEND file.bin nowhere 5000"))).is_none());
+}
+
+#[test]
+fn chat_over_loopback() {
+    let mut listener = DccListener::bind(Ipv4Addr(127, 0, 0, 1)).unwrap();
+    let offer = match Dcc::from_ctcp(&listener.offer_chat().to_ctcp()) {
+        Some(Dcc::Chat(offer)) => offer,
+        _ => panic!("expected a CHAT offer")
+    };
+
+    spawn(proc() {
+        let mut chat = DccChat::connect(&offer).unwrap();
+        chat.send_line("hello").unwrap();
+        chat.send_action("waves").unwrap();
+    });
+
+    let mut chat = DccChat::new(listener.accept(Some(5000)).unwrap());
+    let mut lines = Vec::new();
+    while lines.len() < 2 {
+        match chat.read_line().unwrap() {
+            Some(line) => lines.push(line),
+            None => {}
+        }
+    }
+    assert!(lines[0].as_slice() == "hello");
+    assert!(Ctcp::from_text(lines[1].as_slice()).unwrap().is_action());
+}
+
+#[test]
+fn resumed_send_over_loopback() {
+    let data = Vec::from_fn(10000, |i| i as u8);
+    let mut listener = DccListener::bind(Ipv4Addr(127, 0, 0, 1)).unwrap();
+    let offer = listener.offer_send("data.bin", data.len() as u64);
+
+    // The receiver already has the first 1000 bytes
+    let (tx, rx) = channel();
+    let resume = offer.resume(1000).unwrap();
+    let addr = match offer { Dcc::Send(ref offer) => offer.addr, _ => unreachable!() };
+    spawn(proc() {
+        let position = match resume { Dcc::Resume(ref resume) => resume.position, _ => unreachable!() };
+        let mut receive = DccReceive::new(MemWriter::new(), TcpStream::connect(addr).unwrap(), position, Some(10000));
+        let mut updates = 0u;
+        loop {
+            match receive.step().unwrap() {
+                Some(_) => updates += 1,
+                None => break
+            }
+        }
+        tx.send((receive.get_ref().get_ref().to_vec(), updates));
+    });
+
+    let stream = listener.accept(Some(5000)).unwrap();
+    let mut send = DccSend::new(MemReader::new(data.slice_from(1000).to_vec()), stream, 1000, Some(10000));
+    let first = send.step().unwrap().unwrap();
+    assert!(first.transferred == 1000 + TRANSFER_CHUNK as u64 && !first.is_complete());
+    assert!(send.run().unwrap() == 10000);
+
+    let (received, updates) = rx.recv();
+    assert!(received.as_slice() == data.slice_from(1000));
+    assert!(updates > 0);
+}
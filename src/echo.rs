@@ -0,0 +1,122 @@
+use std::collections::RingBuf;
+
+use isupport::CaseMapping;
+use message::Message;
+
+/// How many sent messages are remembered while waiting for their echoes.
+static MAX_PENDING: uint = 256;
+
+struct PendingEcho {
+    label: Option<String>,
+    command: String,
+    target: String,
+    text: Option<String>
+}
+
+/// Remembers the messages we have sent so that their `echo-message` copies can be recognised when they come back.
+#[experimental]
+pub struct EchoTracker {
+    pending: RingBuf<PendingEcho>
+}
+
+#[experimental]
+impl EchoTracker {
+    #[experimental]
+    pub fn new() -> EchoTracker {
+        EchoTracker {
+            pending: RingBuf::new()
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn len(&self) -> uint {
+        self.pending.len()
+    }
+
+    #[experimental]
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Records an outgoing `PRIVMSG`, `NOTICE` or `TAGMSG`.
+    #[experimental]
+    pub fn sent(&mut self, msg: &Message, case_mapping: CaseMapping) {
+        let (target, text) = match *msg.params() {
+            Some(ref params) => (params[0].as_slice(), if params.len() > 1 { Some(params[1].to_string()) } else { None }),
+            None => return
+        };
+
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(PendingEcho {
+            label: msg.label().map(|label| String::from_str(label)),
+            command: msg.command().to_string(),
+            target: case_mapping.casefold(target),
+            text: text
+        });
+    }
+
+    /// Whether `msg` is the echo of something we sent, in which case it is no longer waited for. Echoes are matched by
+    /// `label` where there is one, and otherwise by command, target and text.
+    #[experimental]
+    pub fn is_echo(&mut self, own_nick: &str, case_mapping: CaseMapping, msg: &Message) -> bool {
+        let ours = match *msg.prefix() {
+            Some(ref prefix) => case_mapping.equals(prefix.nick().unwrap_or(""), own_nick),
+            None => false
+        };
+        let (target, text) = match *msg.params() {
+            Some(ref params) if ours => (case_mapping.casefold(params[0].as_slice()), if params.len() > 1 { Some(params[1].as_slice()) } else { None }),
+            _ => return false
+        };
+
+        let found = match msg.label() {
+            Some(label) => self.pending.iter().position(|p| p.label.as_ref().map_or(false, |l| l.as_slice() == label)),
+            None => {
+                self.pending.iter().position(|p| {
+                    p.label.is_none() && p.command.as_slice() == msg.command().as_slice() && p.target == target
+                        && p.text.as_ref().map(|t| t.as_slice()) == text
+                })
+            }
+        };
+
+        match found {
+            Some(i) => {
+                self.pending.remove(i);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+/// The `PRIVMSG`, `NOTICE` and `TAGMSG` lines in a block of outgoing data.
+#[experimental]
+pub fn outgoing_messages(data: &[u8]) -> Vec<Message<'static>> {
+    String::from_utf8_lossy(data).as_slice().lines_any()
+        .filter_map(|line| Message::from_str(line).ok())
+        .filter(|msg| {
+            match msg.command().as_slice() {
+                "PRIVMSG" | "NOTICE" | "TAGMSG" => msg.params().is_some(),
+                _ => false
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn echoes() {
+    let mut tracker = EchoTracker::new();
+    for msg in outgoing_messages(b"JOIN #chan\r\nPRIVMSG #Chan :hi there\r\n@label=relay1 PRIVMSG #chan :hi there\r\n").iter() {
+        tracker.sent(msg, CaseMapping::Rfc1459);
+    }
+    assert!(tracker.len() == 2);
+
+    // Someone else saying the same thing isn't an echo
+    assert!(!tracker.is_echo("relay", CaseMapping::Rfc1459, &Message::from_str(":alice!a@host PRIVMSG #chan :hi there").unwrap()));
+    assert!(tracker.is_echo("relay", CaseMapping::Rfc1459, &Message::from_str("@label=relay1;msgid=b :relay!r@host PRIVMSG #chan :hi there").unwrap()));
+    assert!(tracker.is_echo("relay", CaseMapping::Rfc1459, &Message::from_str("@msgid=a :Relay!r@host PRIVMSG #chan :hi there").unwrap()));
+    assert!(!tracker.is_echo("relay", CaseMapping::Rfc1459, &Message::from_str("@msgid=c :relay!r@host PRIVMSG #chan :hi there").unwrap()));
+    assert!(tracker.len() == 0);
+}
@@ -0,0 +1,360 @@
+use std::ascii::AsciiExt;
+use std::collections::{HashMap, RingBuf};
+use std::num::Float;
+
+/// Token bucket settings for outgoing lines. Every line costs a token (or one token per `bytes_per_token` bytes), the bucket
+/// holds `burst` tokens, and one token is earned back every `refill_ms` milliseconds.
+#[experimental]
+#[deriving(Clone, PartialEq, Show)]
+pub struct FloodControl {
+    pub burst: uint,
+    pub refill_ms: u64,
+    pub bytes_per_token: Option<uint>
+}
+
+#[experimental]
+impl FloodControl {
+    #[experimental]
+    pub fn new(burst: uint, refill_ms: u64) -> FloodControl {
+        FloodControl {
+            burst: burst,
+            refill_ms: refill_ms,
+            bytes_per_token: None
+        }
+    }
+
+    /// Charges lines by their length, so that long lines use up the bucket faster than short ones.
+    #[experimental]
+    pub fn with_byte_cost(self, bytes_per_token: uint) -> FloodControl {
+        FloodControl {
+            bytes_per_token: Some(bytes_per_token),
+            ..self
+        }
+    }
+}
+
+/// Outgoing lines waiting for the token bucket.
+///
+/// `PING`, `PONG` and `QUIT` jump the queue and are never held back. Other lines are queued by target (their first parameter)
+/// and the targets take turns, so one busy channel cannot hold up the others. The lines of a batch are queued under the
+/// batch's target, so that they go out in order.
+#[experimental]
+pub struct SendQueue {
+    config: FloodControl,
+    tokens: f64,
+    last_refill: Option<u64>,
+    urgent: RingBuf<Vec<u8>>,
+    targets: RingBuf<String>,
+    lines: HashMap<String, RingBuf<Vec<u8>>>,
+    batches: HashMap<String, String>,
+    len: uint
+}
+
+#[experimental]
+impl SendQueue {
+    #[experimental]
+    pub fn new(config: FloodControl) -> SendQueue {
+        SendQueue {
+            tokens: config.burst as f64,
+            config: config,
+            last_refill: None,
+            urgent: RingBuf::new(),
+            targets: RingBuf::new(),
+            lines: HashMap::new(),
+            batches: HashMap::new(),
+            len: 0
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn config<'a>(&'a self) -> &'a FloodControl {
+        &self.config
+    }
+
+    #[experimental]
+    pub fn set_config(&mut self, config: FloodControl) {
+        self.tokens = self.tokens.min(config.burst as f64);
+        self.config = config;
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn len(&self) -> uint {
+        self.len
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues a single line, including its `\r\n`.
+    #[experimental]
+    pub fn push(&mut self, line: Vec<u8>) {
+        let (batch, command, params) = parse_line(line.as_slice());
+        self.len += 1;
+
+        match command.as_slice() {
+            "PING" | "PONG" | "QUIT" => {
+                self.urgent.push_back(line);
+            },
+            _ => {
+                let target = self.target(batch, command.as_slice(), params.as_slice());
+                if !self.lines.contains_key(&target) {
+                    self.lines.insert(target.clone(), RingBuf::new());
+                    self.targets.push_back(target.clone());
+                }
+                self.lines.get_mut(&target).unwrap().push_back(line);
+            }
+        }
+    }
+
+    /// Queues every line in `data`.
+    #[experimental]
+    pub fn push_lines(&mut self, data: &[u8]) {
+        let mut start = 0;
+        for (i, &b) in data.iter().enumerate() {
+            if b == b'\n' {
+                self.push(data.slice(start, i + 1).to_vec());
+                start = i + 1;
+            }
+        }
+        if start < data.len() {
+            self.push(data.slice_from(start).to_vec());
+        }
+    }
+
+    /// Takes the next line if it can be sent at `now_ms`, a time in milliseconds from any fixed point.
+    #[experimental]
+    pub fn pop(&mut self, now_ms: u64) -> Option<Vec<u8>> {
+        self.refill(now_ms);
+
+        match self.urgent.pop_front() {
+            Some(line) => {
+                let cost = self.cost(line.len());
+                self.tokens -= cost;
+                self.len -= 1;
+                return Some(line);
+            },
+            None => {}
+        }
+
+        let cost = match self.peek_normal() {
+            Some(line) => self.cost(line.len()),
+            None => return None
+        };
+        // A line costing more than the whole bucket still goes once the bucket is full
+        if self.tokens < cost.min(self.config.burst as f64) {
+            return None;
+        }
+
+        self.tokens -= cost;
+        self.len -= 1;
+        self.pop_normal()
+    }
+
+    /// How many milliseconds after `now_ms` the next line can be sent, or `None` if the queue is empty.
+    #[experimental]
+    pub fn next_ready_in(&mut self, now_ms: u64) -> Option<u64> {
+        self.refill(now_ms);
+
+        if !self.urgent.is_empty() {
+            return Some(0);
+        }
+
+        let cost = match self.peek_normal() {
+            Some(line) => self.cost(line.len()),
+            None => return None
+        };
+        let needed = cost.min(self.config.burst as f64) - self.tokens;
+
+        if needed <= 0.0 {
+            Some(0)
+        } else {
+            Some((needed * self.config.refill_ms as f64).ceil() as u64)
+        }
+    }
+
+    /// Empties the queue regardless of the bucket, in the order the lines would have been sent.
+    #[experimental]
+    pub fn take_all(&mut self) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        loop {
+            match self.urgent.pop_front().or_else(|| self.pop_normal()) {
+                Some(line) => lines.push(line),
+                None => break
+            }
+        }
+        self.len = 0;
+        lines
+    }
+
+    /// The queue a line goes in: the target of the batch it is part of, or else its first parameter.
+    fn target(&mut self, batch: Option<String>, command: &str, params: &[String]) -> String {
+        let outer = match batch {
+            Some(ref reference) => self.batches.get(reference).map(|target| target.clone()),
+            None => None
+        };
+        let first = if params.len() > 0 { params[0].to_ascii_lower() } else { String::new() };
+
+        if command == "BATCH" && first.as_slice().starts_with("+") {
+            // A nested batch goes with the batch around it
+            let target = match outer {
+                Some(target) => target,
+                None if params.len() > 2 => params[2].to_ascii_lower(),
+                None => first.clone()
+            };
+            self.batches.insert(String::from_str(params[0].as_slice().slice_from(1)), target.clone());
+            return target;
+        }
+        if command == "BATCH" && first.as_slice().starts_with("-") {
+            match self.batches.remove(&String::from_str(params[0].as_slice().slice_from(1))) {
+                Some(target) => return target,
+                None => {}
+            }
+        }
+
+        outer.unwrap_or(first)
+    }
+
+    fn refill(&mut self, now_ms: u64) {
+        let elapsed = match self.last_refill {
+            Some(last) if now_ms > last => now_ms - last,
+            Some(_) => return,
+            None => 0
+        };
+        self.last_refill = Some(now_ms);
+
+        let burst = self.config.burst as f64;
+        self.tokens = if self.config.refill_ms == 0 {
+            burst
+        } else {
+            (self.tokens + elapsed as f64 / self.config.refill_ms as f64).min(burst)
+        };
+    }
+
+    fn cost(&self, len: uint) -> f64 {
+        match self.config.bytes_per_token {
+            Some(bytes) if bytes > 0 => len as f64 / bytes as f64,
+            _ => 1.0
+        }
+    }
+
+    fn peek_normal<'a>(&'a self) -> Option<&'a Vec<u8>> {
+        self.targets.front().and_then(|target| self.lines.get(target)).and_then(|queue| queue.front())
+    }
+
+    fn pop_normal(&mut self) -> Option<Vec<u8>> {
+        let target = match self.targets.pop_front() {
+            Some(target) => target,
+            None => return None
+        };
+
+        let (line, more) = {
+            let queue = self.lines.get_mut(&target).unwrap();
+            let line = queue.pop_front();
+            (line, !queue.is_empty())
+        };
+
+        // Send this target's next line after every other target has had a turn
+        if more {
+            self.targets.push_back(target);
+        } else {
+            self.lines.remove(&target);
+        }
+        line
+    }
+}
+
+/// The `batch` tag, command and space-separated parameters of a raw line.
+fn parse_line(line: &[u8]) -> (Option<String>, String, Vec<String>) {
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.as_slice().trim_right_chars(['\r', '\n'].as_slice()).split(' ').filter(|w| !w.is_empty());
+
+    let mut batch = None;
+    let mut command = words.next().unwrap_or("");
+    if command.starts_with("@") {
+        batch = command.slice_from(1).split(';')
+            .filter(|tag| tag.starts_with("batch="))
+            .map(|tag| String::from_str(tag.slice_from(6)))
+            .next();
+        command = words.next().unwrap_or("");
+    }
+    if command.starts_with(":") {
+        command = words.next().unwrap_or("");
+    }
+
+    let params = words.map(|w| String::from_str(if w.starts_with(":") { w.slice_from(1) } else { w })).collect();
+    (batch, command.to_ascii_upper(), params)
+}
+
+#[test]
+fn bucket_limits_rate() {
+    let mut queue = SendQueue::new(FloodControl::new(2, 1000));
+    queue.push_lines(b"PRIVMSG #a :1\r\nPRIVMSG #a :2\r\nPRIVMSG #a :3\r\n");
+
+    assert!(queue.pop(0).unwrap().as_slice() == b"PRIVMSG #a :1\r\n");
+    assert!(queue.pop(0).unwrap().as_slice() == b"PRIVMSG #a :2\r\n");
+    assert!(queue.pop(0).is_none());
+    assert!(queue.next_ready_in(0) == Some(1000));
+    assert!(queue.pop(999).is_none());
+    assert!(queue.pop(1000).unwrap().as_slice() == b"PRIVMSG #a :3\r\n");
+    assert!(queue.is_empty() && queue.next_ready_in(1000).is_none());
+}
+
+#[test]
+fn urgent_lines_jump_queue() {
+    let mut queue = SendQueue::new(FloodControl::new(1, 1000));
+    queue.push_lines(b"PRIVMSG #a :1\r\nPRIVMSG #a :2\r\nPONG :token\r\n");
+
+    assert!(queue.pop(0).unwrap().as_slice() == b"PONG :token\r\n");
+    // The PONG used the only token, but PONGs are never held back
+    assert!(queue.pop(0).is_none());
+    queue.push(b":me QUIT :bye\r\n".to_vec());
+    assert!(queue.pop(0).unwrap().as_slice() == b":me QUIT :bye\r\n");
+}
+
+#[test]
+fn targets_take_turns() {
+    let mut queue = SendQueue::new(FloodControl::new(10, 1000));
+    queue.push_lines(b"PRIVMSG #busy :1\r\nPRIVMSG #busy :2\r\nPRIVMSG #busy :3\r\nPRIVMSG #Quiet :1\r\nNOTICE #quiet :2\r\n");
+
+    let order: Vec<Vec<u8>> = range(0u, 5).filter_map(|_| queue.pop(0)).collect();
+    assert!(order == vec![
+        b"PRIVMSG #busy :1\r\n".to_vec(),
+        b"PRIVMSG #Quiet :1\r\n".to_vec(),
+        b"PRIVMSG #busy :2\r\n".to_vec(),
+        b"NOTICE #quiet :2\r\n".to_vec(),
+        b"PRIVMSG #busy :3\r\n".to_vec()
+    ]);
+}
+
+#[test]
+fn batches_keep_their_order() {
+    let mut queue = SendQueue::new(FloodControl::new(10, 1000));
+    queue.push_lines(b"BATCH +m1 draft/multiline #A\r\n@batch=m1 PRIVMSG #a :one\r\n@batch=m1;draft/multiline-concat PRIVMSG #a :two\r\nBATCH -m1\r\n");
+    queue.push_lines(b"PRIVMSG #b :1\r\nPRIVMSG #b :2\r\n");
+
+    let order: Vec<Vec<u8>> = range(0u, 6).filter_map(|_| queue.pop(0)).collect();
+    assert!(order == vec![
+        b"BATCH +m1 draft/multiline #A\r\n".to_vec(),
+        b"PRIVMSG #b :1\r\n".to_vec(),
+        b"@batch=m1 PRIVMSG #a :one\r\n".to_vec(),
+        b"PRIVMSG #b :2\r\n".to_vec(),
+        b"@batch=m1;draft/multiline-concat PRIVMSG #a :two\r\n".to_vec(),
+        b"BATCH -m1\r\n".to_vec()
+    ]);
+}
+
+#[test]
+fn byte_cost() {
+    let mut queue = SendQueue::new(FloodControl::new(2, 1000).with_byte_cost(10));
+    queue.push_lines(b"PRIVMSG a :b\r\nPRIVMSG a :b\r\n");
+
+    // Each 14 byte line costs 1.4 tokens
+    assert!(queue.pop(0).is_some());
+    assert!(queue.pop(0).is_none());
+    assert!(queue.next_ready_in(0) == Some(800));
+}
@@ -0,0 +1,393 @@
+/// The RGB values of the mIRC colours 0 to 98. Colour 99 means the default colour.
+static PALETTE: [u32, ..99] = [
+    0xffffff, 0x000000, 0x00007f, 0x009300, 0xff0000, 0x7f0000, 0x9c009c, 0xfc7f00,
+    0xffff00, 0x00fc00, 0x009393, 0x00ffff, 0x0000fc, 0xff00ff, 0x7f7f7f, 0xd2d2d2,
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c, 0x004747, 0x002747, 0x000047, 0x2e0047, 0x470047, 0x47002a,
+    0x740000, 0x743a00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045,
+    0xb50000, 0xb56300, 0xb5b500, 0x7db500, 0x00b500, 0x00b571, 0x00b5b5, 0x0063b5, 0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b,
+    0xff0000, 0xff8c00, 0xffff00, 0xb2ff00, 0x00ff00, 0x00ffa0, 0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098,
+    0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9, 0x6dffff, 0x59b4ff, 0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc,
+    0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c, 0x9cff9c, 0x9cffdb, 0x9cffff, 0x9cd3ff, 0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565, 0x818181, 0x9f9f9f, 0xbcbcbc, 0xe2e2e2, 0xffffff
+];
+
+/// A text colour, either one of the mIRC colours (`\x03`) or any RGB colour (`\x04`).
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Color {
+    Irc(u8),
+    Rgb(u8, u8, u8)
+}
+
+#[experimental]
+impl Color {
+    #[experimental]
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            Color::Irc(n) => {
+                let rgb = PALETTE[n as uint % PALETTE.len()];
+                ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+            },
+            Color::Rgb(r, g, b) => (r, g, b)
+        }
+    }
+}
+
+/// How a piece of text is formatted.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    /// Swaps the text and background colours.
+    pub reverse: bool,
+    pub foreground: Option<Color>,
+    pub background: Option<Color>
+}
+
+#[experimental]
+impl Style {
+    /// No formatting at all.
+    #[experimental]
+    pub fn new() -> Style {
+        Style {
+            bold: false,
+            italic: false,
+            underline: false,
+            strikethrough: false,
+            monospace: false,
+            reverse: false,
+            foreground: None,
+            background: None
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_plain(&self) -> bool {
+        *self == Style::new()
+    }
+}
+
+/// A run of text with the same formatting.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Span {
+    pub text: String,
+    pub style: Style
+}
+
+/// Splits message text into spans of differently formatted text, leaving out the formatting codes.
+#[experimental]
+pub fn parse_formatting(text: &str) -> Vec<Span> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans = Vec::new();
+    let mut style = Style::new();
+    let mut current = String::new();
+    let mut i = 0u;
+
+    while i < chars.len() {
+        let before = style.clone();
+        match chars[i] {
+            '\x02' => style.bold = !style.bold,
+            '\x1d' => style.italic = !style.italic,
+            '\x1f' => style.underline = !style.underline,
+            '\x1e' => style.strikethrough = !style.strikethrough,
+            '\x11' => style.monospace = !style.monospace,
+            '\x16' => style.reverse = !style.reverse,
+            '\x0f' => style = Style::new(),
+            '\x03' => {
+                let (foreground, background, used) = read_colors(chars.slice_from(i + 1), 10, 1, 2);
+                i += used;
+                set_colors(&mut style, foreground.map(|n| irc_color(n)), background.map(|n| irc_color(n)));
+            },
+            '\x04' => {
+                let (foreground, background, used) = read_colors(chars.slice_from(i + 1), 16, 6, 6);
+                i += used;
+                set_colors(&mut style, foreground.map(|n| rgb_color(n)), background.map(|n| rgb_color(n)));
+            },
+            c => current.push(c)
+        }
+        i += 1;
+
+        if style != before && !current.is_empty() {
+            spans.push(Span {
+                text: current,
+                style: before
+            });
+            current = String::new();
+        }
+    }
+
+    if !current.is_empty() {
+        spans.push(Span {
+            text: current,
+            style: style
+        });
+    }
+    spans
+}
+
+/// Removes every formatting code, e.g. for logging.
+#[experimental]
+pub fn strip_formatting(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    for span in parse_formatting(text).iter() {
+        stripped.push_str(span.text.as_slice());
+    }
+    stripped
+}
+
+/// Renders spans as HTML, escaping the text and using inline styles.
+#[experimental]
+pub fn spans_to_html(spans: &[Span]) -> String {
+    let mut html = String::new();
+
+    for span in spans.iter() {
+        let text = escape_html(span.text.as_slice());
+        if span.style.is_plain() {
+            html.push_str(text.as_slice());
+            continue;
+        }
+
+        let style = &span.style;
+        let mut css = Vec::new();
+        if style.bold {
+            css.push(String::from_str("font-weight:bold"));
+        }
+        if style.italic {
+            css.push(String::from_str("font-style:italic"));
+        }
+        match (style.underline, style.strikethrough) {
+            (true, true) => css.push(String::from_str("text-decoration:underline line-through")),
+            (true, false) => css.push(String::from_str("text-decoration:underline")),
+            (false, true) => css.push(String::from_str("text-decoration:line-through")),
+            (false, false) => {}
+        }
+        if style.monospace {
+            css.push(String::from_str("font-family:monospace"));
+        }
+
+        let (foreground, background) = if style.reverse {
+            // Without colours to swap, reverse the usual black on white
+            (Some(style.background.clone().unwrap_or(Color::Irc(0))), Some(style.foreground.clone().unwrap_or(Color::Irc(1))))
+        } else {
+            (style.foreground.clone(), style.background.clone())
+        };
+        match foreground {
+            Some(color) => css.push(format!("color:{}", css_color(&color))),
+            None => {}
+        }
+        match background {
+            Some(color) => css.push(format!("background-color:{}", css_color(&color))),
+            None => {}
+        }
+
+        html.push_str(format!("<span style=\"{}\">{}</span>", css.connect(";"), text).as_slice());
+    }
+    html
+}
+
+/// Renders spans with ANSI escape codes for a terminal, using 24-bit colour. Monospace has no equivalent, so it is left out.
+#[experimental]
+pub fn spans_to_ansi(spans: &[Span]) -> String {
+    let mut ansi = String::new();
+
+    for span in spans.iter() {
+        let style = &span.style;
+        let mut codes = Vec::new();
+        if style.bold {
+            codes.push(String::from_str("1"));
+        }
+        if style.italic {
+            codes.push(String::from_str("3"));
+        }
+        if style.underline {
+            codes.push(String::from_str("4"));
+        }
+        if style.reverse {
+            codes.push(String::from_str("7"));
+        }
+        if style.strikethrough {
+            codes.push(String::from_str("9"));
+        }
+        match style.foreground {
+            Some(ref color) => {
+                let (r, g, b) = color.rgb();
+                codes.push(format!("38;2;{};{};{}", r, g, b));
+            },
+            None => {}
+        }
+        match style.background {
+            Some(ref color) => {
+                let (r, g, b) = color.rgb();
+                codes.push(format!("48;2;{};{};{}", r, g, b));
+            },
+            None => {}
+        }
+
+        if codes.is_empty() {
+            ansi.push_str(span.text.as_slice());
+        } else {
+            ansi.push_str(format!("\x1b[{}m{}\x1b[0m", codes.connect(";"), span.text).as_slice());
+        }
+    }
+    ansi
+}
+
+#[experimental]
+pub fn bold(text: &str) -> String {
+    format!("\x02{}\x02", text)
+}
+
+#[experimental]
+pub fn italic(text: &str) -> String {
+    format!("\x1d{}\x1d", text)
+}
+
+#[experimental]
+pub fn underline(text: &str) -> String {
+    format!("\x1f{}\x1f", text)
+}
+
+#[experimental]
+pub fn strikethrough(text: &str) -> String {
+    format!("\x1e{}\x1e", text)
+}
+
+#[experimental]
+pub fn monospace(text: &str) -> String {
+    format!("\x11{}\x11", text)
+}
+
+/// Colours text with mIRC colours, resetting to the default colours after it.
+#[experimental]
+pub fn color(text: &str, foreground: u8, background: Option<u8>) -> String {
+    match background {
+        Some(background) => format!("\x03{:02},{:02}{}\x03", foreground, background, text),
+        None => format!("\x03{:02}{}{}\x03", foreground, separator(text), text)
+    }
+}
+
+/// Colours text with any RGB colours, resetting to the default colours after it.
+#[experimental]
+pub fn hex_color(text: &str, foreground: (u8, u8, u8), background: Option<(u8, u8, u8)>) -> String {
+    let (r, g, b) = foreground;
+    match background {
+        Some((br, bg, bb)) => format!("\x04{:02X}{:02X}{:02X},{:02X}{:02X}{:02X}{}\x04", r, g, b, br, bg, bb, text),
+        None => format!("\x04{:02X}{:02X}{:02X}{}{}\x04", r, g, b, separator(text), text)
+    }
+}
+
+/// An empty bold toggle to keep a comma at the start of coloured text from being read as a background colour.
+fn separator(text: &str) -> &'static str {
+    if text.starts_with(",") { "\x02\x02" } else { "" }
+}
+
+/// Reads the `foreground[,background]` after a colour code, returning them and how many characters they used.
+fn read_colors(chars: &[char], radix: uint, min_digits: uint, max_digits: uint) -> (Option<u32>, Option<u32>, uint) {
+    let (foreground, used) = read_number(chars, radix, min_digits, max_digits);
+    if foreground.is_none() {
+        return (None, None, 0);
+    }
+
+    if chars.len() > used && chars[used] == ',' {
+        match read_number(chars.slice_from(used + 1), radix, min_digits, max_digits) {
+            (Some(background), bg_used) => return (foreground, Some(background), used + 1 + bg_used),
+            (None, _) => {}
+        }
+    }
+    (foreground, None, used)
+}
+
+fn read_number(chars: &[char], radix: uint, min_digits: uint, max_digits: uint) -> (Option<u32>, uint) {
+    let mut value = 0u32;
+    let mut used = 0u;
+    while used < max_digits && used < chars.len() {
+        match chars[used].to_digit(radix) {
+            Some(digit) => value = value * radix as u32 + digit as u32,
+            None => break
+        }
+        used += 1;
+    }
+
+    if used < min_digits {
+        (None, 0)
+    } else {
+        (Some(value), used)
+    }
+}
+
+/// A `\x03` with no colours after it resets them, and 99 means the default colour.
+fn set_colors(style: &mut Style, foreground: Option<Option<Color>>, background: Option<Option<Color>>) {
+    match foreground {
+        Some(foreground) => {
+            style.foreground = foreground;
+            match background {
+                Some(background) => style.background = background,
+                None => {}
+            }
+        },
+        None => {
+            style.foreground = None;
+            style.background = None;
+        }
+    }
+}
+
+fn irc_color(n: u32) -> Option<Color> {
+    if n < 99 { Some(Color::Irc(n as u8)) } else { None }
+}
+
+fn rgb_color(n: u32) -> Option<Color> {
+    Some(Color::Rgb((n >> 16) as u8, (n >> 8) as u8, n as u8))
+}
+
+fn css_color(color: &Color) -> String {
+    let (r, g, b) = color.rgb();
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+#[test]
+fn parsing() {
+    let spans = parse_formatting("plain \x02bold\x0304,12red\x03 \x1ditalic\x0f\x0412ab34text");
+    let texts: Vec<&str> = spans.iter().map(|span| span.text.as_slice()).collect();
+    assert!(texts == vec!["plain ", "bold", "red", " ", "italic", "text"]);
+
+    assert!(spans[0].style.is_plain());
+    assert!(spans[2].style.bold && spans[2].style.foreground == Some(Color::Irc(4)) && spans[2].style.background == Some(Color::Irc(12)));
+    assert!(spans[3].style.bold && spans[3].style.foreground.is_none());
+    assert!(spans[5].style.foreground == Some(Color::Rgb(0x12, 0xab, 0x34)) && !spans[5].style.italic);
+
+    // Digits after a two digit colour, and a comma with no background, are text
+    assert!(strip_formatting("\x03041234\x03,x \x0399,99default") == String::from_str("1234,x default"));
+}
+
+#[test]
+fn rendering() {
+    let spans = parse_formatting("a \x02<b>\x02 \x0304red");
+    assert!(spans_to_html(spans.as_slice()).as_slice() == "a <span style=\"font-weight:bold\">&lt;b&gt;</span> <span style=\"color:#ff0000\">red</span>");
+    assert!(spans_to_ansi(spans.as_slice()).as_slice() == "a \x1b[1m<b>\x1b[0m \x1b[38;2;255;0;0mred\x1b[0m");
+
+    assert!(color(",5", 4, None).as_slice() == "\x0304\x02\x02,5\x03");
+    assert!(strip_formatting(color(",5", 4, None).as_slice()).as_slice() == ",5");
+    assert!(hex_color("hi", (255, 0, 16), Some((0, 0, 0))).as_slice() == "\x04FF0010,000000hi\x04");
+    assert!(strip_formatting(bold(italic("both").as_slice()).as_slice()).as_slice() == "both");
+}
@@ -0,0 +1,348 @@
+use std::ascii::AsciiExt;
+use std::io::IoResult;
+
+use basicclient::BasicClient;
+use capability::Capability;
+use message::Message;
+use prefix::Prefix;
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
+use std::rc::Rc;
+
+/// Whether a message should be passed on to the handlers after the current one.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Propagation {
+    Continue,
+    Stop
+}
+
+/// Receives the messages read by a `Dispatcher`. Every method defaults to doing nothing and returning `Continue`.
+///
+/// `on_message` is called for every message, before the more specific method for that message's command.
+#[experimental]
+pub trait Handler {
+    #[experimental]
+    fn on_message(&mut self, _client: &mut BasicClient, _msg: &Message<'static>) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_privmsg(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, _target: &str, _text: &str) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_notice(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: Option<&Prefix<'static>>, _target: &str, _text: &str) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_join(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, _channel: &str) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_part(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, _channel: &str, _reason: Option<&str>) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_kick(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, _channel: &str, _nick: &str, _reason: Option<&str>) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_quit(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, _reason: Option<&str>) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_nick(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, _new_nick: &str) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_numeric(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _numeric: u16) -> Propagation {
+        Propagation::Continue
+    }
+
+    #[experimental]
+    fn on_cap_ack(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _caps: &[Capability]) -> Propagation {
+        Propagation::Continue
+    }
+}
+
+/// A closure called for the messages matching a filter, which can keep state between calls.
+#[experimental]
+pub type Callback = Box<FnMut(&mut BasicClient, &Message<'static>) -> Propagation + 'static>;
+
+/// Identifies a registered handler, for removing it.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct HandlerId(uint);
+
+enum Filter {
+    Command(String),
+    Numeric(u16),
+    CapAck
+}
+
+/// Calls a closure for the messages matching a filter.
+struct CallbackHandler {
+    filter: Filter,
+    callback: Callback
+}
+
+impl Handler for CallbackHandler {
+    fn on_message(&mut self, client: &mut BasicClient, msg: &Message<'static>) -> Propagation {
+        let matches = match self.filter {
+            Filter::Command(ref command) => msg.command().as_slice() == command.as_slice(),
+            Filter::Numeric(numeric) => numeric_of(msg) == Some(numeric),
+            Filter::CapAck => is_cap_ack(msg)
+        };
+
+        if matches {
+            self.callback.call_mut((client, msg))
+        } else {
+            Propagation::Continue
+        }
+    }
+}
+
+struct HandlerEntry {
+    id: uint,
+    priority: int,
+    handler: Box<Handler + 'static>
+}
+
+/// Reads messages from a `BasicClient` and passes them to the registered handlers.
+///
+/// Handlers with a lower priority run first, and handlers with the same priority run in the order they were added. Any handler can stop a message from reaching the handlers after it by returning `Stop`.
+#[experimental]
+pub struct Dispatcher {
+    handlers: Vec<HandlerEntry>,
+    next_id: uint
+}
+
+#[experimental]
+impl Dispatcher {
+    #[experimental]
+    pub fn new() -> Dispatcher {
+        Dispatcher {
+            handlers: Vec::new(),
+            next_id: 0
+        }
+    }
+
+    #[experimental]
+    pub fn add_handler(&mut self, priority: int, handler: Box<Handler + 'static>) -> HandlerId {
+        self.next_id += 1;
+        let index = self.handlers.iter().position(|entry| entry.priority > priority).unwrap_or(self.handlers.len());
+        self.handlers.insert(index, HandlerEntry {
+            id: self.next_id,
+            priority: priority,
+            handler: handler
+        });
+        HandlerId(self.next_id)
+    }
+
+    /// Removes a handler or callback, returning false if it had already been removed.
+    #[experimental]
+    pub fn remove_handler(&mut self, id: HandlerId) -> bool {
+        let HandlerId(id) = id;
+        match self.handlers.iter().position(|entry| entry.id == id) {
+            Some(index) => {
+                self.handlers.remove(index);
+                true
+            },
+            None => false
+        }
+    }
+
+    #[experimental]
+    pub fn on_command(&mut self, priority: int, command: &str, callback: Callback) -> HandlerId {
+        self.add_callback(priority, Filter::Command(command.to_ascii_upper()), callback)
+    }
+
+    #[experimental]
+    pub fn on_numeric(&mut self, priority: int, numeric: u16, callback: Callback) -> HandlerId {
+        self.add_callback(priority, Filter::Numeric(numeric), callback)
+    }
+
+    #[experimental]
+    pub fn on_privmsg(&mut self, priority: int, callback: Callback) -> HandlerId {
+        self.on_command(priority, "PRIVMSG", callback)
+    }
+
+    #[experimental]
+    pub fn on_notice(&mut self, priority: int, callback: Callback) -> HandlerId {
+        self.on_command(priority, "NOTICE", callback)
+    }
+
+    #[experimental]
+    pub fn on_join(&mut self, priority: int, callback: Callback) -> HandlerId {
+        self.on_command(priority, "JOIN", callback)
+    }
+
+    #[experimental]
+    pub fn on_cap_ack(&mut self, priority: int, callback: Callback) -> HandlerId {
+        self.add_callback(priority, Filter::CapAck, callback)
+    }
+
+    fn add_callback(&mut self, priority: int, filter: Filter, callback: Callback) -> HandlerId {
+        self.add_handler(priority, box CallbackHandler {
+            filter: filter,
+            callback: callback
+        })
+    }
+
+    /// Passes a message to each handler in turn, returning `Stop` if one of them stopped it.
+    #[experimental]
+    pub fn dispatch(&mut self, client: &mut BasicClient, msg: &Message<'static>) -> Propagation {
+        for entry in self.handlers.iter_mut() {
+            let handler = &mut *entry.handler;
+            if handler.on_message(client, msg) == Propagation::Stop || dispatch_typed(handler, client, msg) == Propagation::Stop {
+                return Propagation::Stop;
+            }
+        }
+        Propagation::Continue
+    }
+
+    /// Reads and dispatches messages until the client disconnects or an error occurs.
+    #[experimental]
+    pub fn run(&mut self, client: &mut BasicClient) -> IoResult<()> {
+        while client.is_connected() {
+            match try!(client.read_message()) {
+                Some(msg) => { self.dispatch(client, &msg); },
+                None => {}
+            }
+            // `read_message` keeps `CAP ACK`s back, so they are taken separately
+            for msg in client.take_cap_acks().iter() {
+                self.dispatch(client, msg);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn dispatch_typed(handler: &mut Handler, client: &mut BasicClient, msg: &Message<'static>) -> Propagation {
+    let params: Vec<&str> = match *msg.params() {
+        Some(ref params) => params.iter().map(|p| p.as_slice()).collect(),
+        None => Vec::new()
+    };
+
+    match numeric_of(msg) {
+        Some(numeric) => return handler.on_numeric(client, msg, numeric),
+        None => {}
+    }
+
+    if is_cap_ack(msg) {
+        let caps: Vec<Capability> = params[2].split(' ').filter_map(|c| Capability::from_str(c)).collect();
+        return handler.on_cap_ack(client, msg, caps.as_slice());
+    }
+
+    let n = params.len();
+    match (msg.command().as_slice(), msg.prefix()) {
+        ("NOTICE", source) if n > 1 => handler.on_notice(client, msg, source.as_ref(), params[0], params[1]),
+        ("PRIVMSG", &Some(ref source)) if n > 1 => handler.on_privmsg(client, msg, source, params[0], params[1]),
+        ("JOIN", &Some(ref source)) if n > 0 => handler.on_join(client, msg, source, params[0]),
+        ("PART", &Some(ref source)) if n > 0 => handler.on_part(client, msg, source, params[0], param(&params, 1)),
+        ("KICK", &Some(ref source)) if n > 1 => handler.on_kick(client, msg, source, params[0], params[1], param(&params, 2)),
+        ("QUIT", &Some(ref source)) => handler.on_quit(client, msg, source, param(&params, 0)),
+        ("NICK", &Some(ref source)) if n > 0 => handler.on_nick(client, msg, source, params[0]),
+        _ => Propagation::Continue
+    }
+}
+
+#[inline]
+fn param<'a>(params: &Vec<&'a str>, index: uint) -> Option<&'a str> {
+    if index < params.len() { Some(params[index]) } else { None }
+}
+
+fn numeric_of(msg: &Message) -> Option<u16> {
+    let command = msg.command().as_slice();
+    if command.len() == 3 && command.chars().all(|c| c >= '0' && c <= '9') {
+        from_str::<u16>(command)
+    } else {
+        None
+    }
+}
+
+fn is_cap_ack(msg: &Message) -> bool {
+    msg.command().as_slice() == "CAP" && match *msg.params() {
+        Some(ref params) => params.len() > 2 && params[1].as_slice() == "ACK",
+        None => false
+    }
+}
+
+#[cfg(test)]
+fn recorder(log: &Rc<RefCell<Vec<&'static str>>>, name: &'static str, result: Propagation) -> Callback {
+    let log = log.clone();
+    box move |&mut: _client: &mut BasicClient, _msg: &Message<'static>| {
+        log.borrow_mut().push(name);
+        result.clone()
+    }
+}
+
+#[test]
+fn priorities_and_stop() {
+    let mut client: BasicClient = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    let mut dispatcher = Dispatcher::new();
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    dispatcher.on_privmsg(10, recorder(&log, "late", Propagation::Continue));
+    dispatcher.on_command(-5, "privmsg", recorder(&log, "early", Propagation::Continue));
+    let stop = dispatcher.on_privmsg(0, recorder(&log, "stop", Propagation::Stop));
+    dispatcher.on_privmsg(0, recorder(&log, "same priority", Propagation::Continue));
+    dispatcher.on_join(-10, recorder(&log, "join", Propagation::Continue));
+
+    let msg = Message::from_str(":alice!a@host PRIVMSG #chan :hi").unwrap();
+    assert!(dispatcher.dispatch(&mut client, &msg) == Propagation::Stop);
+    assert!(*log.borrow() == vec!["early", "stop"]);
+
+    assert!(dispatcher.remove_handler(stop.clone()));
+    assert!(!dispatcher.remove_handler(stop));
+    log.borrow_mut().clear();
+    assert!(dispatcher.dispatch(&mut client, &msg) == Propagation::Continue);
+    assert!(*log.borrow() == vec!["early", "same priority", "late"]);
+}
+
+#[test]
+fn stateful_handlers() {
+    struct Counter {
+        joins: Rc<RefCell<uint>>
+    }
+
+    impl Handler for Counter {
+        fn on_join(&mut self, _client: &mut BasicClient, _msg: &Message<'static>, _source: &Prefix<'static>, channel: &str) -> Propagation {
+            assert!(channel == "#chan");
+            *self.joins.borrow_mut() += 1;
+            Propagation::Continue
+        }
+    }
+
+    let mut client: BasicClient = BasicClient::new("relay", "relay", "Relay", HashSet::new());
+    let mut dispatcher = Dispatcher::new();
+    let joins = Rc::new(RefCell::new(0u));
+    dispatcher.add_handler(0, box Counter { joins: joins.clone() });
+
+    // A callback can keep its own state too
+    let mut numerics = 0u;
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let log = seen.clone();
+    dispatcher.on_numeric(0, 1, box move |&mut: _client: &mut BasicClient, _msg: &Message<'static>| {
+        numerics += 1;
+        log.borrow_mut().push(numerics);
+        Propagation::Continue
+    });
+
+    dispatcher.dispatch(&mut client, &Message::from_str(":alice!a@host JOIN #chan").unwrap());
+    dispatcher.dispatch(&mut client, &Message::from_str(":irc 001 relay :Welcome").unwrap());
+    dispatcher.dispatch(&mut client, &Message::from_str(":irc 001 relay :Welcome").unwrap());
+    assert!(*joins.borrow() == 1);
+    assert!(*seen.borrow() == vec![1u, 2]);
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet, RingBuf};
+use std::fmt;
+use time::Timespec;
+
+use batch::Batch;
+use isupport::ISupport;
+use message::{Message, format_server_time, parse_server_time};
+
+/// How many message IDs are remembered for spotting duplicates.
+static MAX_SEEN_IDS: uint = 4096;
+
+/// A point in a conversation's history, for the `CHATHISTORY` commands.
+#[experimental]
+#[deriving(Clone, PartialEq)]
+pub enum HistorySelector {
+    /// `*`, meaning no bound, which `CHATHISTORY LATEST` accepts.
+    Latest,
+    Timestamp(Timespec),
+    MsgId(String)
+}
+
+#[experimental]
+impl fmt::Show for HistorySelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HistorySelector::Latest => write!(f, "*"),
+            HistorySelector::Timestamp(time) => write!(f, "timestamp={}", format_server_time(time)),
+            HistorySelector::MsgId(ref id) => write!(f, "msgid={}", id)
+        }
+    }
+}
+
+/// The messages from a `chathistory` batch.
+#[experimental]
+pub struct History {
+    target: String,
+    messages: Vec<Message<'static>>
+}
+
+#[experimental]
+impl History {
+    /// Reads a `chathistory` batch, or returns `None` for any other kind of batch.
+    #[experimental]
+    pub fn from_batch(batch: &Batch) -> Option<History> {
+        if batch.batch_type() != "chathistory" || batch.params().is_empty() {
+            return None;
+        }
+
+        Some(History {
+            target: batch.params()[0].clone(),
+            messages: batch.messages().to_vec()
+        })
+    }
+
+    /// The channel or nick whose history this is.
+    #[experimental]
+    #[inline]
+    pub fn target<'a>(&'a self) -> &'a str {
+        self.target.as_slice()
+    }
+
+    /// The messages, oldest first.
+    #[experimental]
+    #[inline]
+    pub fn messages<'a>(&'a self) -> &'a [Message<'static>] {
+        self.messages.as_slice()
+    }
+}
+
+/// Reads a `draft/chathistory-targets` batch, giving each target and the time of its latest message.
+#[experimental]
+pub fn history_targets(batch: &Batch) -> Option<Vec<(String, Option<Timespec>)>> {
+    if batch.batch_type() != "draft/chathistory-targets" {
+        return None;
+    }
+
+    Some(batch.messages().iter().filter_map(|msg| {
+        match (msg.command().as_slice(), msg.params()) {
+            ("CHATHISTORY", &Some(ref params)) if params.len() > 2 && params[0].as_slice() == "TARGETS" => {
+                let time = params[2].as_slice();
+                let time = if time.starts_with("timestamp=") { time.slice_from(10) } else { time };
+                Some((params[1].to_string(), parse_server_time(time)))
+            },
+            _ => None
+        }
+    }).collect())
+}
+
+/// Remembers the last message seen in each conversation so that missed messages can be fetched after reconnecting, and
+/// the message IDs seen so that messages received twice can be dropped.
+#[experimental]
+pub struct HistoryTracker {
+    last_seen: HashMap<String, (HistorySelector, Option<Timespec>)>,
+    seen_ids: HashSet<String>,
+    seen_order: RingBuf<String>,
+    catching_up: HashSet<String>
+}
+
+#[experimental]
+impl HistoryTracker {
+    #[experimental]
+    pub fn new() -> HistoryTracker {
+        HistoryTracker {
+            last_seen: HashMap::new(),
+            seen_ids: HashSet::new(),
+            seen_order: RingBuf::new(),
+            catching_up: HashSet::new()
+        }
+    }
+
+    /// Records a message, returning false if its `msgid` has been seen before. A conversation's last seen message only
+    /// moves forward, so replayed history older than it is ignored.
+    #[experimental]
+    pub fn record(&mut self, own_nick: &str, isupport: &ISupport, msg: &Message) -> bool {
+        match msg.msgid() {
+            Some(id) => {
+                let id = String::from_str(id);
+                if self.seen_ids.contains(&id) {
+                    return false;
+                }
+                if self.seen_order.len() == MAX_SEEN_IDS {
+                    let oldest = self.seen_order.pop_front().unwrap();
+                    self.seen_ids.remove(&oldest);
+                }
+                self.seen_ids.insert(id.clone());
+                self.seen_order.push_back(id);
+            },
+            None => {}
+        }
+
+        match conversation(own_nick, isupport, msg) {
+            Some(target) => {
+                let time = msg.server_time();
+                let selector = match (msg.msgid(), time) {
+                    (Some(id), _) => HistorySelector::MsgId(String::from_str(id)),
+                    (None, Some(time)) => HistorySelector::Timestamp(time),
+                    (None, None) => return true
+                };
+                let older = match (self.last_seen.get(&target), time) {
+                    (Some(&(_, Some(last))), Some(time)) => time < last,
+                    _ => false
+                };
+                if !older {
+                    self.last_seen.insert(target, (selector, time));
+                }
+            },
+            None => {}
+        }
+        true
+    }
+
+    /// Where to fetch history from for `target`, if anything from it has been seen.
+    #[experimental]
+    pub fn last_seen<'a>(&'a self, target: &str, isupport: &ISupport) -> Option<&'a HistorySelector> {
+        self.last_seen.get(&isupport.case_mapping().casefold(target)).map(|&(ref selector, _)| selector)
+    }
+
+    /// Marks every known conversation as needing to catch up, e.g. after reconnecting.
+    #[experimental]
+    pub fn start_catch_up(&mut self) {
+        self.catching_up = self.last_seen.keys().map(|target| target.clone()).collect();
+    }
+
+    /// Takes the point to catch up `target` from, if it still needs to catch up.
+    #[experimental]
+    pub fn catch_up(&mut self, target: &str, isupport: &ISupport) -> Option<HistorySelector> {
+        let target = isupport.case_mapping().casefold(target);
+        if self.catching_up.remove(&target) {
+            self.last_seen.get(&target).map(|&(ref selector, _)| selector.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Takes every conversation with a user (rather than a channel) that still needs to catch up.
+    #[experimental]
+    pub fn catch_up_queries(&mut self, isupport: &ISupport) -> Vec<(String, HistorySelector)> {
+        let queries: Vec<String> = self.catching_up.iter().filter(|target| !isupport.is_channel(target.as_slice())).map(|t| t.clone()).collect();
+
+        queries.into_iter().filter_map(|target| {
+            self.catching_up.remove(&target);
+            self.last_seen.get(&target).map(|&(ref selector, _)| (target.clone(), selector.clone()))
+        }).collect()
+    }
+}
+
+/// The casefolded channel or nick a message belongs to, for messages that are kept in history.
+fn conversation(own_nick: &str, isupport: &ISupport, msg: &Message) -> Option<String> {
+    match msg.command().as_slice() {
+        "PRIVMSG" | "NOTICE" | "TAGMSG" => {},
+        _ => return None
+    }
+
+    let target = match *msg.params() {
+        Some(ref params) => params[0].as_slice(),
+        None => return None
+    };
+    let case_mapping = isupport.case_mapping();
+
+    // A private message belongs to the conversation with whoever sent it
+    if case_mapping.equals(target, own_nick) {
+        msg.prefix().as_ref().and_then(|prefix| prefix.nick()).map(|nick| case_mapping.casefold(nick))
+    } else {
+        Some(case_mapping.casefold(target))
+    }
+}
+
+#[test]
+fn selectors() {
+    assert!(HistorySelector::Latest.to_string().as_slice() == "*");
+    assert!(HistorySelector::MsgId(String::from_str("abc")).to_string().as_slice() == "msgid=abc");
+    assert!(HistorySelector::Timestamp(Timespec::new(1319042451, 620000000)).to_string().as_slice() == "timestamp=2011-10-19T16:40:51.620Z");
+}
+
+#[test]
+fn tracking_and_duplicates() {
+    let isupport = ISupport::new();
+    let mut tracker = HistoryTracker::new();
+
+    let first = Message::from_str("@msgid=a1 :alice!a@host PRIVMSG #Chan :hi").unwrap();
+    assert!(tracker.record("relay", &isupport, &first));
+    assert!(!tracker.record("relay", &isupport, &first));
+    assert!(tracker.record("relay", &isupport, &Message::from_str("@time=2011-10-19T16:40:51.620Z :bob!b@host PRIVMSG relay :psst").unwrap()));
+
+    assert!(tracker.last_seen("#chan", &isupport) == Some(&HistorySelector::MsgId(String::from_str("a1"))));
+    tracker.start_catch_up();
+    assert!(tracker.catch_up_queries(&isupport) == vec![(String::from_str("bob"), HistorySelector::Timestamp(Timespec::new(1319042451, 620000000)))]);
+    assert!(tracker.catch_up("#CHAN", &isupport).is_some());
+    assert!(tracker.catch_up("#chan", &isupport).is_none());
+}
+
+#[test]
+fn last_seen_only_moves_forward() {
+    let isupport = ISupport::new();
+    let mut tracker = HistoryTracker::new();
+
+    tracker.record("relay", &isupport, &Message::from_str("@msgid=b2;time=2011-10-19T16:40:51.620Z :alice!a@host PRIVMSG #chan :now").unwrap());
+    tracker.record("relay", &isupport, &Message::from_str("@msgid=a1;time=2011-10-19T16:30:00.000Z :alice!a@host PRIVMSG #chan :then").unwrap());
+    assert!(tracker.last_seen("#chan", &isupport) == Some(&HistorySelector::MsgId(String::from_str("b2"))));
+
+    tracker.record("relay", &isupport, &Message::from_str("@msgid=c3 :alice!a@host PRIVMSG #chan :later").unwrap());
+    assert!(tracker.last_seen("#chan", &isupport) == Some(&HistorySelector::MsgId(String::from_str("c3"))));
+}
@@ -1,7 +1,14 @@
-use std::io::{IoResult, Writer};
-use std::str::replace;
+use std::io::{IoError, IoErrorKind, IoResult, Writer};
 
 use capability::Capability;
+use ctcp::Ctcp;
+use dcc::Dcc;
+use history::HistorySelector;
+use mode::{ModeBuilder, ModeChange};
+use tag::Tag;
+
+/// The most bytes of tag data a client may send, not counting the `@` and the space after the tags.
+static MAX_CLIENT_TAG_LEN: uint = 4094;
 
 #[experimental]
 pub trait IrcMethods: Writer {
@@ -123,6 +130,19 @@ pub trait IrcMethods: Writer {
         }
     }
 
+    /// Sends the mode changes in as many `MODE` commands as the server's `MODES` limit requires.
+    #[experimental]
+    fn change_modes(&mut self, target: &str, changes: &[ModeChange], max_modes: Option<uint>) -> IoResult<()> {
+        let mut builder = ModeBuilder::with_limit(max_modes);
+        for change in changes.iter() {
+            builder.push(change.clone());
+        }
+        for line in builder.build().iter() {
+            try!(write!(self, "MODE {} {}\r\n", target, line));
+        }
+        Ok(())
+    }
+
     #[experimental]
     fn topic(&mut self, channel: &str, new_topic: Option<&str>) -> IoResult<()> {
         match new_topic {
@@ -282,16 +302,52 @@ pub trait IrcMethods: Writer {
         write!(self, "NOTICE {} :{}\r\n", target, message)
     }
 
+    /// Whether client-only (`+`) tags can be sent, i.e. whether the server has enabled `message-tags`. Writers that don't
+    /// know which capabilities are enabled assume it hasn't been.
+    #[experimental]
+    fn client_tags_enabled(&self) -> bool {
+        false
+    }
+
+    #[experimental]
+    fn privmsg_tagged(&mut self, tags: &[Tag], target: &str, message: &str) -> IoResult<()> {
+        let tags = try!(format_tags(tags, self.client_tags_enabled()));
+        write!(self, "{}PRIVMSG {} :{}\r\n", tags, target, message)
+    }
+
+    #[experimental]
+    fn notice_tagged(&mut self, tags: &[Tag], target: &str, message: &str) -> IoResult<()> {
+        let tags = try!(format_tags(tags, self.client_tags_enabled()));
+        write!(self, "{}NOTICE {} :{}\r\n", tags, target, message)
+    }
+
+    /// Sends a `TAGMSG`, such as a typing notification. Without `message-tags` there is nothing to send, so this does nothing.
+    #[experimental]
+    fn tagmsg(&mut self, tags: &[Tag], target: &str) -> IoResult<()> {
+        if !self.client_tags_enabled() {
+            return Ok(());
+        }
+
+        let tags = try!(format_tags(tags, true));
+        write!(self, "{}TAGMSG {}\r\n", tags, target)
+    }
+
     #[experimental]
     fn ctcp_request(&mut self, ctcp_type: &str, target: &str, content: &str) -> IoResult<()> {
-        let content = replace(content, "\x16", "\x16\x16").replace("\0", "\x160").replace("\n", "\x16n").replace("\r", "\x16r").replace("\x01", "\\a");
-        write!(self, "PRIVMSG {} :\x01{} {}\x01", target, ctcp_type, content)
+        let ctcp = Ctcp::new(ctcp_type, if content.is_empty() { None } else { Some(content) });
+        write!(self, "PRIVMSG {} :{}\r\n", target, ctcp.to_text())
     }
 
     #[experimental]
     fn ctcp_reply(&mut self, ctcp_type: &str, target: &str, content: &str) -> IoResult<()> {
-        let content = replace(content, "\x16", "\x16\x16").replace("\0", "\x160").replace("\n", "\x16n").replace("\r", "\x16r").replace("\x01", "\\a");
-        write!(self, "NOTICE {} :\x01{} {}\x01", target, ctcp_type, content)
+        let ctcp = Ctcp::new(ctcp_type, if content.is_empty() { None } else { Some(content) });
+        write!(self, "NOTICE {} :{}\r\n", target, ctcp.to_text())
+    }
+
+    /// Sends a DCC offer, or a `RESUME` or `ACCEPT`.
+    #[experimental]
+    fn dcc(&mut self, target: &str, dcc: &Dcc) -> IoResult<()> {
+        write!(self, "PRIVMSG {} :{}\r\n", target, dcc.to_ctcp().to_text())
     }
 
     #[experimental]
@@ -447,6 +503,33 @@ pub trait IrcMethods: Writer {
         write!(self, "METADATA {} CLEAR\r\n", target)
     }
 
+    #[experimental]
+    fn metadata_get(&mut self, target: &str, keys: Vec<&str>) -> IoResult<()> {
+        write!(self, "METADATA {} GET {}\r\n", target, keys.connect(" "))
+    }
+
+    /// Asks to be told whenever any of `keys` changes on a user or channel we share (`draft/metadata-2`).
+    #[experimental]
+    fn metadata_sub(&mut self, keys: Vec<&str>) -> IoResult<()> {
+        write!(self, "METADATA * SUB {}\r\n", keys.connect(" "))
+    }
+
+    #[experimental]
+    fn metadata_unsub(&mut self, keys: Vec<&str>) -> IoResult<()> {
+        write!(self, "METADATA * UNSUB {}\r\n", keys.connect(" "))
+    }
+
+    #[experimental]
+    fn metadata_subs(&mut self) -> IoResult<()> {
+        self.write(b"METADATA * SUBS\r\n")
+    }
+
+    /// Asks for the metadata of a target again, after the server replied that it couldn't send it yet.
+    #[experimental]
+    fn metadata_sync(&mut self, target: &str) -> IoResult<()> {
+        write!(self, "METADATA {} SYNC\r\n", target)
+    }
+
     #[experimental]
     fn monitor_add(&mut self, targets: Vec<&str>) -> IoResult<()> {
         write!(self, "MONITOR + {}\r\n", targets.connect(","))
@@ -471,4 +554,68 @@ pub trait IrcMethods: Writer {
     fn monitor_status(&mut self) -> IoResult<()> {
         self.write(b"MONITOR S\r\n")
     }
+
+    /// Asks for the latest `limit` messages in `target`, or only those after `after` if it isn't `Latest`.
+    #[experimental]
+    fn chathistory_latest(&mut self, target: &str, after: &HistorySelector, limit: uint) -> IoResult<()> {
+        write!(self, "CHATHISTORY LATEST {} {} {}\r\n", target, after, limit)
+    }
+
+    #[experimental]
+    fn chathistory_before(&mut self, target: &str, before: &HistorySelector, limit: uint) -> IoResult<()> {
+        write!(self, "CHATHISTORY BEFORE {} {} {}\r\n", target, before, limit)
+    }
+
+    #[experimental]
+    fn chathistory_after(&mut self, target: &str, after: &HistorySelector, limit: uint) -> IoResult<()> {
+        write!(self, "CHATHISTORY AFTER {} {} {}\r\n", target, after, limit)
+    }
+
+    #[experimental]
+    fn chathistory_around(&mut self, target: &str, around: &HistorySelector, limit: uint) -> IoResult<()> {
+        write!(self, "CHATHISTORY AROUND {} {} {}\r\n", target, around, limit)
+    }
+
+    #[experimental]
+    fn chathistory_between(&mut self, target: &str, from: &HistorySelector, to: &HistorySelector, limit: uint) -> IoResult<()> {
+        write!(self, "CHATHISTORY BETWEEN {} {} {} {}\r\n", target, from, to, limit)
+    }
+
+    /// Asks which conversations had messages between two times. The server only accepts `Timestamp` selectors here.
+    #[experimental]
+    fn chathistory_targets(&mut self, from: &HistorySelector, to: &HistorySelector, limit: uint) -> IoResult<()> {
+        write!(self, "CHATHISTORY TARGETS {} {} {}\r\n", from, to, limit)
+    }
+}
+
+/// Formats tags as `@a=b;c `, leaving out client-only tags if they can't be sent.
+fn format_tags(tags: &[Tag], client_tags: bool) -> IoResult<String> {
+    let tags: Vec<String> = tags.iter()
+        .filter(|tag| client_tags || !tag.is_client_only())
+        .map(|tag| tag.to_string())
+        .collect();
+    let tags = tags.connect(";");
+
+    if tags.len() > MAX_CLIENT_TAG_LEN {
+        Err(IoError {
+            kind: IoErrorKind::InvalidInput,
+            desc: "Tags are too long",
+            detail: Some(format!("{} bytes of tags, but at most {} can be sent", tags.len(), MAX_CLIENT_TAG_LEN))
+        })
+    } else if tags.is_empty() {
+        Ok(tags)
+    } else {
+        Ok(format!("@{} ", tags))
+    }
+}
+
+#[test]
+fn format_message_tags() {
+    let tags = [Tag::new("+draft/reply", Some("abc;123")), Tag::new("label", Some("x"))];
+    assert!(format_tags(&tags, true).unwrap().as_slice() == "@+draft/reply=abc\\:123;label=x ");
+    assert!(format_tags(&tags, false).unwrap().as_slice() == "@label=x ");
+    assert!(format_tags(tags.slice_to(1), false).unwrap().as_slice() == "");
+
+    let long = String::from_char(MAX_CLIENT_TAG_LEN, 'a');
+    assert!(format_tags(&[Tag::new("+typing", Some(long.as_slice()))], true).is_err());
 }
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::num::from_str_radix;
+
+use message::Message;
+
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum CaseMapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459
+}
+
+#[experimental]
+impl CaseMapping {
+    #[experimental]
+    pub fn from_str(s: &str) -> Option<CaseMapping> {
+        match s {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            _ => None
+        }
+    }
+
+    #[experimental]
+    pub fn casefold_char(&self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'...'Z') => ((c as u8) + 32) as char,
+            (&CaseMapping::Ascii, _) => c,
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (&CaseMapping::Rfc1459, '^') => '~',
+            _ => c
+        }
+    }
+
+    #[experimental]
+    pub fn casefold(&self, input: &str) -> String {
+        input.chars().map(|c| self.casefold_char(c)).collect()
+    }
+
+    #[experimental]
+    pub fn equals(&self, a: &str, b: &str) -> bool {
+        a.len() == b.len() && a.chars().zip(b.chars()).all(|(x, y)| self.casefold_char(x) == self.casefold_char(y))
+    }
+}
+
+#[experimental]
+#[deriving(Clone)]
+pub struct ISupport {
+    tokens: HashMap<String, Option<String>>,
+    case_mapping: CaseMapping,
+    prefix: Vec<(char, char)>,
+    chan_modes: Vec<String>,
+    chan_types: String,
+    modes: Option<uint>
+}
+
+#[experimental]
+impl ISupport {
+    #[experimental]
+    pub fn new() -> ISupport {
+        ISupport {
+            tokens: HashMap::new(),
+            case_mapping: CaseMapping::Rfc1459,
+            prefix: vec![('o', '@'), ('v', '+')],
+            chan_modes: vec![String::from_str("beI"), String::from_str("k"), String::from_str("l"), String::from_str("imnpst")],
+            chan_types: String::from_str("#&"),
+            modes: Some(3)
+        }
+    }
+
+    /// Applies the tokens from an `RPL_ISUPPORT` (005) reply.
+    #[experimental]
+    pub fn update(&mut self, msg: &Message) {
+        let params = match *msg.params() {
+            Some(ref params) if params.len() > 2 => params,
+            _ => return
+        };
+
+        // The first parameter is our nick and the last is the human-readable trailer
+        for token in params.slice(1, params.len() - 1).iter().map(|p| p.as_slice()) {
+            if token.starts_with("-") {
+                let name = token.slice_from(1);
+                self.tokens.remove(&String::from_str(name));
+                self.reset(name);
+            } else {
+                let mut t = token.splitn(1, '=');
+                let name = t.next().unwrap_or("");
+                if name == "" {
+                    continue;
+                }
+                let value = t.next().map(|v| unescape_value(v));
+                self.apply(name, value.as_ref().map(|v| v.as_slice()));
+                self.tokens.insert(String::from_str(name), value);
+            }
+        }
+    }
+
+    fn apply(&mut self, name: &str, value: Option<&str>) {
+        match (name, value) {
+            ("CASEMAPPING", Some(v)) => {
+                match CaseMapping::from_str(v) {
+                    Some(case_mapping) => self.case_mapping = case_mapping,
+                    None => {}
+                }
+            },
+            ("PREFIX", Some(v)) => {
+                self.prefix = match (v.find('('), v.find(')')) {
+                    (Some(0), Some(close)) => {
+                        v.slice(1, close).chars().zip(v.slice_from(close + 1).chars()).collect()
+                    },
+                    _ => Vec::new()
+                };
+            },
+            ("PREFIX", None) => {
+                self.prefix = Vec::new();
+            },
+            ("CHANMODES", Some(v)) => {
+                let mut classes: Vec<String> = v.split(',').map(|c| String::from_str(c)).collect();
+                while classes.len() < 4 {
+                    classes.push(String::new());
+                }
+                self.chan_modes = classes;
+            },
+            ("CHANTYPES", v) => {
+                self.chan_types = String::from_str(v.unwrap_or(""));
+            },
+            ("MODES", v) => {
+                self.modes = match v {
+                    Some(v) => from_str::<uint>(v),
+                    None => None
+                };
+            },
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self, name: &str) {
+        let defaults = ISupport::new();
+        match name {
+            "CASEMAPPING" => self.case_mapping = defaults.case_mapping,
+            "PREFIX" => self.prefix = defaults.prefix,
+            "CHANMODES" => self.chan_modes = defaults.chan_modes,
+            "CHANTYPES" => self.chan_types = defaults.chan_types,
+            "MODES" => self.modes = defaults.modes,
+            _ => {}
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.tokens.contains_key(&String::from_str(name))
+    }
+
+    #[experimental]
+    pub fn value<'a>(&'a self, name: &str) -> Option<&'a str> {
+        match self.tokens.get(&String::from_str(name)) {
+            Some(&Some(ref value)) => Some(value.as_slice()),
+            _ => None
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn case_mapping(&self) -> CaseMapping {
+        self.case_mapping.clone()
+    }
+
+    /// The membership prefixes as `(mode, symbol)` pairs, highest rank first.
+    #[experimental]
+    #[inline]
+    pub fn prefixes<'a>(&'a self) -> &'a [(char, char)] {
+        self.prefix.as_slice()
+    }
+
+    #[experimental]
+    pub fn prefix_symbol(&self, mode: char) -> Option<char> {
+        self.prefix.iter().find(|&&(m, _)| m == mode).map(|&(_, s)| s)
+    }
+
+    #[experimental]
+    pub fn prefix_mode(&self, symbol: char) -> Option<char> {
+        self.prefix.iter().find(|&&(_, s)| s == symbol).map(|&(m, _)| m)
+    }
+
+    /// The rank of a membership prefix symbol, where 0 is the highest.
+    #[experimental]
+    pub fn prefix_rank(&self, symbol: char) -> Option<uint> {
+        self.prefix.iter().position(|&(_, s)| s == symbol)
+    }
+
+    /// The `CHANMODES` classes A (lists), B (always take a parameter), C (take a parameter when set) and D (never take a parameter).
+    #[experimental]
+    #[inline]
+    pub fn chan_modes<'a>(&'a self) -> &'a [String] {
+        self.chan_modes.as_slice()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn chan_types<'a>(&'a self) -> &'a str {
+        self.chan_types.as_slice()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn modes(&self) -> Option<uint> {
+        self.modes
+    }
+
+    #[experimental]
+    pub fn is_channel(&self, target: &str) -> bool {
+        target.len() > 0 && self.chan_types.as_slice().contains_char(target.char_at(0))
+    }
+}
+
+fn unescape_value(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0u;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1] == b'x' && bytes[i + 2] < 128 && bytes[i + 3] < 128 {
+            match from_str_radix::<u8>(input.slice(i + 2, i + 4), 16) {
+                Some(b) => {
+                    out.push(b);
+                    i += 4;
+                    continue;
+                },
+                None => {}
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(out.as_slice()).into_string()
+}
+
+#[test]
+fn case_mappings() {
+    assert!(CaseMapping::Rfc1459.casefold("Nick[^]\\").as_slice() == "nick{~}|");
+    assert!(CaseMapping::StrictRfc1459.casefold("Nick[^]").as_slice() == "nick{^}");
+    assert!(CaseMapping::Ascii.casefold("Nick[^]").as_slice() == "nick[^]");
+    assert!(CaseMapping::Rfc1459.equals("a^b", "A~B"));
+}
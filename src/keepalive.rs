@@ -0,0 +1,139 @@
+use message::Message;
+
+/// What a `Keepalive` wants done after being polled.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum KeepaliveAction {
+    /// Nothing for now.
+    Wait,
+    /// Send `PING` with this token.
+    Ping(String),
+    /// A `PING` went unanswered and nothing else has arrived since it was sent: the connection is dead.
+    TimedOut
+}
+
+/// Tracks how long the connection has been quiet, so that a `PING` can be sent after `idle_ms` without traffic and the
+/// connection declared dead if nothing comes back within `timeout_ms`. Times are in milliseconds from any fixed point.
+#[experimental]
+pub struct Keepalive {
+    idle_ms: u64,
+    timeout_ms: u64,
+    last_received: u64,
+    pending: Option<(String, u64)>,
+    lag: Option<u64>
+}
+
+#[experimental]
+impl Keepalive {
+    #[experimental]
+    pub fn new(idle_ms: u64, timeout_ms: u64) -> Keepalive {
+        Keepalive {
+            idle_ms: idle_ms,
+            timeout_ms: timeout_ms,
+            last_received: 0,
+            pending: None,
+            lag: None
+        }
+    }
+
+    /// Starts timing a new connection.
+    #[experimental]
+    pub fn reset(&mut self, now_ms: u64) {
+        self.last_received = now_ms;
+        self.pending = None;
+        self.lag = None;
+    }
+
+    /// The round-trip time of the last answered `PING`, or `None` if the last one went unanswered.
+    #[experimental]
+    #[inline]
+    pub fn lag(&self) -> Option<u64> {
+        self.lag
+    }
+
+    /// Records a message from the server, returning true if it was the `PONG` to our own `PING`.
+    #[experimental]
+    pub fn received(&mut self, now_ms: u64, msg: &Message) -> bool {
+        self.last_received = now_ms;
+
+        let answered = match (&self.pending, msg.params()) {
+            (&Some((ref token, _)), &Some(ref params)) if msg.command().as_slice() == "PONG" => {
+                params.last().map_or(false, |p| p.as_slice() == token.as_slice())
+            },
+            _ => false
+        };
+
+        if answered {
+            let (_, sent_at) = self.pending.take().unwrap();
+            self.lag = Some(now_ms - sent_at);
+        }
+        answered
+    }
+
+    /// A `PING` unanswered after `timeout_ms` means the connection is dead, unless something else has arrived since it was
+    /// sent, in which case its `PONG` is given up on and another `PING` is sent.
+    #[experimental]
+    pub fn poll(&mut self, now_ms: u64) -> KeepaliveAction {
+        if now_ms < self.deadline() {
+            return KeepaliveAction::Wait;
+        }
+
+        match self.pending {
+            Some((_, sent_at)) if self.last_received <= sent_at => return KeepaliveAction::TimedOut,
+            Some(_) => self.lag = None,
+            None => {}
+        }
+        let token = format!("relay-{}", now_ms);
+        self.pending = Some((token.clone(), now_ms));
+        KeepaliveAction::Ping(token)
+    }
+
+    /// How long until `poll` next needs to be called.
+    #[experimental]
+    pub fn next_poll_in(&self, now_ms: u64) -> u64 {
+        let deadline = self.deadline();
+        if deadline > now_ms { deadline - now_ms } else { 0 }
+    }
+
+    fn deadline(&self) -> u64 {
+        match self.pending {
+            Some((_, sent_at)) => sent_at + self.timeout_ms,
+            None => self.last_received + self.idle_ms
+        }
+    }
+}
+
+#[test]
+fn ping_and_lag() {
+    let mut keepalive = Keepalive::new(1000, 500);
+    keepalive.reset(0);
+
+    assert!(keepalive.poll(999) == KeepaliveAction::Wait);
+    assert!(keepalive.next_poll_in(999) == 1);
+    let token = match keepalive.poll(1000) {
+        KeepaliveAction::Ping(token) => token,
+        action => panic!("expected a PING, got {}", action)
+    };
+
+    let pong = Message::from_str(format!(":server PONG server :{}", token).as_slice()).unwrap();
+    assert!(keepalive.received(1120, &pong));
+    assert!(keepalive.lag() == Some(120));
+    assert!(keepalive.poll(1500) == KeepaliveAction::Wait);
+}
+
+#[test]
+fn unanswered_ping_times_out() {
+    let mut keepalive = Keepalive::new(1000, 500);
+    keepalive.reset(0);
+
+    keepalive.poll(1000);
+    // Other traffic shows the connection is alive, so a lost PONG only means trying again
+    assert!(!keepalive.received(1200, &Message::from_str(":a!b@c PRIVMSG #chan :hi").unwrap()));
+    assert!(keepalive.poll(1499) == KeepaliveAction::Wait);
+    match keepalive.poll(1500) {
+        KeepaliveAction::Ping(token) => assert!(token.as_slice() == "relay-1500"),
+        action => panic!("expected a PING, got {}", action)
+    }
+    assert!(keepalive.lag().is_none());
+    assert!(keepalive.poll(2000) == KeepaliveAction::TimedOut);
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::comm::TryRecvError;
+use std::io::{IoError, IoErrorKind, IoResult};
+use std::io::timer::sleep;
+use std::time::duration::Duration;
+use time::precise_time_ns;
+
+use batch::Batch;
+use message::Message;
+
+/// The server's answer to a labeled command.
+#[experimental]
+#[deriving(Clone)]
+pub enum LabeledResponse {
+    /// A single reply.
+    Message(Message<'static>),
+    /// The command was accepted but there was nothing to say.
+    Ack,
+    /// A `labeled-response` batch holding every reply.
+    Batch(Batch)
+}
+
+/// Waits for the response to a command sent with `request`.
+///
+/// Responses are delivered as the client reads messages, so `wait` only makes sense when the client is being read from
+/// another task (see `BasicClient::split`). A single-task client can read as usual and check `try_get` instead.
+#[experimental]
+pub struct ResponseHandle {
+    label: String,
+    receiver: Receiver<LabeledResponse>
+}
+
+#[experimental]
+impl ResponseHandle {
+    #[experimental]
+    #[inline]
+    pub fn label<'a>(&'a self) -> &'a str {
+        self.label.as_slice()
+    }
+
+    /// The response, if it has arrived.
+    #[experimental]
+    pub fn try_get(&self) -> Option<LabeledResponse> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Blocks until the response arrives or `timeout_ms` milliseconds have passed. Fails with `NotConnected` if the
+    /// connection is reset first.
+    #[experimental]
+    pub fn wait(&self, timeout_ms: Option<u64>) -> IoResult<LabeledResponse> {
+        let deadline = timeout_ms.map(|timeout| now_ms() + timeout);
+
+        loop {
+            match self.receiver.try_recv() {
+                Ok(response) => return Ok(response),
+                Err(TryRecvError::Empty) => {},
+                Err(TryRecvError::Disconnected) => {
+                    return Err(IoError {
+                        kind: IoErrorKind::NotConnected,
+                        desc: "Connection reset before the response arrived",
+                        detail: Some(self.label.clone())
+                    });
+                }
+            }
+
+            match deadline {
+                Some(deadline) if now_ms() >= deadline => {
+                    return Err(IoError {
+                        kind: IoErrorKind::TimedOut,
+                        desc: "No response to labeled command",
+                        detail: Some(self.label.clone())
+                    });
+                },
+                _ => sleep(Duration::milliseconds(10))
+            }
+        }
+    }
+}
+
+/// The labels of commands still waiting for a response.
+pub struct PendingLabels {
+    next: uint,
+    pending: HashMap<String, Sender<LabeledResponse>>
+}
+
+impl PendingLabels {
+    pub fn new() -> PendingLabels {
+        PendingLabels {
+            next: 0,
+            pending: HashMap::new()
+        }
+    }
+
+    pub fn register(&mut self) -> ResponseHandle {
+        self.next += 1;
+        let label = format!("relay{}", self.next);
+        let (tx, rx) = channel();
+        self.pending.insert(label.clone(), tx);
+
+        ResponseHandle {
+            label: label,
+            receiver: rx
+        }
+    }
+
+    /// Hands a response to whoever is waiting for `label`, returning false if nobody was.
+    pub fn respond(&mut self, label: &str, response: LabeledResponse) -> bool {
+        match self.pending.remove(&String::from_str(label)) {
+            Some(tx) => tx.send_opt(response).is_ok(),
+            None => false
+        }
+    }
+
+    /// Gives up on every pending response, so that their handles fail rather than wait forever.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Adds a `label` tag to a raw line, alongside any tags it already has.
+pub fn add_label(line: &str, label: &str) -> String {
+    if line.starts_with("@") {
+        format!("@label={};{}", label, line.slice_from(1))
+    } else {
+        format!("@label={} {}", label, line)
+    }
+}
+
+fn now_ms() -> u64 {
+    precise_time_ns() / 1_000_000
+}
+
+#[test]
+fn labels() {
+    assert!(add_label("WHOIS alice", "relay1").as_slice() == "@label=relay1 WHOIS alice");
+    assert!(add_label("@+draft/reply=abc PRIVMSG #chan :hi", "relay2").as_slice() == "@label=relay2;+draft/reply=abc PRIVMSG #chan :hi");
+
+    let mut pending = PendingLabels::new();
+    let first = pending.register();
+    let second = pending.register();
+    assert!(first.label() != second.label());
+
+    assert!(pending.respond(second.label(), LabeledResponse::Ack));
+    assert!(!pending.respond(second.label(), LabeledResponse::Ack));
+    match second.wait(Some(0)) {
+        Ok(LabeledResponse::Ack) => {},
+        _ => panic!("expected an ACK")
+    }
+
+    assert!(first.try_get().is_none());
+    assert!(first.wait(Some(20)).unwrap_err().kind == IoErrorKind::TimedOut);
+    pending.clear();
+    assert!(first.wait(None).unwrap_err().kind == IoErrorKind::NotConnected);
+}
@@ -1,23 +1,65 @@
-#![feature(default_type_params)]
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
+#![feature(default_type_params, unboxed_closures)]
 
-pub use basicclient::{BasicClient, ConnectionStatus};
+extern crate time;
+
+pub use asyncclient::{AsyncClient, MessageSink, MessageStream};
+pub use basicclient::{BasicClient, ClientEvent, ClientReader, ClientSender, ConnectionStatus};
+pub use batch::{Batch, BatchTracker};
 pub use capability::{Capability, CapabilityModifier};
-pub use connection::Connection;
+pub use channel::{Channel, ChannelState, Member, Topic};
+pub use connection::{Connection, IrcStream};
+pub use ctcp::{Ctcp, CtcpResponder, low_level_dequote, low_level_quote};
+pub use dcc::{Dcc, DccChat, DccListener, DccOffer, DccProgress, DccReceive, DccResume, DccSend};
+pub use echo::{EchoTracker, outgoing_messages};
+pub use flood::{FloodControl, SendQueue};
+pub use formatting::{Color, Span, Style, bold, color, hex_color, italic, monospace, parse_formatting, spans_to_ansi, spans_to_html, strikethrough, strip_formatting, underline};
+pub use handler::{Callback, Dispatcher, Handler, HandlerId, Propagation};
+pub use history::{History, HistorySelector, HistoryTracker, history_targets};
 pub use irc_methods::IrcMethods;
+pub use isupport::{CaseMapping, ISupport};
+pub use keepalive::{Keepalive, KeepaliveAction};
+pub use labeled::{LabeledResponse, ResponseHandle};
+pub use mask::{BanMaskStyle, ban_mask, glob_match, normalise_mask};
 pub use message::{Message, MessageErr};
+pub use metadata::{MetadataCache, MetadataErr, MetadataEvent};
+pub use mode::{ModeBuilder, ModeChange};
+pub use monitor::{Monitor, MonitorEvent, monitor_lines};
+pub use multiline::{MultilineLimits, plan_batches, reassemble};
 pub use parser::Parser;
 pub use prefix::{ClientPrefix, Prefix};
+pub use splitting::{split_text, text_budget};
 pub use tag::{Tag, TagErr};
+pub use user::User;
 
+mod asyncclient;
 mod basicclient;
+mod batch;
 mod capability;
+mod channel;
 mod connection;
+mod ctcp;
+mod dcc;
+mod echo;
+mod flood;
+mod formatting;
+mod handler;
+mod history;
 mod irc_methods;
+mod isupport;
+mod keepalive;
+mod labeled;
+mod mask;
 mod message;
+mod metadata;
+mod mode;
+mod monitor;
+mod multiline;
 mod parser;
+#[cfg(test)]
+mod parser_tests;
+#[cfg(test)]
+mod pipe;
 mod prefix;
+mod splitting;
 mod tag;
+mod user;
@@ -0,0 +1,179 @@
+use isupport::CaseMapping;
+
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum BanMaskStyle {
+    /// `nick!*@*`
+    Nick,
+    /// `*!*@host`
+    Host,
+    /// `*!*user@host`, ignoring any `~` on the user name
+    UserHost,
+    /// `*!*@*.example.com`, or `*!*@192.0.2.*` for IPv4 addresses
+    Domain,
+    /// `*!*user@*.example.com`
+    UserDomain,
+    /// `nick!*user@host`
+    Full
+}
+
+/// Matches `input` against an IRC glob `mask`, where `*` matches any run of characters, `?` matches any one character and `\` escapes the next character.
+#[experimental]
+pub fn glob_match(case_mapping: CaseMapping, mask: &str, input: &str) -> bool {
+    let mask: Vec<(char, bool)> = tokenise(mask).into_iter().map(|(c, escaped)| (case_mapping.casefold_char(c), escaped)).collect();
+    let input: Vec<char> = input.chars().map(|c| case_mapping.casefold_char(c)).collect();
+
+    let (mut m, mut i) = (0u, 0u);
+    // The position of the last unescaped `*` in the mask, and where in the input it started matching
+    let mut backtrack: Option<(uint, uint)> = None;
+
+    while i < input.len() {
+        if m < mask.len() {
+            match mask[m] {
+                ('*', false) => {
+                    backtrack = Some((m, i));
+                    m += 1;
+                    continue;
+                },
+                ('?', false) => {
+                    m += 1;
+                    i += 1;
+                    continue;
+                },
+                (c, _) if c == input[i] => {
+                    m += 1;
+                    i += 1;
+                    continue;
+                },
+                _ => {}
+            }
+        }
+
+        match backtrack {
+            Some((star, start)) => {
+                m = star + 1;
+                i = start + 1;
+                backtrack = Some((star, start + 1));
+            },
+            None => return false
+        }
+    }
+
+    mask.slice_from(m).iter().all(|&(c, escaped)| c == '*' && !escaped)
+}
+
+fn tokenise(mask: &str) -> Vec<(char, bool)> {
+    let mut tokens = Vec::new();
+    let mut chars = mask.chars();
+
+    loop {
+        match chars.next() {
+            Some('\\') => {
+                match chars.next() {
+                    Some(c) => tokens.push((c, true)),
+                    None => tokens.push(('\\', false))
+                }
+            },
+            Some(c) => tokens.push((c, false)),
+            None => break
+        }
+    }
+
+    tokens
+}
+
+/// Fills in the missing parts of a mask with `*`, so `nick` becomes `nick!*@*`, `user@host` becomes `*!user@host` and `host.example.com` becomes `*!*@host.example.com`.
+#[experimental]
+pub fn normalise_mask(mask: &str) -> String {
+    let (nick, user, host) = match (mask.find('!'), mask.find('@')) {
+        (Some(bang), Some(at)) if bang < at => {
+            (mask.slice_to(bang), mask.slice(bang + 1, at), mask.slice_from(at + 1))
+        },
+        (Some(bang), None) => {
+            (mask.slice_to(bang), mask.slice_from(bang + 1), "")
+        },
+        (None, Some(at)) | (Some(_), Some(at)) => {
+            ("", mask.slice_to(at), mask.slice_from(at + 1))
+        },
+        (None, None) => {
+            if mask.contains_char('.') || mask.contains_char(':') {
+                ("", "", mask)
+            } else {
+                (mask, "", "")
+            }
+        }
+    };
+
+    format!("{}!{}@{}", or_star(nick), or_star(user), or_star(host))
+}
+
+#[inline]
+fn or_star<'a>(part: &'a str) -> &'a str {
+    if part == "" { "*" } else { part }
+}
+
+/// Generates a ban mask in the given style from the parts of a hostmask.
+#[experimental]
+pub fn ban_mask(style: BanMaskStyle, nick: &str, user: &str, host: &str) -> String {
+    let user = if user.starts_with("~") { user.slice_from(1) } else { user };
+    let user = or_star(user);
+
+    match style {
+        BanMaskStyle::Nick => format!("{}!*@*", nick),
+        BanMaskStyle::Host => format!("*!*@{}", or_star(host)),
+        BanMaskStyle::UserHost => format!("*!*{}@{}", user, or_star(host)),
+        BanMaskStyle::Domain => format!("*!*@{}", domain(host)),
+        BanMaskStyle::UserDomain => format!("*!*{}@{}", user, domain(host)),
+        BanMaskStyle::Full => format!("{}!*{}@{}", nick, user, or_star(host))
+    }
+}
+
+/// Wildcards the most specific part of a host: the last octet of an IPv4 address or the first label of a hostname.
+fn domain(host: &str) -> String {
+    let is_ipv4 = host.split('.').count() == 4 && host.split('.').all(|octet| octet.len() > 0 && octet.chars().all(|c| c >= '0' && c <= '9'));
+
+    if is_ipv4 {
+        format!("{}.*", host.slice_to(host.rfind('.').unwrap()))
+    } else if host.contains_char(':') || host.split('.').count() < 3 {
+        // IPv6 addresses, cloaks and short domains are used as-is
+        String::from_str(or_star(host))
+    } else {
+        format!("*{}", host.slice_from(host.find('.').unwrap()))
+    }
+}
+
+#[test]
+fn glob() {
+    assert!(glob_match(CaseMapping::Rfc1459, "*!*@*.example.com", "nick!user@host.example.com"));
+    assert!(!glob_match(CaseMapping::Rfc1459, "*!*@*.example.com", "nick!user@example.com"));
+    assert!(glob_match(CaseMapping::Rfc1459, "n?ck!*@*", "nick!user@host"));
+    assert!(glob_match(CaseMapping::Rfc1459, "NICK[1]!*@*", "nick{1}!user@host"));
+    assert!(!glob_match(CaseMapping::Ascii, "NICK[1]!*@*", "nick{1}!user@host"));
+    assert!(glob_match(CaseMapping::Rfc1459, "a*b*c", "aXXbYYbc"));
+    assert!(glob_match(CaseMapping::Rfc1459, "what\\?", "what?"));
+    assert!(!glob_match(CaseMapping::Rfc1459, "what\\?", "whatt"));
+    assert!(glob_match(CaseMapping::Rfc1459, "star\\*", "star*"));
+    assert!(!glob_match(CaseMapping::Rfc1459, "star\\*", "starry"));
+    assert!(glob_match(CaseMapping::Rfc1459, "***", ""));
+}
+
+#[test]
+fn normalise_masks() {
+    assert!(normalise_mask("nick").as_slice() == "nick!*@*");
+    assert!(normalise_mask("user@host").as_slice() == "*!user@host");
+    assert!(normalise_mask("nick!user").as_slice() == "nick!user@*");
+    assert!(normalise_mask("host.example.com").as_slice() == "*!*@host.example.com");
+    assert!(normalise_mask("!@").as_slice() == "*!*@*");
+    assert!(normalise_mask("nick!user@host").as_slice() == "nick!user@host");
+}
+
+#[test]
+fn ban_masks() {
+    assert!(ban_mask(BanMaskStyle::Nick, "nick", "~user", "a.b.example.com").as_slice() == "nick!*@*");
+    assert!(ban_mask(BanMaskStyle::Host, "nick", "~user", "a.b.example.com").as_slice() == "*!*@a.b.example.com");
+    assert!(ban_mask(BanMaskStyle::UserHost, "nick", "~user", "a.b.example.com").as_slice() == "*!*user@a.b.example.com");
+    assert!(ban_mask(BanMaskStyle::Domain, "nick", "~user", "a.b.example.com").as_slice() == "*!*@*.b.example.com");
+    assert!(ban_mask(BanMaskStyle::Domain, "nick", "user", "192.0.2.1").as_slice() == "*!*@192.0.2.*");
+    assert!(ban_mask(BanMaskStyle::UserDomain, "nick", "user", "2001:db8::1").as_slice() == "*!*user@2001:db8::1");
+    assert!(ban_mask(BanMaskStyle::Full, "nick", "user", "host").as_slice() == "nick!*user@host");
+}
@@ -4,6 +4,8 @@ use std::str::{MaybeOwned, Owned};
 #[cfg(test)]
 use std::str::Slice;
 
+use time::Timespec;
+
 use prefix::Prefix;
 use tag::Tag;
 
@@ -27,7 +29,8 @@ pub enum MessageErr {
 impl<'_> Message<'_> {
     #[stable]
     pub fn from_str(mut msg: &str) -> Result<Message<'static>, MessageErr> {
-        msg = msg.trim();
+        // Spaces at the end may be part of the trailing parameter
+        msg = msg.trim_left_chars(' ').trim_right_chars(['\r', '\n'].as_slice());
 
         if msg == "" {
             return Err(EmptyInput);
@@ -149,6 +152,135 @@ impl<'_> Message<'_> {
     pub fn params_mut<'a>(&'a mut self) -> &'a mut Option<Vec<MaybeOwned<'_>>> {
         &mut self.params
     }
+
+    /// Finds a tag by name. If the name appears more than once, the last one wins.
+    #[experimental]
+    pub fn tag<'a>(&'a self, name: &str) -> Option<&'a Tag<'_>> {
+        match self.tags {
+            Some(ref tags) => tags.iter().rev().find(|tag| tag.name().as_slice() == name),
+            None => None
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn has_tag(&self, name: &str) -> bool {
+        self.tag(name).is_some()
+    }
+
+    /// The value of a tag, or `None` if the tag is missing or has no value.
+    #[experimental]
+    pub fn tag_value<'a>(&'a self, name: &str) -> Option<&'a str> {
+        self.tag(name).and_then(|tag| tag.value().as_ref()).map(|value| value.as_slice())
+    }
+
+    /// When the server says the message was sent, from the `server-time` capability's `time` tag.
+    #[experimental]
+    pub fn server_time(&self) -> Option<Timespec> {
+        self.tag_value("time").and_then(parse_server_time)
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn msgid<'a>(&'a self) -> Option<&'a str> {
+        self.tag_value("msgid")
+    }
+
+    /// The services account of the sender, from `account-tag`.
+    #[experimental]
+    #[inline]
+    pub fn account<'a>(&'a self) -> Option<&'a str> {
+        self.tag_value("account")
+    }
+
+    /// The reference of the batch this message is part of.
+    #[experimental]
+    #[inline]
+    pub fn batch<'a>(&'a self) -> Option<&'a str> {
+        self.tag_value("batch")
+    }
+
+    /// The label of the command this message is a response to, from `labeled-response`.
+    #[experimental]
+    #[inline]
+    pub fn label<'a>(&'a self) -> Option<&'a str> {
+        self.tag_value("label")
+    }
+
+    /// Whether the sender has marked itself as a bot.
+    #[experimental]
+    #[inline]
+    pub fn is_bot(&self) -> bool {
+        self.has_tag("bot") || self.has_tag("draft/bot")
+    }
+}
+
+/// Parses a `server-time` timestamp such as `2011-10-19T16:40:51.620Z`.
+#[experimental]
+pub fn parse_server_time(time: &str) -> Option<Timespec> {
+    let bytes = time.as_bytes();
+    if bytes.len() < 20 || !bytes.iter().all(|&b| b < 128) || !time.ends_with("Z")
+        || bytes[4] != b'-' || bytes[7] != b'-' || bytes[10] != b'T' || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+
+    let field = |start: uint, end: uint| from_str::<i64>(time.slice(start, end));
+    let (year, month, day, hour, minute, second) = match (field(0, 4), field(5, 7), field(8, 10), field(11, 13), field(14, 16), field(17, 19)) {
+        (Some(year), Some(month), Some(day), Some(hour), Some(minute), Some(second))
+            if month >= 1 && month <= 12 && day >= 1 && day <= 31 && hour < 24 && minute < 60 && second <= 60 => {
+            (year, month, day, hour, minute, second)
+        },
+        _ => return None
+    };
+
+    // Up to nanosecond precision after the decimal point
+    let fraction = time.slice(19, time.len() - 1);
+    let nanos = if fraction.is_empty() {
+        0
+    } else if fraction.starts_with(".") && fraction.len() > 1 && fraction.len() <= 10 && fraction.slice_from(1).chars().all(|c| c >= '0' && c <= '9') {
+        let digits = fraction.slice_from(1);
+        from_str::<i32>(digits).unwrap() * range(digits.len(), 9).fold(1, |scale, _| scale * 10)
+    } else {
+        return None;
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(Timespec::new(seconds, nanos))
+}
+
+/// The number of days between 1970-01-01 and the given date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Formats a time as a `server-time` timestamp, to the millisecond.
+#[experimental]
+pub fn format_server_time(time: Timespec) -> String {
+    let days = (if time.sec >= 0 { time.sec } else { time.sec - 86399 }) / 86400;
+    let seconds = time.sec - days * 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60, time.nsec / 1000000)
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+
+    (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
 }
 
 #[stable]
@@ -167,23 +299,15 @@ impl<'a> fmt::Show for Message<'a> {
 
         if self.params.is_some() {
             let params = self.params.as_ref().unwrap();
-            let p: Vec<String> = params.iter().take_while(|p| !p.as_slice().contains_char(' ')).map(|p| p.to_string()).collect();
-            let out = p.connect(" ");
-
-            try!(if params.iter().len() > p.iter().len() {
-                let trailing: Vec<String> = params.iter().skip(p.len()).map(|p| p.to_string()).collect();
-                if out.len() > 0 {
-                    write!(f, " {} :{}", out, trailing.connect(" "))
+            for (i, param) in params.iter().enumerate() {
+                let param = param.as_slice();
+                // Only the last parameter may be empty, contain spaces or start with a colon
+                if i + 1 == params.len() && (param == "" || param.contains_char(' ') || param.starts_with(":")) {
+                    try!(write!(f, " :{}", param));
                 } else {
-                    write!(f, " :{}", trailing.connect(" "))
+                    try!(write!(f, " {}", param));
                 }
-            } else {
-                if params.iter().rev().next().unwrap().as_slice() == "" {
-                    write!(f, " {}:", out)
-                } else {
-                    write!(f, " {}", out)
-                }
-            });
+            }
         }
 
         write!(f, "\r\n")
@@ -411,3 +535,30 @@ fn all_parts() {
         }
     }
 }
+
+#[test]
+fn tag_lookup() {
+    let msg = Message::from_str("@time=2011-10-19T16:40:51.620Z;msgid=abc;+example.com/foo=1;bot;msgid=def :nick!user@host PRIVMSG #chan :hi").unwrap();
+    assert!(msg.has_tag("bot") && msg.is_bot());
+    assert!(!msg.has_tag("label") && msg.label().is_none());
+    assert!(msg.msgid() == Some("def"));
+    assert!(msg.server_time() == Some(Timespec::new(1319042451, 620000000)));
+
+    let tag = msg.tag("+example.com/foo").unwrap();
+    assert!(tag.is_client_only());
+    assert!(tag.vendor() == Some("example.com"));
+    assert!(tag.key() == "foo");
+    assert!(msg.tag("msgid").unwrap().vendor().is_none());
+}
+
+#[test]
+fn server_times() {
+    assert!(parse_server_time("1970-01-01T00:00:00Z") == Some(Timespec::new(0, 0)));
+    assert!(parse_server_time("2000-02-29T12:00:00.5Z") == Some(Timespec::new(951825600, 500000000)));
+    assert!(parse_server_time("2000-13-01T00:00:00.000Z").is_none());
+    assert!(parse_server_time("yesterday").is_none());
+
+    for time in ["1970-01-01T00:00:00.000Z", "2000-02-29T12:00:00.500Z", "2011-10-19T16:40:51.620Z", "1969-12-31T23:59:59.000Z"].iter() {
+        assert!(format_server_time(parse_server_time(*time).unwrap()).as_slice() == *time);
+    }
+}
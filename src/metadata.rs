@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use isupport::CaseMapping;
+use message::Message;
+
+/// Why the server refused a `METADATA` command, from a `FAIL METADATA` reply or one of the older error numerics.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum MetadataErr {
+    /// The key name isn't valid.
+    KeyInvalid(String),
+    /// The target and key we aren't allowed to see or change.
+    KeyNoPermission(String, String),
+    /// The target and key that has no value to clear.
+    KeyNotSet(String, String),
+    /// The target already has as many keys as the server allows.
+    LimitReached(String),
+    TargetInvalid(String),
+    ValueInvalid,
+    /// We are sending too many commands; the server may say how many seconds to wait.
+    RateLimited(Option<uint>),
+    /// The key we couldn't subscribe to because we are subscribed to too many already.
+    TooManySubs(String),
+    /// Any other failure, with the server's code.
+    Other(String)
+}
+
+/// Something learned from a `METADATA` reply or notification.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum MetadataEvent {
+    /// A target's key was set, or cleared if there is no value.
+    Changed(String, String, Option<String>),
+    /// The end of a `METADATA LIST` or `GET` reply.
+    End,
+    /// The server can't send a target's metadata yet, and asks us to `SYNC` it again after this many seconds.
+    SyncLater(String, Option<uint>),
+    Failed(MetadataErr)
+}
+
+/// The metadata of users and channels, kept up to date from the server's replies and `METADATA` notifications, and the keys
+/// we are subscribed to.
+#[experimental]
+pub struct MetadataCache {
+    targets: HashMap<String, HashMap<String, String>>,
+    subscriptions: HashSet<String>,
+    resubscribe: Vec<String>
+}
+
+#[experimental]
+impl MetadataCache {
+    #[experimental]
+    pub fn new() -> MetadataCache {
+        MetadataCache {
+            targets: HashMap::new(),
+            subscriptions: HashSet::new(),
+            resubscribe: Vec::new()
+        }
+    }
+
+    #[experimental]
+    pub fn get<'a>(&'a self, target: &str, key: &str, case_mapping: CaseMapping) -> Option<&'a str> {
+        self.targets.get(&case_mapping.casefold(target))
+            .and_then(|keys| keys.get(&String::from_str(key)))
+            .map(|value| value.as_slice())
+    }
+
+    /// Every key known for a target, with its value.
+    #[experimental]
+    pub fn keys<'a>(&'a self, target: &str, case_mapping: CaseMapping) -> Vec<(&'a str, &'a str)> {
+        match self.targets.get(&case_mapping.casefold(target)) {
+            Some(keys) => keys.iter().map(|(key, value)| (key.as_slice(), value.as_slice())).collect(),
+            None => Vec::new()
+        }
+    }
+
+    #[experimental]
+    pub fn forget(&mut self, target: &str, case_mapping: CaseMapping) {
+        self.targets.remove(&case_mapping.casefold(target));
+    }
+
+    /// The keys the server has confirmed we are subscribed to.
+    #[experimental]
+    pub fn subscriptions<'a>(&'a self) -> Vec<&'a str> {
+        self.subscriptions.iter().map(|key| key.as_slice()).collect()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_subscribed(&self, key: &str) -> bool {
+        self.subscriptions.contains(&String::from_str(key))
+    }
+
+    /// Forgets every value, and remembers to subscribe to the same keys again once the new connection is registered.
+    #[experimental]
+    pub fn reconnected(&mut self) {
+        self.targets.clear();
+        let subscriptions = mem::replace(&mut self.subscriptions, HashSet::new());
+        self.resubscribe.extend(subscriptions.into_iter());
+    }
+
+    /// The keys to subscribe to again after reconnecting, if that hasn't been done yet.
+    #[experimental]
+    pub fn take_resubscribe(&mut self) -> Vec<String> {
+        mem::replace(&mut self.resubscribe, Vec::new())
+    }
+
+    /// Updates the cache from a `METADATA` notification, a metadata reply or error, or a `NICK` or `QUIT`, returning what
+    /// the caller should be told.
+    #[experimental]
+    pub fn update(&mut self, msg: &Message, case_mapping: CaseMapping) -> Option<MetadataEvent> {
+        let empty = Vec::new();
+        let params: Vec<&str> = msg.params().as_ref().unwrap_or(&empty).iter().map(|p| p.as_slice()).collect();
+        let source = msg.prefix().as_ref().and_then(|prefix| prefix.nick());
+
+        match (msg.command().as_slice(), params.len()) {
+            ("METADATA", n) if n > 2 => Some(self.set(params[0], params[1], if n > 3 { Some(params[3]) } else { None }, case_mapping)),
+            // RPL_KEYVALUE
+            ("761", n) if n > 4 => Some(self.set(params[1], params[2], Some(params[4]), case_mapping)),
+            // RPL_METADATAEND
+            ("762", _) => Some(MetadataEvent::End),
+            // RPL_KEYNOTSET: nothing to cache, but anything we had is out of date
+            ("766", n) if n > 2 => Some(self.set(params[1], params[2], None, case_mapping)),
+            // RPL_METADATASUBOK and RPL_METADATASUBS
+            ("770", _) | ("772", _) => {
+                for key in keys_from(params.as_slice()).into_iter() {
+                    self.subscriptions.insert(key);
+                }
+                None
+            },
+            // RPL_METADATAUNSUBOK
+            ("771", _) => {
+                for key in keys_from(params.as_slice()).iter() {
+                    self.subscriptions.remove(key);
+                }
+                None
+            },
+            // RPL_METADATASYNCLATER
+            ("774", n) if n > 1 => {
+                let retry = if n > 2 { from_str::<uint>(params[2]) } else { None };
+                Some(MetadataEvent::SyncLater(String::from_str(params[1]), retry))
+            },
+            ("764", n) if n > 1 => Some(MetadataEvent::Failed(MetadataErr::LimitReached(String::from_str(params[1])))),
+            ("765", n) if n > 1 => Some(MetadataEvent::Failed(MetadataErr::TargetInvalid(String::from_str(params[1])))),
+            ("767", n) if n > 1 => Some(MetadataEvent::Failed(MetadataErr::KeyInvalid(String::from_str(params[1])))),
+            ("768", n) if n > 2 => Some(MetadataEvent::Failed(MetadataErr::KeyNotSet(String::from_str(params[1]), String::from_str(params[2])))),
+            ("769", n) if n > 2 => Some(MetadataEvent::Failed(MetadataErr::KeyNoPermission(String::from_str(params[1]), String::from_str(params[2])))),
+            ("FAIL", n) if n > 2 && params[0] == "METADATA" => Some(MetadataEvent::Failed(self.failure(params.slice(1, n - 1)))),
+            ("NICK", n) if n > 0 && source.is_some() => {
+                let old = case_mapping.casefold(source.unwrap());
+                match self.targets.remove(&old) {
+                    Some(keys) => { self.targets.insert(case_mapping.casefold(params[0]), keys); },
+                    None => {}
+                }
+                None
+            },
+            ("QUIT", _) if source.is_some() => {
+                self.forget(source.unwrap(), case_mapping);
+                None
+            },
+            _ => None
+        }
+    }
+
+    fn set(&mut self, target: &str, key: &str, value: Option<&str>, case_mapping: CaseMapping) -> MetadataEvent {
+        let folded = case_mapping.casefold(target);
+        match value {
+            Some(value) => {
+                if !self.targets.contains_key(&folded) {
+                    self.targets.insert(folded.clone(), HashMap::new());
+                }
+                self.targets.get_mut(&folded).unwrap().insert(String::from_str(key), String::from_str(value));
+            },
+            None => {
+                let now_empty = match self.targets.get_mut(&folded) {
+                    Some(keys) => {
+                        keys.remove(&String::from_str(key));
+                        keys.is_empty()
+                    },
+                    None => false
+                };
+                if now_empty {
+                    self.targets.remove(&folded);
+                }
+            }
+        }
+
+        MetadataEvent::Changed(String::from_str(target), String::from_str(key), value.map(|value| String::from_str(value)))
+    }
+
+    /// Reads the code and context of a `FAIL METADATA` reply, without its description.
+    fn failure(&mut self, params: &[&str]) -> MetadataErr {
+        let arg = |i: uint| if params.len() > i { String::from_str(params[i]) } else { String::new() };
+
+        match params[0] {
+            "KEY_INVALID" => MetadataErr::KeyInvalid(arg(1)),
+            "KEY_NO_PERMISSION" => MetadataErr::KeyNoPermission(arg(1), arg(2)),
+            "KEY_NOT_SET" => MetadataErr::KeyNotSet(arg(1), arg(2)),
+            "LIMIT_REACHED" => MetadataErr::LimitReached(arg(1)),
+            "INVALID_TARGET" => MetadataErr::TargetInvalid(arg(1)),
+            "VALUE_INVALID" => MetadataErr::ValueInvalid,
+            "RATE_LIMITED" => MetadataErr::RateLimited(params.iter().skip(1).filter_map(|p| from_str::<uint>(*p)).last()),
+            "TOO_MANY_SUBS" => {
+                let key = arg(1);
+                self.subscriptions.remove(&key);
+                MetadataErr::TooManySubs(key)
+            },
+            code => MetadataErr::Other(String::from_str(code))
+        }
+    }
+}
+
+/// The keys listed in a subscription reply, which servers may send as one parameter or several.
+fn keys_from(params: &[&str]) -> Vec<String> {
+    params.iter().skip(1)
+        .flat_map(|p| p.split(' '))
+        .filter(|key| !key.is_empty())
+        .map(|key| String::from_str(key))
+        .collect()
+}
+
+#[test]
+fn cache_updates() {
+    let mut cache = MetadataCache::new();
+    let cm = CaseMapping::Rfc1459;
+
+    let event = cache.update(&Message::from_str(":irc 761 relay Alice avatar * :https://example.com/a.png").unwrap(), cm.clone());
+    assert!(event == Some(MetadataEvent::Changed(String::from_str("Alice"), String::from_str("avatar"), Some(String::from_str("https://example.com/a.png")))));
+    cache.update(&Message::from_str(":irc METADATA alice url * :https://example.com").unwrap(), cm.clone());
+    assert!(cache.get("ALICE", "avatar", cm.clone()) == Some("https://example.com/a.png"));
+    assert!(cache.keys("alice", cm.clone()).len() == 2);
+
+    cache.update(&Message::from_str(":irc METADATA alice url *").unwrap(), cm.clone());
+    assert!(cache.get("alice", "url", cm.clone()).is_none());
+
+    cache.update(&Message::from_str(":alice!a@host NICK alicia").unwrap(), cm.clone());
+    assert!(cache.get("alicia", "avatar", cm.clone()).is_some());
+    cache.update(&Message::from_str(":alicia!a@host QUIT :bye").unwrap(), cm.clone());
+    assert!(cache.keys("alicia", cm.clone()).is_empty());
+}
+
+#[test]
+fn subscriptions_and_errors() {
+    let mut cache = MetadataCache::new();
+    let cm = CaseMapping::Rfc1459;
+
+    cache.update(&Message::from_str(":irc 770 relay :avatar url").unwrap(), cm.clone());
+    cache.update(&Message::from_str(":irc 771 relay url").unwrap(), cm.clone());
+    assert!(cache.subscriptions() == vec!["avatar"]);
+
+    let event = cache.update(&Message::from_str(":irc FAIL METADATA KEY_NO_PERMISSION #chan secret :permission denied").unwrap(), cm.clone());
+    assert!(event == Some(MetadataEvent::Failed(MetadataErr::KeyNoPermission(String::from_str("#chan"), String::from_str("secret")))));
+    let event = cache.update(&Message::from_str(":irc FAIL METADATA RATE_LIMITED alice avatar 5 :slow down").unwrap(), cm.clone());
+    assert!(event == Some(MetadataEvent::Failed(MetadataErr::RateLimited(Some(5)))));
+    let event = cache.update(&Message::from_str(":irc 767 relay bad~key :invalid key").unwrap(), cm.clone());
+    assert!(event == Some(MetadataEvent::Failed(MetadataErr::KeyInvalid(String::from_str("bad~key")))));
+
+    cache.reconnected();
+    assert!(cache.subscriptions().is_empty());
+    assert!(cache.take_resubscribe() == vec![String::from_str("avatar")]);
+    assert!(cache.take_resubscribe().is_empty());
+}
@@ -0,0 +1,206 @@
+use isupport::ISupport;
+#[cfg(test)]
+use message::Message;
+
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub arg: Option<String>
+}
+
+#[experimental]
+impl ModeChange {
+    #[experimental]
+    pub fn new(adding: bool, mode: char, arg: Option<&str>) -> ModeChange {
+        ModeChange {
+            adding: adding,
+            mode: mode,
+            arg: arg.map(|a| String::from_str(a))
+        }
+    }
+
+    /// Parses a channel mode string and its arguments, using the server's `CHANMODES` and `PREFIX` to decide which modes take an argument.
+    #[experimental]
+    pub fn parse(isupport: &ISupport, modes: &str, args: &[&str]) -> Vec<ModeChange> {
+        let mut args = args.iter();
+        let mut adding = true;
+        let mut changes = Vec::new();
+
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => {
+                    let arg = if takes_arg(isupport, mode, adding) {
+                        args.next().map(|a| String::from_str(*a))
+                    } else {
+                        None
+                    };
+                    changes.push(ModeChange {
+                        adding: adding,
+                        mode: mode,
+                        arg: arg
+                    });
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Parses a user mode string, none of which take an argument.
+    #[experimental]
+    pub fn parse_user(modes: &str) -> Vec<ModeChange> {
+        let mut adding = true;
+        let mut changes = Vec::new();
+
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => changes.push(ModeChange::new(adding, mode, None))
+            }
+        }
+
+        changes
+    }
+}
+
+/// Whether a channel mode takes an argument when being set (`adding`) or unset.
+#[experimental]
+pub fn takes_arg(isupport: &ISupport, mode: char, adding: bool) -> bool {
+    let chan_modes = isupport.chan_modes();
+    isupport.prefix_symbol(mode).is_some()
+        || chan_modes[0].as_slice().contains_char(mode)
+        || chan_modes[1].as_slice().contains_char(mode)
+        || (adding && chan_modes[2].as_slice().contains_char(mode))
+}
+
+/// Packs mode changes into as few `MODE` parameter lists as the server's `MODES` limit allows.
+#[experimental]
+pub struct ModeBuilder {
+    changes: Vec<ModeChange>,
+    max_modes: Option<uint>
+}
+
+#[experimental]
+impl ModeBuilder {
+    #[experimental]
+    pub fn new(isupport: &ISupport) -> ModeBuilder {
+        ModeBuilder {
+            changes: Vec::new(),
+            max_modes: isupport.modes()
+        }
+    }
+
+    #[experimental]
+    pub fn with_limit(max_modes: Option<uint>) -> ModeBuilder {
+        ModeBuilder {
+            changes: Vec::new(),
+            max_modes: max_modes
+        }
+    }
+
+    #[experimental]
+    pub fn add<'a>(&'a mut self, mode: char, arg: Option<&str>) -> &'a mut ModeBuilder {
+        self.changes.push(ModeChange::new(true, mode, arg));
+        self
+    }
+
+    #[experimental]
+    pub fn remove<'a>(&'a mut self, mode: char, arg: Option<&str>) -> &'a mut ModeBuilder {
+        self.changes.push(ModeChange::new(false, mode, arg));
+        self
+    }
+
+    #[experimental]
+    pub fn push<'a>(&'a mut self, change: ModeChange) -> &'a mut ModeBuilder {
+        self.changes.push(change);
+        self
+    }
+
+    /// Builds the parameter lists, e.g. `+ov-b nick1 nick2 *!*@bad`. Only modes with an argument count towards the `MODES` limit.
+    #[experimental]
+    pub fn build(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut modes = String::new();
+        let mut args: Vec<&str> = Vec::new();
+        let mut sign: Option<bool> = None;
+
+        for change in self.changes.iter() {
+            if change.arg.is_some() && self.max_modes.map_or(false, |max| args.len() >= max) {
+                lines.push(join_modes(&modes, &args));
+                modes = String::new();
+                args.clear();
+                sign = None;
+            }
+
+            if sign != Some(change.adding) {
+                modes.push(if change.adding { '+' } else { '-' });
+                sign = Some(change.adding);
+            }
+            modes.push(change.mode);
+
+            match change.arg {
+                Some(ref arg) => args.push(arg.as_slice()),
+                None => {}
+            }
+        }
+
+        if modes.len() > 0 {
+            lines.push(join_modes(&modes, &args));
+        }
+
+        lines
+    }
+}
+
+fn join_modes(modes: &String, args: &Vec<&str>) -> String {
+    if args.is_empty() {
+        modes.clone()
+    } else {
+        format!("{} {}", modes, args.connect(" "))
+    }
+}
+
+#[test]
+fn parse_channel_modes() {
+    let isupport = ISupport::new();
+    let changes = ModeChange::parse(&isupport, "+ov-b+lk-l", &["nick1", "nick2", "*!*@bad", "10", "key"]);
+    assert!(changes == vec![
+        ModeChange::new(true, 'o', Some("nick1")),
+        ModeChange::new(true, 'v', Some("nick2")),
+        ModeChange::new(false, 'b', Some("*!*@bad")),
+        ModeChange::new(true, 'l', Some("10")),
+        ModeChange::new(true, 'k', Some("key")),
+        ModeChange::new(false, 'l', None)
+    ]);
+}
+
+#[test]
+fn parse_with_isupport() {
+    let mut isupport = ISupport::new();
+    isupport.update(&Message::from_str(":server 005 me PREFIX=(qaohv)~&@%+ CHANMODES=beI,kf,lj,imnpst :are supported by this server").unwrap());
+    let changes = ModeChange::parse(&isupport, "+qhf-j", &["nick1", "nick2", "[5:5]"]);
+    assert!(changes == vec![
+        ModeChange::new(true, 'q', Some("nick1")),
+        ModeChange::new(true, 'h', Some("nick2")),
+        ModeChange::new(true, 'f', Some("[5:5]")),
+        ModeChange::new(false, 'j', None)
+    ]);
+}
+
+#[test]
+fn build_respects_modes_limit() {
+    let isupport = ISupport::new();
+    let lines = ModeBuilder::new(&isupport)
+        .add('o', Some("a"))
+        .add('o', Some("b"))
+        .add('m', None)
+        .remove('v', Some("c"))
+        .add('v', Some("d"))
+        .build();
+    assert!(lines == vec![String::from_str("+oom-v a b c"), String::from_str("+v d")]);
+}
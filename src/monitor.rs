@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use isupport::CaseMapping;
+use message::Message;
+
+/// The longest `MONITOR + targets` line we send, leaving room for the `\r\n`.
+static MAX_MONITOR_LINE: uint = 510;
+
+/// A change in the status of a monitored nick.
+#[experimental]
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum MonitorEvent {
+    /// The nick has come online, or was online when we started monitoring it.
+    Online(String),
+    Offline(String),
+    /// The server's monitor list was full, so these targets aren't being monitored.
+    ListFull(Vec<String>)
+}
+
+#[deriving(Clone)]
+struct Target {
+    nick: String,
+    online: Option<bool>,
+    mask: Option<String>
+}
+
+/// Keeps the list of nicks being monitored and whether each of them is online, from the `MONITOR` replies.
+#[experimental]
+#[deriving(Clone)]
+pub struct Monitor {
+    targets: HashMap<String, Target>,
+    needs_readd: bool
+}
+
+#[experimental]
+impl Monitor {
+    #[experimental]
+    pub fn new() -> Monitor {
+        Monitor {
+            targets: HashMap::new(),
+            needs_readd: false
+        }
+    }
+
+    /// The nicks being monitored.
+    #[experimental]
+    pub fn targets<'a>(&'a self) -> Vec<&'a str> {
+        self.targets.values().map(|target| target.nick.as_slice()).collect()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_monitored(&self, nick: &str, case_mapping: CaseMapping) -> bool {
+        self.targets.contains_key(&case_mapping.casefold(nick))
+    }
+
+    /// Whether a monitored nick is online, or `None` if it isn't monitored or the server hasn't said yet.
+    #[experimental]
+    pub fn is_online(&self, nick: &str, case_mapping: CaseMapping) -> Option<bool> {
+        self.targets.get(&case_mapping.casefold(nick)).and_then(|target| target.online)
+    }
+
+    /// The `nick!user@host` of a monitored nick that is online, if the server sent it.
+    #[experimental]
+    pub fn mask<'a>(&'a self, nick: &str, case_mapping: CaseMapping) -> Option<&'a str> {
+        self.targets.get(&case_mapping.casefold(nick)).and_then(|target| target.mask.as_ref()).map(|mask| mask.as_slice())
+    }
+
+    /// Adds targets, as long as there is room under the server's `MONITOR` limit. Returns the targets that are new and
+    /// need to be sent to the server, and those that didn't fit.
+    #[experimental]
+    pub fn add(&mut self, targets: &[&str], limit: Option<uint>, case_mapping: CaseMapping) -> (Vec<String>, Vec<String>) {
+        let mut added = Vec::new();
+        let mut refused = Vec::new();
+
+        for &nick in targets.iter() {
+            let key = case_mapping.casefold(nick);
+            if self.targets.contains_key(&key) {
+                continue;
+            }
+            if limit.map_or(false, |limit| self.targets.len() >= limit) {
+                refused.push(String::from_str(nick));
+                continue;
+            }
+
+            self.targets.insert(key, Target {
+                nick: String::from_str(nick),
+                online: None,
+                mask: None
+            });
+            added.push(String::from_str(nick));
+        }
+        (added, refused)
+    }
+
+    /// Removes targets, returning those that were being monitored.
+    #[experimental]
+    pub fn remove(&mut self, targets: &[&str], case_mapping: CaseMapping) -> Vec<String> {
+        targets.iter()
+            .filter(|nick| self.targets.remove(&case_mapping.casefold(**nick)).is_some())
+            .map(|nick| String::from_str(*nick))
+            .collect()
+    }
+
+    #[experimental]
+    pub fn clear(&mut self) {
+        self.targets.clear();
+    }
+
+    /// Forgets who is online, and remembers to monitor every target again once the new connection is registered.
+    #[experimental]
+    pub fn reconnected(&mut self) {
+        for target in self.targets.values_mut() {
+            target.online = None;
+            target.mask = None;
+        }
+        self.needs_readd = !self.targets.is_empty();
+    }
+
+    /// The `MONITOR +` lines to send to monitor every target again after reconnecting, if that hasn't been done yet.
+    #[experimental]
+    pub fn take_readd(&mut self) -> Vec<String> {
+        if !self.needs_readd {
+            return Vec::new();
+        }
+        self.needs_readd = false;
+
+        let nicks: Vec<&str> = self.targets.values().map(|target| target.nick.as_slice()).collect();
+        monitor_lines("+", nicks.as_slice())
+    }
+
+    /// Updates the status of monitored nicks from `RPL_MONONLINE`, `RPL_MONOFFLINE`, `RPL_MONLIST` and `ERR_MONLISTFULL`,
+    /// returning what changed.
+    #[experimental]
+    pub fn update(&mut self, msg: &Message, case_mapping: CaseMapping) -> Vec<MonitorEvent> {
+        let params = match *msg.params() {
+            Some(ref params) if params.len() > 1 => params,
+            _ => return Vec::new()
+        };
+        let mut events = Vec::new();
+
+        match msg.command().as_slice() {
+            // RPL_MONONLINE
+            "730" => {
+                for mask in params[1].as_slice().split(',').filter(|m| !m.is_empty()) {
+                    let nick = mask.split('!').next().unwrap();
+                    let target = self.target(nick, case_mapping.clone());
+                    target.mask = if mask.contains_char('!') { Some(String::from_str(mask)) } else { None };
+                    if target.online != Some(true) {
+                        target.online = Some(true);
+                        events.push(MonitorEvent::Online(target.nick.clone()));
+                    }
+                }
+            },
+            // RPL_MONOFFLINE
+            "731" => {
+                for nick in params[1].as_slice().split(',').filter(|n| !n.is_empty()) {
+                    let target = self.target(nick, case_mapping.clone());
+                    target.mask = None;
+                    if target.online != Some(false) {
+                        target.online = Some(false);
+                        events.push(MonitorEvent::Offline(target.nick.clone()));
+                    }
+                }
+            },
+            // RPL_MONLIST: the server's list is the real one
+            "732" => {
+                for nick in params[1].as_slice().split(',').filter(|n| !n.is_empty()) {
+                    self.target(nick, case_mapping.clone());
+                }
+            },
+            // ERR_MONLISTFULL
+            "734" if params.len() > 2 => {
+                let refused: Vec<String> = params[2].as_slice().split(',').filter(|n| !n.is_empty()).map(|n| String::from_str(n)).collect();
+                for nick in refused.iter() {
+                    self.targets.remove(&case_mapping.casefold(nick.as_slice()));
+                }
+                events.push(MonitorEvent::ListFull(refused));
+            },
+            _ => {}
+        }
+        events
+    }
+
+    fn target<'a>(&'a mut self, nick: &str, case_mapping: CaseMapping) -> &'a mut Target {
+        let key = case_mapping.casefold(nick);
+        if !self.targets.contains_key(&key) {
+            self.targets.insert(key.clone(), Target {
+                nick: String::from_str(nick),
+                online: None,
+                mask: None
+            });
+        }
+        self.targets.get_mut(&key).unwrap()
+    }
+}
+
+/// Formats `MONITOR + nick,nick` or `MONITOR - nick,nick` lines, as many as needed to fit the line length limit.
+#[experimental]
+pub fn monitor_lines(modifier: &str, targets: &[&str]) -> Vec<String> {
+    let start = format!("MONITOR {} ", modifier);
+    let mut lines = Vec::new();
+    let mut line = start.clone();
+
+    for &target in targets.iter() {
+        let needed = if line.len() > start.len() { target.len() + 1 } else { target.len() };
+        if line.len() > start.len() && line.len() + needed > MAX_MONITOR_LINE {
+            lines.push(line);
+            line = start.clone();
+        }
+        if line.len() > start.len() {
+            line.push(',');
+        }
+        line.push_str(target);
+    }
+
+    if line.len() > start.len() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[test]
+fn online_status() {
+    let mut monitor = Monitor::new();
+    let cm = CaseMapping::Rfc1459;
+    let (added, refused) = monitor.add(&["alice", "Bob", "carol"], Some(2), cm.clone());
+    assert!(added == vec![String::from_str("alice"), String::from_str("Bob")]);
+    assert!(refused == vec![String::from_str("carol")]);
+
+    let events = monitor.update(&Message::from_str(":irc 730 relay :alice!a@host,bob!b@host").unwrap(), cm.clone());
+    assert!(events == vec![MonitorEvent::Online(String::from_str("alice")), MonitorEvent::Online(String::from_str("Bob"))]);
+    assert!(monitor.is_online("BOB", cm.clone()) == Some(true));
+    assert!(monitor.mask("alice", cm.clone()) == Some("alice!a@host"));
+
+    // Repeats don't count as changes
+    assert!(monitor.update(&Message::from_str(":irc 730 relay :alice!a@host").unwrap(), cm.clone()).is_empty());
+    let events = monitor.update(&Message::from_str(":irc 731 relay :alice").unwrap(), cm.clone());
+    assert!(events == vec![MonitorEvent::Offline(String::from_str("alice"))]);
+
+    monitor.reconnected();
+    assert!(monitor.is_online("alice", cm.clone()).is_none());
+    assert!(monitor.take_readd().len() == 1);
+    assert!(monitor.take_readd().is_empty());
+}
+
+#[test]
+fn full_list_and_long_lines() {
+    let mut monitor = Monitor::new();
+    let cm = CaseMapping::Rfc1459;
+    monitor.add(&["alice", "bob"], None, cm.clone());
+
+    let events = monitor.update(&Message::from_str(":irc 734 relay 1 bob :Monitor list is full").unwrap(), cm.clone());
+    assert!(events == vec![MonitorEvent::ListFull(vec![String::from_str("bob")])]);
+    assert!(!monitor.is_monitored("bob", cm.clone()));
+
+    let nicks: Vec<String> = range(0u, 100).map(|i| format!("nickname{}", i)).collect();
+    let nicks: Vec<&str> = nicks.iter().map(|n| n.as_slice()).collect();
+    let lines = monitor_lines("+", nicks.as_slice());
+    assert!(lines.len() == 3);
+    assert!(lines.iter().all(|line| line.len() <= MAX_MONITOR_LINE && line.as_slice().starts_with("MONITOR + nickname")));
+}
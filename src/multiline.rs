@@ -0,0 +1,150 @@
+use std::str::Owned;
+
+use batch::Batch;
+use message::Message;
+use tag::Tag;
+#[cfg(test)]
+use batch::BatchTracker;
+
+/// The limits a server puts on `draft/multiline` batches, from the capability's value.
+#[experimental]
+#[deriving(Clone, PartialEq, Show)]
+pub struct MultilineLimits {
+    /// The most bytes of text in one batch, counting a byte for each line break.
+    pub max_bytes: uint,
+    pub max_lines: Option<uint>
+}
+
+#[experimental]
+impl MultilineLimits {
+    /// Reads a capability value such as `max-bytes=4096,max-lines=24`. `max-bytes` is required.
+    #[experimental]
+    pub fn from_cap_value(value: &str) -> Option<MultilineLimits> {
+        let mut max_bytes = None;
+        let mut max_lines = None;
+
+        for token in value.split(',') {
+            let mut kv = token.splitn(1, '=');
+            match (kv.next(), kv.next().and_then(|v| from_str::<uint>(v))) {
+                (Some("max-bytes"), Some(n)) => max_bytes = Some(n),
+                (Some("max-lines"), Some(n)) => max_lines = Some(n),
+                _ => {}
+            }
+        }
+
+        max_bytes.map(|max_bytes| MultilineLimits {
+            max_bytes: max_bytes,
+            max_lines: max_lines
+        })
+    }
+}
+
+/// Groups the pieces of each line into as few batches as the limits allow. Each piece is paired with whether it carries on
+/// from the previous piece without a line break (`draft/multiline-concat`).
+#[experimental]
+pub fn plan_batches(lines: &[Vec<String>], limits: &MultilineLimits) -> Vec<Vec<(String, bool)>> {
+    let mut batches = Vec::new();
+    let mut batch: Vec<(String, bool)> = Vec::new();
+    let mut bytes = 0u;
+
+    for pieces in lines.iter() {
+        for (i, piece) in pieces.iter().enumerate() {
+            let line_break = if i == 0 && !batch.is_empty() { 1 } else { 0 };
+            let full = !batch.is_empty()
+                && (bytes + line_break + piece.len() > limits.max_bytes || limits.max_lines.map_or(false, |max| batch.len() >= max));
+            if full {
+                batches.push(batch);
+                batch = Vec::new();
+                bytes = 0;
+            }
+
+            // The first line of a batch can't be a continuation
+            let concat = i > 0 && !batch.is_empty();
+            bytes += piece.len() + if i == 0 && !batch.is_empty() { 1 } else { 0 };
+            batch.push((piece.clone(), concat));
+        }
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Joins the lines of a `draft/multiline` batch back into one message, with a `\n` between lines, carrying the tags of the
+/// batch itself (such as its `msgid` and `time`).
+#[experimental]
+pub fn reassemble(batch: &Batch) -> Option<Message<'static>> {
+    if batch.batch_type() != "draft/multiline" || batch.params().is_empty() || batch.messages().is_empty() {
+        return None;
+    }
+
+    let mut text = String::new();
+    for (i, msg) in batch.messages().iter().enumerate() {
+        if i > 0 && !msg.has_tag("draft/multiline-concat") {
+            text.push('\n');
+        }
+        match *msg.params() {
+            Some(ref params) if params.len() > 1 => text.push_str(params[1].as_slice()),
+            _ => {}
+        }
+    }
+
+    let first = &batch.messages()[0];
+    let tags: Vec<Tag<'static>> = match *batch.start().tags() {
+        Some(ref tags) => tags.iter().filter(|tag| tag.name().as_slice() != "batch").map(|tag| tag.clone()).collect(),
+        None => Vec::new()
+    };
+
+    Some(Message::from_parts(
+        if tags.is_empty() { None } else { Some(tags) },
+        first.prefix().clone(),
+        first.command().clone(),
+        Some(vec![Owned(batch.params()[0].clone()), Owned(text)])
+    ))
+}
+
+#[test]
+fn limits() {
+    assert!(MultilineLimits::from_cap_value("max-bytes=4096,max-lines=24") == Some(MultilineLimits { max_bytes: 4096, max_lines: Some(24) }));
+    assert!(MultilineLimits::from_cap_value("max-bytes=4096") == Some(MultilineLimits { max_bytes: 4096, max_lines: None }));
+    assert!(MultilineLimits::from_cap_value("max-lines=24").is_none());
+}
+
+#[test]
+fn planning() {
+    let lines = vec![
+        vec![String::from_str("abc "), String::from_str("def")],
+        vec![String::from_str("ghi")],
+        vec![String::from_str("jkl")]
+    ];
+
+    let batches = plan_batches(lines.as_slice(), &MultilineLimits { max_bytes: 100, max_lines: Some(3) });
+    assert!(batches == vec![
+        vec![(String::from_str("abc "), false), (String::from_str("def"), true), (String::from_str("ghi"), false)],
+        vec![(String::from_str("jkl"), false)]
+    ]);
+
+    // "abc def" is 7 bytes, and the line break before "ghi" makes 11
+    let batches = plan_batches(lines.as_slice(), &MultilineLimits { max_bytes: 10, max_lines: None });
+    assert!(batches.len() == 2 && batches[1].len() == 2);
+}
+
+#[test]
+fn reassembly() {
+    let mut tracker = BatchTracker::new();
+    let lines = [
+        "@msgid=xyz :alice!a@host BATCH +m draft/multiline #chan",
+        "@batch=m :alice!a@host PRIVMSG #chan :hello",
+        "@batch=m;draft/multiline-concat :alice!a@host PRIVMSG #chan : world",
+        "@batch=m :alice!a@host PRIVMSG #chan :bye"
+    ];
+    for line in lines.iter() {
+        tracker.process(&Message::from_str(*line).unwrap());
+    }
+    let batch = tracker.process(&Message::from_str(":alice!a@host BATCH -m").unwrap()).unwrap();
+
+    let msg = reassemble(&batch).unwrap();
+    assert!(msg.msgid() == Some("xyz"));
+    assert!(msg.to_string().as_slice() == "@msgid=xyz :alice!a@host PRIVMSG #chan :hello world\nbye\r\n");
+}
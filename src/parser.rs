@@ -26,6 +26,12 @@ impl<T: Reader> Parser<T> {
         }
     }
 
+    #[experimental]
+    #[inline]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        &mut self.reader
+    }
+
     #[experimental]
     fn read_message_from_buffer(&mut self) -> IoResult<Option<Message<'static>>> {
         let mut start: uint = 0;
@@ -0,0 +1,148 @@
+//! The message splitting, joining and user@host vectors from the IRCv3 parser-tests suite
+//! (https://github.com/ircdocs/parser-tests).
+//!
+//! Commands are always upper-cased by `Message::from_str`, so verbs are compared case-insensitively.
+
+use std::ascii::AsciiExt;
+use std::str::Owned;
+
+use message::Message;
+use prefix::Prefix;
+use tag::Tag;
+
+/// Parses `input` and checks each part. An empty tag value means the tag has no value.
+fn split(input: &str, tags: &[(&str, &str)], source: Option<&str>, verb: &str, params: &[&str]) {
+    let msg = match Message::from_str(input) {
+        Ok(msg) => msg,
+        Err(e) => panic!("{} failed to parse: {}", input, e)
+    };
+
+    let tag_count = msg.tags().as_ref().map_or(0, |t| t.len());
+    assert!(tag_count == tags.len(), "{}: expected {} tags, got {}", input, tags.len(), tag_count);
+    for &(name, value) in tags.iter() {
+        assert!(msg.has_tag(name), "{}: missing tag {}", input, name);
+        assert!(msg.tag_value(name).unwrap_or("") == value, "{}: wrong value for tag {}", input, name);
+    }
+
+    let prefix = msg.prefix().as_ref().map(|p| p.to_string());
+    assert!(prefix.as_ref().map(|p| p.as_slice()) == source, "{}: wrong source {}", input, prefix);
+
+    assert!(msg.command().as_slice() == verb.to_ascii_upper().as_slice(), "{}: wrong verb {}", input, msg.command());
+
+    let got: Vec<&str> = msg.params().as_ref().map_or(Vec::new(), |p| p.iter().map(|p| p.as_slice()).collect());
+    assert!(got.as_slice() == params, "{}: wrong params {}", input, got);
+}
+
+/// Builds a message from its parts and checks that it serialises to one of `expected`.
+fn join(tags: &[(&str, &str)], source: Option<&str>, verb: &str, params: &[&str], expected: &[&str]) {
+    let tags = if tags.is_empty() {
+        None
+    } else {
+        Some(tags.iter().map(|&(name, value)| Tag::new(name, if value == "" { None } else { Some(value) })).collect())
+    };
+    let params = if params.is_empty() {
+        None
+    } else {
+        Some(params.iter().map(|p| Owned(String::from_str(*p))).collect())
+    };
+
+    let msg = Message::from_parts(tags, source.map(|s| Prefix::from_str(s)), Owned(String::from_str(verb)), params);
+    let line = msg.to_string();
+    assert!(expected.iter().any(|e| format!("{}\r\n", e) == line), "{} does not match {}", line, expected);
+}
+
+fn userhost(source: &str, nick: &str, user: Option<&str>, host: Option<&str>) {
+    let prefix = Prefix::from_str(source);
+    assert!(prefix.nick() == Some(nick), "{}: wrong nick", source);
+    assert!(prefix.ident() == user, "{}: wrong user", source);
+    assert!(prefix.host_opt() == host, "{}: wrong host", source);
+}
+
+#[test]
+fn msg_split_simple() {
+    split("foo bar baz asdf", &[], None, "foo", &["bar", "baz", "asdf"]);
+    split(":coolguy foo bar baz asdf", &[], Some("coolguy"), "foo", &["bar", "baz", "asdf"]);
+    split("foo bar baz :asdf quux", &[], None, "foo", &["bar", "baz", "asdf quux"]);
+    split("foo bar baz :", &[], None, "foo", &["bar", "baz", ""]);
+    split("foo bar baz ::asdf", &[], None, "foo", &["bar", "baz", ":asdf"]);
+    split(":coolguy foo bar baz :asdf quux", &[], Some("coolguy"), "foo", &["bar", "baz", "asdf quux"]);
+    split(":coolguy foo bar baz :  asdf quux ", &[], Some("coolguy"), "foo", &["bar", "baz", "  asdf quux "]);
+    split(":coolguy PRIVMSG bar :lol :) ", &[], Some("coolguy"), "PRIVMSG", &["bar", "lol :) "]);
+    split(":coolguy foo bar baz :", &[], Some("coolguy"), "foo", &["bar", "baz", ""]);
+    split(":coolguy foo bar baz :  ", &[], Some("coolguy"), "foo", &["bar", "baz", "  "]);
+    split(":src JOIN #chan", &[], Some("src"), "JOIN", &["#chan"]);
+    split(":src JOIN :#chan", &[], Some("src"), "JOIN", &["#chan"]);
+    split(":src AWAY", &[], Some("src"), "AWAY", &[]);
+    split(":src AWAY ", &[], Some("src"), "AWAY", &[]);
+    split(":cool\tguy foo bar baz", &[], Some("cool\tguy"), "foo", &["bar", "baz"]);
+    split(":coolguy!ag@net\x035w\x03ork.admin PRIVMSG foo :bar baz", &[], Some("coolguy!ag@net\x035w\x03ork.admin"), "PRIVMSG", &["foo", "bar baz"]);
+    split(":coolguy!~ag@n\x02et\x0305w\x0fork.admin PRIVMSG foo :bar baz", &[], Some("coolguy!~ag@n\x02et\x0305w\x0fork.admin"), "PRIVMSG", &["foo", "bar baz"]);
+    split(":irc.example.com COMMAND param1 param2 :param3 param3", &[], Some("irc.example.com"), "COMMAND", &["param1", "param2", "param3 param3"]);
+    split("COMMAND", &[], None, "COMMAND", &[]);
+}
+
+#[test]
+fn msg_split_broken_servers() {
+    split(":gravel.mozilla.org 432  #momo :Erroneous Nickname: Illegal characters", &[], Some("gravel.mozilla.org"), "432", &["#momo", "Erroneous Nickname: Illegal characters"]);
+    split(":gravel.mozilla.org MODE #tckk +n ", &[], Some("gravel.mozilla.org"), "MODE", &["#tckk", "+n"]);
+    split(":services.esper.net MODE #foo-bar +o foobar  ", &[], Some("services.esper.net"), "MODE", &["#foo-bar", "+o", "foobar"]);
+    split(":SomeOp MODE #channel :+i", &[], Some("SomeOp"), "MODE", &["#channel", "+i"]);
+    split(":SomeOp MODE #channel +oo SomeUser :AnotherUser", &[], Some("SomeOp"), "MODE", &["#channel", "+oo", "SomeUser", "AnotherUser"]);
+}
+
+#[test]
+fn msg_split_tags() {
+    split("@a=b;c=32;k;rt=ql7 foo", &[("a", "b"), ("c", "32"), ("k", ""), ("rt", "ql7")], None, "foo", &[]);
+    split(r"@a=b\\and\nk;c=72\s45;d=gh\:764 foo", &[("a", "b\\and\nk"), ("c", "72 45"), ("d", "gh;764")], None, "foo", &[]);
+    split("@c;h=;a=b :quux ab cd", &[("c", ""), ("h", ""), ("a", "b")], Some("quux"), "ab", &["cd"]);
+    split("@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4= :irc.example.com COMMAND param1 param2 :param3 param3",
+          &[("tag1", "value1"), ("tag2", ""), ("vendor1/tag3", "value2"), ("vendor2/tag4", "")],
+          Some("irc.example.com"), "COMMAND", &["param1", "param2", "param3 param3"]);
+    split("@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4 COMMAND param1 param2 :param3 param3",
+          &[("tag1", "value1"), ("tag2", ""), ("vendor1/tag3", "value2"), ("vendor2/tag4", "")],
+          None, "COMMAND", &["param1", "param2", "param3 param3"]);
+    split(r"@foo=\\\\\:\\s\s\r\n COMMAND", &[("foo", "\\\\;\\s \r\n")], None, "COMMAND", &[]);
+}
+
+#[test]
+fn msg_split_broken_tags() {
+    split(r"@tag1=value\\ntest COMMAND", &[("tag1", "value\\ntest")], None, "COMMAND", &[]);
+    split(r"@tag1=value\1 COMMAND", &[("tag1", "value1")], None, "COMMAND", &[]);
+    split(r"@tag1=value1\ COMMAND", &[("tag1", "value1")], None, "COMMAND", &[]);
+    split("@tag1=1;tag2=3;tag3=4;tag1=5 COMMAND", &[("tag1", "5"), ("tag2", "3"), ("tag3", "4")], None, "COMMAND", &[]);
+    split("@tag1=1;tag2=3;tag3=4;tag1=5;vendor/tag2=8 COMMAND", &[("tag1", "5"), ("tag2", "3"), ("tag3", "4"), ("vendor/tag2", "8")], None, "COMMAND", &[]);
+}
+
+#[test]
+fn msg_join_simple() {
+    join(&[], None, "foo", &["bar", "baz", "asdf"], &["foo bar baz asdf", "foo bar baz :asdf"]);
+    join(&[], Some("coolguy"), "foo", &["bar", "baz", "asdf"], &[":coolguy foo bar baz asdf", ":coolguy foo bar baz :asdf"]);
+    join(&[], None, "foo", &["bar", "baz", "asdf quux"], &["foo bar baz :asdf quux"]);
+    join(&[], None, "foo", &["bar", "baz", ""], &["foo bar baz :"]);
+    join(&[], None, "foo", &["bar", "baz", ":asdf"], &["foo bar baz ::asdf"]);
+    join(&[], Some("coolguy"), "foo", &["bar", "baz", "asdf quux"], &[":coolguy foo bar baz :asdf quux"]);
+    join(&[], Some("coolguy"), "foo", &["bar", "baz", "  asdf quux "], &[":coolguy foo bar baz :  asdf quux "]);
+    join(&[], Some("coolguy"), "PRIVMSG", &["bar", "lol :) "], &[":coolguy PRIVMSG bar :lol :) "]);
+    join(&[], Some("coolguy"), "foo", &["bar", "baz", ""], &[":coolguy foo bar baz :"]);
+    join(&[], Some("coolguy"), "foo", &["bar", "baz", "  "], &[":coolguy foo bar baz :  "]);
+    join(&[], Some("src"), "JOIN", &["#chan"], &[":src JOIN #chan", ":src JOIN :#chan"]);
+    join(&[], Some("src"), "AWAY", &[], &[":src AWAY"]);
+}
+
+#[test]
+fn msg_join_tags() {
+    join(&[("a", "b"), ("c", "32"), ("k", ""), ("rt", "ql7")], None, "foo", &[], &["@a=b;c=32;k;rt=ql7 foo"]);
+    join(&[("a", "b\\and\nk"), ("c", "72 45"), ("d", "gh;764")], None, "foo", &[], &[r"@a=b\\and\nk;c=72\s45;d=gh\:764 foo"]);
+    join(&[("c", ""), ("h", ""), ("a", "b")], Some("quux"), "ab", &["cd"], &["@c;h;a=b :quux ab cd", "@c;h=;a=b :quux ab cd"]);
+}
+
+#[test]
+fn userhost_split() {
+    userhost("coolguy", "coolguy", None, None);
+    userhost("coolguy!ag@127.0.0.1", "coolguy", Some("ag"), Some("127.0.0.1"));
+    userhost("coolguy!~ag@localhost", "coolguy", Some("~ag"), Some("localhost"));
+    userhost("coolguy@127.0.0.1", "coolguy", None, Some("127.0.0.1"));
+    userhost("coolguy!ag", "coolguy", Some("ag"), None);
+    userhost("coolguy!ag@net\x035w\x03ork.admin", "coolguy", Some("ag"), Some("net\x035w\x03ork.admin"));
+    userhost("coolguy!~ag@n\x02et\x0305w\x0fork.admin", "coolguy", Some("~ag"), Some("n\x02et\x0305w\x0fork.admin"));
+}
@@ -0,0 +1,153 @@
+use std::cmp::min;
+use std::comm::{Disconnected, Empty};
+use std::io::{IoError, IoErrorKind, IoResult, Reader, Writer};
+use std::io::net::ip::SocketAddr;
+use std::io::timer::sleep;
+use std::sync::{Arc, Mutex};
+use std::time::duration::Duration;
+use time::precise_time_ns;
+
+use connection::IrcStream;
+
+struct PipeReader {
+    rx: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: uint,
+    closed: bool
+}
+
+impl PipeReader {
+    /// Waits for the next chunk from the other end. An empty chunk means the other end was closed.
+    fn fill(&mut self, timeout_ms: Option<u64>) -> IoResult<()> {
+        let chunk = match timeout_ms {
+            None => self.rx.recv_opt().unwrap_or(Vec::new()),
+            Some(timeout_ms) => {
+                let deadline = precise_time_ns() + timeout_ms * 1_000_000;
+                let mut chunk = None;
+                while chunk.is_none() {
+                    match self.rx.try_recv() {
+                        Ok(c) => chunk = Some(c),
+                        Err(Disconnected) => chunk = Some(Vec::new()),
+                        Err(Empty) if precise_time_ns() >= deadline => {
+                            return Err(IoError {
+                                kind: IoErrorKind::TimedOut,
+                                desc: "Read timed out",
+                                detail: None
+                            });
+                        },
+                        Err(Empty) => sleep(Duration::milliseconds(1))
+                    }
+                }
+                chunk.unwrap()
+            }
+        };
+
+        if chunk.is_empty() {
+            self.closed = true;
+        }
+        self.buffer = chunk;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+/// One end of an in-memory, bidirectional byte stream, for running a client without a network connection.
+#[experimental]
+#[deriving(Clone)]
+pub struct DuplexPipe {
+    tx: Sender<Vec<u8>>,
+    reader: Arc<Mutex<PipeReader>>,
+    read_timeout: Option<u64>
+}
+
+#[experimental]
+impl DuplexPipe {
+    /// Creates a connected pair of pipe ends: what is written to one can be read from the other.
+    #[experimental]
+    pub fn new() -> (DuplexPipe, DuplexPipe) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+
+        (DuplexPipe::from_parts(a_tx, b_rx), DuplexPipe::from_parts(b_tx, a_rx))
+    }
+
+    fn from_parts(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> DuplexPipe {
+        DuplexPipe {
+            tx: tx,
+            reader: Arc::new(Mutex::new(PipeReader {
+                rx: rx,
+                buffer: Vec::new(),
+                position: 0,
+                closed: false
+            })),
+            read_timeout: None
+        }
+    }
+}
+
+#[experimental]
+impl Reader for DuplexPipe {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let mut reader = self.reader.lock();
+
+        while !reader.closed && reader.position >= reader.buffer.len() {
+            try!(reader.fill(self.read_timeout));
+        }
+
+        if reader.closed {
+            return Err(IoError {
+                kind: IoErrorKind::EndOfFile,
+                desc: "Pipe closed",
+                detail: None
+            });
+        }
+
+        let count = min(buf.len(), reader.buffer.len() - reader.position);
+        for i in range(0, count) {
+            buf[i] = reader.buffer[reader.position + i];
+        }
+        reader.position += count;
+        Ok(count)
+    }
+}
+
+#[experimental]
+impl Writer for DuplexPipe {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        self.tx.send_opt(buf.to_vec()).map_err(|_| IoError {
+            kind: IoErrorKind::BrokenPipe,
+            desc: "Pipe closed",
+            detail: None
+        })
+    }
+}
+
+#[experimental]
+impl IrcStream for DuplexPipe {
+    #[inline]
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.read_timeout = timeout_ms;
+    }
+
+    /// Writes to a pipe never block, so there is nothing to time out.
+    #[inline]
+    fn set_write_timeout(&mut self, _timeout_ms: Option<u64>) {}
+
+    fn close(&mut self) -> IoResult<()> {
+        // The other end sees an empty chunk as the end of the stream
+        let _ = self.tx.send_opt(Vec::new());
+        Ok(())
+    }
+
+    fn open(_addr: SocketAddr) -> IoResult<DuplexPipe> {
+        Err(IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "Pipes cannot be reopened",
+            detail: None
+        })
+    }
+}
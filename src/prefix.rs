@@ -1,6 +1,12 @@
 use std::fmt;
 use std::str::{MaybeOwned, Owned};
+#[cfg(test)]
+use std::str::Slice;
 
+use isupport::CaseMapping;
+use mask::{mod, BanMaskStyle};
+
+/// A `nick!ident@host` prefix. `ident` and `host` are empty if the prefix left them out.
 #[unstable]
 #[deriving(Clone, PartialEq, Eq)]
 pub struct ClientPrefix<'a> {
@@ -9,11 +15,34 @@ pub struct ClientPrefix<'a> {
     pub host: MaybeOwned<'a>
 }
 
+#[experimental]
+impl<'a> ClientPrefix<'a> {
+    /// Tests this hostmask against a ban or ignore mask, filling in any missing parts of the mask with `*`.
+    #[experimental]
+    pub fn matches(&self, mask: &str, case_mapping: CaseMapping) -> bool {
+        let hostmask = format!("{}!{}@{}", self.nick.as_slice(), self.ident.as_slice(), self.host.as_slice());
+        mask::glob_match(case_mapping, mask::normalise_mask(mask).as_slice(), hostmask.as_slice())
+    }
+
+    #[experimental]
+    pub fn ban_mask(&self, style: BanMaskStyle) -> String {
+        mask::ban_mask(style, self.nick.as_slice(), self.ident.as_slice(), self.host.as_slice())
+    }
+}
+
 #[stable]
 impl<'a> fmt::Show for ClientPrefix<'a> {
     #[stable]
     fn fmt<'a>(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}!{}@{}", self.nick.as_slice(), self.ident.as_slice(), self.host.as_slice())
+        try!(write!(f, "{}", self.nick.as_slice()));
+
+        if !self.ident.as_slice().is_empty() {
+            try!(write!(f, "!{}", self.ident.as_slice()));
+        }
+        if !self.host.as_slice().is_empty() {
+            try!(write!(f, "@{}", self.host.as_slice()));
+        }
+        Ok(())
     }
 }
 
@@ -26,19 +55,29 @@ pub enum Prefix<'a> {
 
 #[unstable]
 impl<'a> Prefix<'a> {
+    /// Parses a message prefix. Anything of the form `nick[!user][@host]` is a client, unless it is a bare name containing a dot, which is a server.
     #[unstable]
     pub fn from_str(s: &str) -> Prefix<'static> {
-        let re = regex!(r"^(.+)!(.+)@(.+)$");
-        match re.captures(s) {
-            Some(captures) => {
-                Client(ClientPrefix{
-                    nick: Owned(String::from_str(captures.at(1))),
-                    ident: Owned(String::from_str(captures.at(2))),
-                    host: Owned(String::from_str(captures.at(3)))
-                })
-            },
-            None => {
+        let bang = s.find('!');
+        let at = s.find('@');
+
+        match (bang, at) {
+            (None, None) if s.contains_char('.') => {
                 Server(Owned(String::from_str(s)))
+            },
+            _ => {
+                // A `!` after the `@` is part of the host
+                let bang = match (bang, at) {
+                    (Some(b), Some(a)) if b > a => None,
+                    _ => bang
+                };
+                let nick_end = bang.or(at).unwrap_or(s.len());
+
+                Client(ClientPrefix {
+                    nick: Owned(String::from_str(s.slice_to(nick_end))),
+                    ident: Owned(String::from_str(bang.map_or("", |b| s.slice(b + 1, at.unwrap_or(s.len()))))),
+                    host: Owned(String::from_str(at.map_or("", |a| s.slice_from(a + 1))))
+                })
             }
         }
     }
@@ -58,6 +97,17 @@ impl<'a> Prefix<'a> {
         !self.is_client()
     }
 
+    /// Tests this prefix against a ban or ignore mask. Server prefixes are matched as if they were a host.
+    #[experimental]
+    pub fn matches(&self, mask: &str, case_mapping: CaseMapping) -> bool {
+        match *self {
+            Client(ref client_prefix) => client_prefix.matches(mask, case_mapping),
+            Server(ref server_prefix) => {
+                mask::glob_match(case_mapping, mask::normalise_mask(mask).as_slice(), format!("!@{}", server_prefix).as_slice())
+            }
+        }
+    }
+
     #[unstable]
     pub fn nick<'a>(&'a self) -> Option<&'a str> {
         match *self {
@@ -72,12 +122,14 @@ impl<'a> Prefix<'a> {
     pub fn ident<'a>(&'a self) -> Option<&'a str> {
         match *self {
             Client(ref client_prefix) => {
-                Some(client_prefix.ident.as_slice())
+                let ident = client_prefix.ident.as_slice();
+                if ident.is_empty() { None } else { Some(ident) }
             },
             Server(_) => None
         }
     }
 
+    /// The client's host, or the server name. Empty if a client prefix didn't include the host.
     #[unstable]
     pub fn host<'a>(&'a self) -> &'a str {
         match *self {
@@ -87,6 +139,13 @@ impl<'a> Prefix<'a> {
             Server(ref server_prefix) => server_prefix.as_slice()
         }
     }
+
+    /// The client's host, if it was sent, or the server name.
+    #[experimental]
+    pub fn host_opt<'a>(&'a self) -> Option<&'a str> {
+        let host = self.host();
+        if host.is_empty() { None } else { Some(host) }
+    }
 }
 
 #[stable]
@@ -102,3 +161,52 @@ impl<'a> fmt::Show for Prefix<'a> {
         }
     }
 }
+
+#[test]
+fn full_prefix() {
+    let prefix = Prefix::from_str("nick!user@host.example.com");
+    assert!(prefix == Client(ClientPrefix {
+        nick: Slice("nick"),
+        ident: Slice("user"),
+        host: Slice("host.example.com")
+    }));
+    assert!(prefix.to_string().as_slice() == "nick!user@host.example.com");
+}
+
+#[test]
+fn partial_prefixes() {
+    let prefix = Prefix::from_str("nick@host");
+    assert!(prefix.nick() == Some("nick"));
+    assert!(prefix.ident().is_none());
+    assert!(prefix.host() == "host");
+    assert!(prefix.to_string().as_slice() == "nick@host");
+
+    let prefix = Prefix::from_str("nick");
+    assert!(prefix.is_client());
+    assert!(prefix.nick() == Some("nick"));
+    assert!(prefix.host() == "" && prefix.host_opt().is_none());
+    assert!(prefix.to_string().as_slice() == "nick");
+
+    let prefix = Prefix::from_str("nick!user");
+    assert!(prefix.ident() == Some("user"));
+    assert!(prefix.host_opt().is_none());
+    assert!(prefix.to_string().as_slice() == "nick!user");
+}
+
+#[test]
+fn server_prefix() {
+    let prefix = Prefix::from_str("irc.example.net");
+    assert!(prefix.is_server());
+    assert!(prefix.nick().is_none());
+    assert!(prefix.host() == "irc.example.net");
+    assert!(prefix.to_string().as_slice() == "irc.example.net");
+}
+
+#[test]
+fn prefix_matches() {
+    let prefix = Prefix::from_str("Nick!~user@host.example.com");
+    assert!(prefix.matches("*!*@*.example.com", CaseMapping::Rfc1459));
+    assert!(prefix.matches("nick", CaseMapping::Rfc1459));
+    assert!(!prefix.matches("*!*@*.example.org", CaseMapping::Rfc1459));
+    assert!(Prefix::from_str("nick").matches("nick!*@*", CaseMapping::Rfc1459));
+}
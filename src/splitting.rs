@@ -0,0 +1,133 @@
+/// The longest line a server will accept or send, including the `\r\n`.
+static MAX_LINE_LEN: uint = 512;
+/// What to assume when we don't yet know our own user name (usually `USERLEN` plus a `~`) or host.
+static ASSUMED_USER_LEN: uint = 11;
+static ASSUMED_HOST_LEN: uint = 63;
+
+/// The most bytes of text that fit in one `command` to `target` once the server has added our `nick!user@host` prefix
+/// for the recipients.
+#[experimental]
+pub fn text_budget(command: &str, target: &str, nick: &str, user: Option<&str>, host: Option<&str>) -> uint {
+    let user_len = user.map_or(ASSUMED_USER_LEN, |u| u.len());
+    let host_len = host.map_or(ASSUMED_HOST_LEN, |h| h.len());
+    // :nick!user@host COMMAND target :text\r\n
+    let overhead = 1 + nick.len() + 1 + user_len + 1 + host_len + 1 + command.len() + 1 + target.len() + 2 + 2;
+
+    if overhead < MAX_LINE_LEN { MAX_LINE_LEN - overhead } else { 0 }
+}
+
+/// Splits a line of text into pieces of at most `max_bytes` bytes, preferring to break after a space.
+///
+/// Pieces never end partway through a UTF-8 character, a grapheme cluster or a colour code, and joining them gives back
+/// the original text. A single grapheme or colour code longer than `max_bytes` gets a piece to itself.
+#[experimental]
+pub fn split_text(text: &str, max_bytes: uint) -> Vec<String> {
+    let bounds = atom_bounds(text);
+    let mut pieces = Vec::new();
+    let mut start = 0u;
+    // Where the current piece can end without breaking a word
+    let mut word_end: Option<uint> = None;
+    let mut i = 0u;
+
+    while i + 1 < bounds.len() {
+        let (atom_start, atom_end) = (bounds[i], bounds[i + 1]);
+
+        if atom_end - start > max_bytes && atom_start > start {
+            let end = word_end.unwrap_or(atom_start);
+            pieces.push(String::from_str(text.slice(start, end)));
+            start = end;
+            word_end = None;
+            continue;
+        }
+
+        if text.slice(atom_start, atom_end) == " " {
+            word_end = Some(atom_end);
+        }
+        i += 1;
+    }
+
+    if start < text.len() || pieces.is_empty() {
+        pieces.push(String::from_str(text.slice_from(start)));
+    }
+    pieces
+}
+
+/// The byte offsets where text can be split: grapheme boundaries that aren't inside a colour code, plus the end of the text.
+fn atom_bounds(text: &str) -> Vec<uint> {
+    let bytes = text.as_bytes();
+    let mut bounds = Vec::new();
+    let mut skip_to = 0u;
+
+    for (i, _) in text.grapheme_indices(true) {
+        if i < skip_to {
+            continue;
+        }
+        bounds.push(i);
+
+        match bytes[i] {
+            0x03 => skip_to = i + 1 + colour_params(bytes.slice_from(i + 1), 2, is_digit),
+            0x04 => skip_to = i + 1 + colour_params(bytes.slice_from(i + 1), 6, is_hex_digit),
+            _ => {}
+        }
+    }
+
+    bounds.push(text.len());
+    bounds
+}
+
+/// The length of a colour code's `foreground[,background]` parameters.
+fn colour_params(s: &[u8], max_len: uint, valid: fn(u8) -> bool) -> uint {
+    let foreground = count_while(s, max_len, valid);
+
+    if foreground > 0 && s.len() > foreground + 1 && s[foreground] == b',' && valid(s[foreground + 1]) {
+        foreground + 1 + count_while(s.slice_from(foreground + 1), max_len, valid)
+    } else {
+        foreground
+    }
+}
+
+fn count_while(s: &[u8], max_len: uint, valid: fn(u8) -> bool) -> uint {
+    s.iter().take(max_len).take_while(|&&b| valid(b)).count()
+}
+
+fn is_digit(b: u8) -> bool {
+    b >= b'0' && b <= b'9'
+}
+
+fn is_hex_digit(b: u8) -> bool {
+    is_digit(b) || (b >= b'a' && b <= b'f') || (b >= b'A' && b <= b'F')
+}
+
+#[test]
+fn budget() {
+    assert!(text_budget("PRIVMSG", "#chan", "nick", Some("user"), Some("host")) == 479);
+    assert!(text_budget("NOTICE", "#chan", "nick", None, None) == 414);
+}
+
+#[test]
+fn split_on_words() {
+    assert!(split_text("hello world foo", 12) == vec![String::from_str("hello world "), String::from_str("foo")]);
+    assert!(split_text("abcdefgh", 3) == vec![String::from_str("abc"), String::from_str("def"), String::from_str("gh")]);
+    assert!(split_text("short", 100) == vec![String::from_str("short")]);
+    assert!(split_text("", 100) == vec![String::new()]);
+}
+
+#[test]
+fn split_keeps_characters_whole() {
+    // Each e with a combining acute accent is 3 bytes
+    let pieces = split_text("e\u0301e\u0301e\u0301", 4);
+    assert!(pieces == vec![String::from_str("e\u0301"), String::from_str("e\u0301"), String::from_str("e\u0301")]);
+
+    // Each precomposed e acute is 2 bytes
+    let pieces = split_text("\u00e9\u00e9\u00e9", 5);
+    assert!(pieces == vec![String::from_str("\u00e9\u00e9"), String::from_str("\u00e9")]);
+}
+
+#[test]
+fn split_keeps_colour_codes_whole() {
+    let pieces = split_text("ab\x0304,12cd", 4);
+    assert!(pieces == vec![String::from_str("ab"), String::from_str("\x0304,12"), String::from_str("cd")]);
+
+    let pieces = split_text("a\x04FF00AAb", 5);
+    assert!(pieces == vec![String::from_str("a"), String::from_str("\x04FF00AA"), String::from_str("b")]);
+}
@@ -33,14 +33,24 @@ impl<'_> Tag<'_> {
                 None => { return Err(MalformedInput); }
             };
 
+            if name == "" {
+                continue;
+            }
+
             let value = t.next().unwrap_or("");
 
-            if value.chars().find(|c| {*c == '\0' || *c == '\x07' || *c == '\r' || *c == '\n' || *c == ' '}).is_some() {
+            if value.chars().find(|c| {*c == '\0' || *c == '\r' || *c == '\n' || *c == ' '}).is_some() {
                 return Err(MalformedInput);
             }
 
+            // When a tag is repeated, only the last value counts
+            match tags.iter().position(|existing| existing.name.as_slice() == name) {
+                Some(i) => { tags.remove(i); },
+                None => {}
+            }
+
             tags.push(Tag {
-                name: Owned(unescape(name)),
+                name: Owned(String::from_str(name)),
                 value: if value == "" { None } else { Some(Owned(unescape(value))) }
             })
         }
@@ -52,6 +62,14 @@ impl<'_> Tag<'_> {
         }
     }
 
+    #[experimental]
+    pub fn new(name: &str, value: Option<&str>) -> Tag<'static> {
+        Tag {
+            name: Owned(String::from_str(name)),
+            value: value.map(|v| Owned(String::from_str(v)))
+        }
+    }
+
     #[experimental]
     pub fn from_parts<'a>(name: MaybeOwned<'a>, value: Option<MaybeOwned<'a>>) -> Tag<'a> {
         Tag {
@@ -83,6 +101,35 @@ impl<'_> Tag<'_> {
     pub fn value_mut(&'_ mut self) -> &'_ mut Option<MaybeOwned<'_>> {
         &mut self.value
     }
+
+    /// Whether this is a client-only tag (`+example`), which servers pass on to other clients without acting on.
+    #[inline]
+    #[experimental]
+    pub fn is_client_only(&self) -> bool {
+        self.name.as_slice().starts_with("+")
+    }
+
+    /// The vendor part of the name, e.g. `example.com` for `+example.com/foo`.
+    #[experimental]
+    pub fn vendor<'a>(&'a self) -> Option<&'a str> {
+        let name = self.unprefixed_name();
+        name.find('/').map(|slash| name.slice_to(slash))
+    }
+
+    /// The name without any `+` or vendor, e.g. `foo` for `+example.com/foo`.
+    #[experimental]
+    pub fn key<'a>(&'a self) -> &'a str {
+        let name = self.unprefixed_name();
+        match name.find('/') {
+            Some(slash) => name.slice_from(slash + 1),
+            None => name
+        }
+    }
+
+    fn unprefixed_name<'a>(&'a self) -> &'a str {
+        let name = self.name.as_slice();
+        if self.is_client_only() { name.slice_from(1) } else { name }
+    }
 }
 
 #[stable]
@@ -90,21 +137,65 @@ impl<'_> fmt::Show for Tag<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.value {
             Some(ref value) => {
-                write!(f, "{}={}", escape(&self.name), escape(value))
+                write!(f, "{}={}", self.name, escape(value.as_slice()))
             },
             None => {
-                write!(f, "{}", escape(&self.name))
+                write!(f, "{}", self.name)
             }
         }
     }
 }
 
-#[stable]
-fn escape(input: &MaybeOwned) -> String {
-    input.replace("\\", "\\\\").replace(";", "\\:").replace(" ", "\\s").replace("\0", "\\0").replace("\r", "\\r").replace("\n", "\\n")
+/// Escapes a tag value for sending. Tag names are never escaped.
+#[experimental]
+pub fn escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c)
+        }
+    }
+    out
 }
 
-#[stable]
-fn unescape(input: &str) -> String {
-    input.to_string().replace("\\n", "\n").replace("\\r", "\r").replace("\\0", "\0").replace("\\s", " ").replace("\\:", ";").replace("\\\\", "\\")
+/// Unescapes a received tag value. A backslash before any other character is dropped, as is one at the end of the value.
+#[experimental]
+pub fn unescape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    loop {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some(':') => out.push(';'),
+                Some('s') => out.push(' '),
+                Some('r') => out.push('\r'),
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => break
+            },
+            Some(c) => out.push(c),
+            None => break
+        }
+    }
+    out
+}
+
+#[test]
+fn escaping() {
+    assert!(escape("a;b c\\d\r\n") == String::from_str("a\\:b\\sc\\\\d\\r\\n"));
+    assert!(unescape("a\\:b\\sc\\\\d\\r\\n") == String::from_str("a;b c\\d\r\n"));
+    // Escaped backslashes are handled before what follows them
+    assert!(unescape("\\\\s") == String::from_str("\\s"));
+    assert!(unescape("a\\b\\") == String::from_str("ab"));
+}
+
+#[test]
+fn repeated_tags() {
+    let tags = Tag::from_str("a=1;b;;a=2").unwrap().unwrap();
+    assert!(tags == vec![Tag::new("b", None), Tag::new("a", Some("2"))]);
 }
@@ -0,0 +1,96 @@
+/// A user that shares at least one channel with us.
+#[experimental]
+#[deriving(Clone, Show)]
+pub struct User {
+    nick: String,
+    user_name: Option<String>,
+    host: Option<String>,
+    real_name: Option<String>,
+    account: Option<String>,
+    away: Option<String>
+}
+
+#[experimental]
+impl User {
+    #[experimental]
+    pub fn new(nick: &str) -> User {
+        User {
+            nick: String::from_str(nick),
+            user_name: None,
+            host: None,
+            real_name: None,
+            account: None,
+            away: None
+        }
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn nick<'a>(&'a self) -> &'a str {
+        self.nick.as_slice()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn user_name<'a>(&'a self) -> Option<&'a str> {
+        self.user_name.as_ref().map(|u| u.as_slice())
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn host<'a>(&'a self) -> Option<&'a str> {
+        self.host.as_ref().map(|h| h.as_slice())
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn real_name<'a>(&'a self) -> Option<&'a str> {
+        self.real_name.as_ref().map(|r| r.as_slice())
+    }
+
+    /// The account the user is logged in to, if known.
+    #[experimental]
+    #[inline]
+    pub fn account<'a>(&'a self) -> Option<&'a str> {
+        self.account.as_ref().map(|a| a.as_slice())
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn is_away(&self) -> bool {
+        self.away.is_some()
+    }
+
+    #[experimental]
+    #[inline]
+    pub fn away_message<'a>(&'a self) -> Option<&'a str> {
+        self.away.as_ref().map(|a| a.as_slice())
+    }
+
+    #[experimental]
+    pub fn set_nick(&mut self, nick: &str) {
+        self.nick = String::from_str(nick);
+    }
+
+    #[experimental]
+    pub fn set_user_host(&mut self, user_name: &str, host: &str) {
+        self.user_name = Some(String::from_str(user_name));
+        self.host = Some(String::from_str(host));
+    }
+
+    #[experimental]
+    pub fn set_real_name(&mut self, real_name: &str) {
+        self.real_name = Some(String::from_str(real_name));
+    }
+
+    /// Sets the account, where `*` means the user is not logged in.
+    #[experimental]
+    pub fn set_account(&mut self, account: &str) {
+        self.account = if account == "*" { None } else { Some(String::from_str(account)) };
+    }
+
+    #[experimental]
+    pub fn set_away(&mut self, message: Option<&str>) {
+        self.away = message.map(|m| String::from_str(m));
+    }
+}